@@ -3,7 +3,11 @@ set -e
 
 if [ -z "$(ls -A /store)" ]; then
   echo "Store is empty. Initializing..."
-  relayt init-store store
+  if [ -n "$RELAYT_PASSPHRASE" ]; then
+    relayt init-store --encrypt-key store
+  else
+    relayt init-store store
+  fi
 else
   echo "Store already contains data. Skipping initialization."
 fi
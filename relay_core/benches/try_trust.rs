@@ -0,0 +1,133 @@
+use std::{convert::Infallible, ops::RangeInclusive, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use relay_core::{
+    archive::MemoryArchive,
+    clock::{Clock, PeriodSchedule, VirtualClock},
+    crypto::{PublicKey, SecretKey},
+    mailroom::{GetNextLine, Mailroom, NextLine, TTLConfig},
+    payload::UntrustedPayload,
+};
+use tokio::runtime::Builder;
+
+struct BenchLineGenerator;
+
+impl GetNextLine for BenchLineGenerator {
+    fn get_next_line(&mut self) -> Option<NextLine> {
+        Some(NextLine {
+            line: uuid::Uuid::new_v4().hyphenated().to_string(),
+            author: "bench".to_owned(),
+        })
+    }
+}
+
+type BenchMailroom = Mailroom<BenchLineGenerator, MemoryArchive, Infallible>;
+
+fn new_mailroom(clock: &VirtualClock) -> (BenchMailroom, PublicKey) {
+    let secret_key = SecretKey::generate();
+    let public_key = secret_key.public_key();
+
+    (
+        Mailroom::new_with_clock(
+            BenchLineGenerator,
+            MemoryArchive::new(),
+            secret_key,
+            Arc::new(clock.clone()),
+            PeriodSchedule::HOURLY,
+        ),
+        public_key,
+    )
+}
+
+struct ForwardingPayload {
+    payload: String,
+    hub_key: PublicKey,
+    receiver_key: PublicKey,
+    accepted_periods: RangeInclusive<DateTime<Utc>>,
+}
+
+// builds a payload from a hub relay forwarding one envelope from each of `authors` relays
+async fn create_forwarding_payload(authors: usize) -> ForwardingPayload {
+    let clock = VirtualClock::new("2025-01-01T00:00:00Z".parse().unwrap());
+    let (mut hub, hub_key) = new_mailroom(&clock);
+    let (_, receiver_key) = new_mailroom(&clock);
+
+    for _ in 0..authors {
+        let (mut author, author_key) = new_mailroom(&clock);
+
+        let payload = author
+            .get_outgoing(&hub_key, TTLConfig::default())
+            .await
+            .unwrap()
+            .create_payload();
+        let trusted_payload = UntrustedPayload::from_json(&payload)
+            .unwrap()
+            .try_trust([author_key], &hub_key, hub.accepted_periods(clock.now()))
+            .unwrap();
+
+        hub.receive_payload(&trusted_payload).await.unwrap();
+    }
+
+    clock.advance(Duration::from_secs(3600));
+    let payload = hub
+        .get_outgoing(&receiver_key, TTLConfig::default())
+        .await
+        .unwrap()
+        .create_payload();
+
+    ForwardingPayload {
+        payload,
+        hub_key,
+        receiver_key,
+        accepted_periods: hub.accepted_periods(clock.now()),
+    }
+}
+
+fn bench_try_trust(c: &mut Criterion) {
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let mut group = c.benchmark_group("try_trust");
+
+    for authors in [10, 100, 500] {
+        let forwarding = runtime.block_on(create_forwarding_payload(authors));
+
+        group.bench_with_input(
+            BenchmarkId::new("batched", authors),
+            &forwarding,
+            |b, forwarding| {
+                b.iter(|| {
+                    UntrustedPayload::from_json(&forwarding.payload)
+                        .unwrap()
+                        .try_trust(
+                            [forwarding.hub_key],
+                            &forwarding.receiver_key,
+                            forwarding.accepted_periods.clone(),
+                        )
+                        .unwrap()
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("without_batching", authors),
+            &forwarding,
+            |b, forwarding| {
+                b.iter(|| {
+                    UntrustedPayload::from_json(&forwarding.payload)
+                        .unwrap()
+                        .try_trust_without_batching(
+                            [forwarding.hub_key],
+                            &forwarding.receiver_key,
+                            forwarding.accepted_periods.clone(),
+                        )
+                        .unwrap()
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_try_trust);
+criterion_main!(benches);
@@ -0,0 +1,356 @@
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    mailroom::Archive,
+    message::{Envelope, Message, MessageKind},
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArchivedEnvelope {
+    pub from: String,
+    pub envelope: Envelope,
+}
+
+// every filter that's set has to match. messages come back oldest first, created_from is inclusive
+// and created_until isn't
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageQuery {
+    pub key: Option<String>,
+    pub uuid: Option<String>,
+    pub created_from: Option<i64>,
+    pub created_until: Option<i64>,
+    pub offset: u32,
+    pub limit: Option<u32>,
+}
+
+impl MessageQuery {
+    pub fn matches(&self, message: &Message) -> bool {
+        let created_at = message.contents.created_at;
+
+        self.key
+            .as_ref()
+            .is_none_or(|key| *key == message.certificate.key)
+            && self
+                .uuid
+                .as_ref()
+                .is_none_or(|uuid| *uuid == message.contents.uuid)
+            && self.created_from.is_none_or(|from| created_at >= from)
+            && self.created_until.is_none_or(|until| created_at < until)
+    }
+
+    // without a limit the query already gets everything, so there's no page after it
+    pub fn next_page(&self) -> Option<Self> {
+        let limit = self.limit?;
+
+        Some(Self {
+            offset: self.offset.saturating_add(limit),
+            ..self.clone()
+        })
+    }
+}
+
+// messages are told apart by their signature, like the daemon's archive does
+#[derive(Clone, Debug, Default)]
+pub struct MemoryArchive {
+    messages: Vec<Message>,
+    message_signatures: HashSet<String>,
+    envelopes: Vec<ArchivedEnvelope>,
+    quarantined_envelopes: Vec<ArchivedEnvelope>,
+}
+
+impl MemoryArchive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    pub fn envelopes(&self) -> &[ArchivedEnvelope] {
+        &self.envelopes
+    }
+
+    pub fn quarantined_envelopes(&self) -> &[ArchivedEnvelope] {
+        &self.quarantined_envelopes
+    }
+
+    pub fn contains(&self, message: &Message) -> bool {
+        self.message_signatures
+            .contains(&message.certificate.signature)
+    }
+
+    // only counts when the retraction is signed by the same key as the line
+    pub fn is_retracted(&self, message: &Message) -> bool {
+        self.messages.iter().any(|retraction| {
+            retraction.contents.kind == MessageKind::Retraction
+                && retraction.certificate.key == message.certificate.key
+                && retraction.contents.retracts.as_ref() == Some(&message.certificate.signature)
+        })
+    }
+
+    fn add_envelope(&mut self, from: &str, envelope: &Envelope) {
+        if self
+            .message_signatures
+            .insert(envelope.message.certificate.signature.clone())
+        {
+            self.messages.push(envelope.message.clone());
+        }
+
+        self.envelopes.push(ArchivedEnvelope {
+            from: from.to_owned(),
+            envelope: envelope.clone(),
+        });
+    }
+
+    fn add_quarantined_envelope(&mut self, from: &str, envelope: &Envelope) {
+        self.quarantined_envelopes.push(ArchivedEnvelope {
+            from: from.to_owned(),
+            envelope: envelope.clone(),
+        });
+    }
+
+    fn query_messages(&self, query: &MessageQuery) -> Vec<Message> {
+        let mut messages = self
+            .messages
+            .iter()
+            .filter(|message| query.matches(message))
+            .collect::<Vec<&Message>>();
+        messages.sort_by_key(|message| message.contents.created_at);
+
+        messages
+            .into_iter()
+            .skip(query.offset as usize)
+            .take(query.limit.map_or(usize::MAX, |limit| limit as usize))
+            .cloned()
+            .collect()
+    }
+
+    fn query_envelopes(&self, signature: &str) -> Vec<ArchivedEnvelope> {
+        self.envelopes
+            .iter()
+            .filter(|archived| archived.envelope.message.certificate.signature == signature)
+            .cloned()
+            .collect()
+    }
+
+    fn find_messages_with_line(&self, key: &str, line: &str) -> Vec<Message> {
+        self.messages
+            .iter()
+            .filter(|message| {
+                message.contents.kind == MessageKind::Line
+                    && message.certificate.key == key
+                    && message.contents.line == line
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+impl Archive for MemoryArchive {
+    type Error = Infallible;
+
+    async fn is_message_in_archive(&self, message: &Message) -> Result<bool, Self::Error> {
+        Ok(self.contains(message))
+    }
+
+    async fn add_envelope_to_archive(
+        &mut self,
+        from: &str,
+        envelope: &Envelope,
+    ) -> Result<(), Self::Error> {
+        self.add_envelope(from, envelope);
+        Ok(())
+    }
+
+    async fn add_envelope_to_quarantine(
+        &mut self,
+        from: &str,
+        envelope: &Envelope,
+    ) -> Result<(), Self::Error> {
+        self.add_quarantined_envelope(from, envelope);
+        Ok(())
+    }
+
+    async fn find_messages_with_line(
+        &self,
+        key: &str,
+        line: &str,
+    ) -> Result<Vec<Message>, Self::Error> {
+        Ok(self.find_messages_with_line(key, line))
+    }
+
+    async fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, Self::Error> {
+        Ok(self.query_messages(query))
+    }
+
+    async fn find_envelopes_of_message(
+        &self,
+        signature: &str,
+    ) -> Result<Vec<ArchivedEnvelope>, Self::Error> {
+        Ok(self.query_envelopes(signature))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum JsonlArchiveError {
+    #[error("cannot open archive file: {0}")]
+    Open(#[source] io::Error),
+    #[error("cannot read archive file: {0}")]
+    Read(#[source] io::Error),
+    #[error("cannot parse line {0} of archive file")]
+    Parse(usize),
+    #[error("cannot write to archive file: {0}")]
+    Write(#[source] io::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "archive", rename_all = "lowercase")]
+enum ArchiveRecord {
+    Envelope(ArchivedEnvelope),
+    Quarantine(ArchivedEnvelope),
+}
+
+// one json record per line, only ever appended to. everything is also kept in memory, so the file
+// is only read once when it's opened
+#[derive(Debug)]
+pub struct JsonlArchive {
+    path: PathBuf,
+    file: File,
+    memory: MemoryArchive,
+}
+
+impl JsonlArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, JsonlArchiveError> {
+        let path = path.as_ref().to_path_buf();
+        let mut memory = MemoryArchive::new();
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(JsonlArchiveError::Read(error)),
+        };
+        let is_cut_short = !contents.is_empty() && !contents.ends_with('\n');
+        let lines = contents.lines().collect::<Vec<&str>>();
+        let mut is_last_line_broken = false;
+
+        for (i, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(line) {
+                Ok(ArchiveRecord::Envelope(archived)) => {
+                    memory.add_envelope(&archived.from, &archived.envelope)
+                }
+                Ok(ArchiveRecord::Quarantine(archived)) => {
+                    memory.add_quarantined_envelope(&archived.from, &archived.envelope)
+                }
+                // a write cut short by a crash only ever leaves the last line broken, and it's
+                // dropped from the file below
+                Err(_) if is_cut_short && i == lines.len() - 1 => is_last_line_broken = true,
+                Err(_) => return Err(JsonlArchiveError::Parse(i + 1)),
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(JsonlArchiveError::Open)?;
+        if is_last_line_broken {
+            let intact_length = contents.rfind('\n').map_or(0, |i| i + 1);
+            file.set_len(intact_length as u64)
+                .map_err(JsonlArchiveError::Write)?;
+        } else if is_cut_short {
+            // the last record made it, only its newline didn't
+            file.write_all(b"\n").map_err(JsonlArchiveError::Write)?;
+        }
+
+        Ok(Self { path, file, memory })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn memory(&self) -> &MemoryArchive {
+        &self.memory
+    }
+
+    fn append(&mut self, record: &ArchiveRecord) -> Result<(), JsonlArchiveError> {
+        let mut line =
+            serde_json::to_string(record).expect("should be able to serialize any record");
+        line.push('\n');
+
+        self.file
+            .write_all(line.as_bytes())
+            .map_err(JsonlArchiveError::Write)
+    }
+}
+
+impl Archive for JsonlArchive {
+    type Error = JsonlArchiveError;
+
+    async fn is_message_in_archive(&self, message: &Message) -> Result<bool, Self::Error> {
+        Ok(self.memory.contains(message))
+    }
+
+    async fn add_envelope_to_archive(
+        &mut self,
+        from: &str,
+        envelope: &Envelope,
+    ) -> Result<(), Self::Error> {
+        let archived = ArchivedEnvelope {
+            from: from.to_owned(),
+            envelope: envelope.clone(),
+        };
+        self.append(&ArchiveRecord::Envelope(archived))?;
+        self.memory.add_envelope(from, envelope);
+
+        Ok(())
+    }
+
+    async fn add_envelope_to_quarantine(
+        &mut self,
+        from: &str,
+        envelope: &Envelope,
+    ) -> Result<(), Self::Error> {
+        let archived = ArchivedEnvelope {
+            from: from.to_owned(),
+            envelope: envelope.clone(),
+        };
+        self.append(&ArchiveRecord::Quarantine(archived))?;
+        self.memory.add_quarantined_envelope(from, envelope);
+
+        Ok(())
+    }
+
+    async fn find_messages_with_line(
+        &self,
+        key: &str,
+        line: &str,
+    ) -> Result<Vec<Message>, Self::Error> {
+        Ok(self.memory.find_messages_with_line(key, line))
+    }
+
+    async fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, Self::Error> {
+        Ok(self.memory.query_messages(query))
+    }
+
+    async fn find_envelopes_of_message(
+        &self,
+        signature: &str,
+    ) -> Result<Vec<ArchivedEnvelope>, Self::Error> {
+        Ok(self.memory.query_envelopes(signature))
+    }
+}
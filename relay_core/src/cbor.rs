@@ -0,0 +1,164 @@
+use std::fmt;
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ciborium::Value as CborValue;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use thiserror::Error;
+
+use crate::message::MessageKind;
+
+#[derive(Error, Debug)]
+pub enum CborError {
+    #[error("cannot parse json payload")]
+    CannotParseJson,
+    #[error("cannot parse cbor payload")]
+    CannotParseCbor,
+}
+
+// cbor is only a wire format. signatures stay over the canonical json, so a message signed once can
+// be forwarded in either format, and a payload sent as cbor has to come back as the exact json it
+// was made from. keys and signatures go as byte strings, which is most of what cbor saves over
+// json. fields this version doesn't know about don't make it across
+pub fn from_json(json_str: &str) -> Result<Vec<u8>, CborError> {
+    let payload: WirePayload =
+        serde_json::from_str(json_str).map_err(|_| CborError::CannotParseJson)?;
+    let value =
+        CborValue::serialized(&payload).expect("should be able to turn any payload into cbor");
+
+    let mut cbor = vec![];
+    ciborium::into_writer(&canonicalize(value), &mut cbor)
+        .expect("should be able to write cbor to vec");
+
+    Ok(cbor)
+}
+
+pub fn to_json(cbor: &[u8]) -> Result<String, CborError> {
+    let payload: WirePayload =
+        ciborium::from_reader(cbor).map_err(|_| CborError::CannotParseCbor)?;
+
+    Ok(serde_json::to_string(&payload).expect("should be able to serialize any payload to json"))
+}
+
+// map keys are sorted by their encoded bytes (rfc 8949 section 4.2), which for text keys means
+// shorter first, so the same payload always turns into the same bytes
+fn canonicalize(value: CborValue) -> CborValue {
+    match value {
+        CborValue::Array(array) => CborValue::Array(array.into_iter().map(canonicalize).collect()),
+        CborValue::Map(members) => {
+            let mut members: Vec<(CborValue, CborValue)> = members
+                .into_iter()
+                .map(|(name, value)| (name, canonicalize(value)))
+                .collect();
+            members.sort_by(|(a, _), (b, _)| {
+                let (a, b) = (
+                    a.as_text().unwrap_or_default(),
+                    b.as_text().unwrap_or_default(),
+                );
+                a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+            });
+
+            CborValue::Map(members)
+        }
+        value => value,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct WirePayload {
+    version: u16,
+    certificate: WireCertificate,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    successions: Vec<WireKeySuccession>,
+    recipient: Base64,
+    period: i64,
+    envelopes: Vec<WireEnvelope>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireCertificate {
+    key: Base64,
+    signature: Base64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireKeySuccession {
+    old_key: Base64,
+    new_key: Base64,
+    signature: Base64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireEnvelope {
+    forwarded: Vec<WireForwardingHop>,
+    ttl: u8,
+    message: WireMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireForwardingHop {
+    key: Base64,
+    ttl: u8,
+    signature: Base64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireMessage {
+    certificate: WireCertificate,
+    contents: WireMessageContents,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireMessageContents {
+    version: u16,
+    #[serde(default, skip_serializing_if = "MessageKind::is_line")]
+    kind: MessageKind,
+    uuid: String,
+    author: String,
+    line: String,
+    created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retracts: Option<Base64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u8>,
+}
+
+// base64 that encodes back to the same string goes into cbor as a byte string, anything else stays
+// text so it still comes back unchanged
+struct Base64(String);
+
+impl Serialize for Base64 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable()
+            && let Ok(bytes) = BASE64_STANDARD.decode(&self.0)
+            && BASE64_STANDARD.encode(&bytes) == self.0
+        {
+            return serializer.serialize_bytes(&bytes);
+        }
+
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Base64 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(Base64Visitor)
+    }
+}
+
+struct Base64Visitor;
+
+impl de::Visitor<'_> for Base64Visitor {
+    type Value = Base64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a base64 string or a byte string")
+    }
+
+    fn visit_str<E: de::Error>(self, string: &str) -> Result<Base64, E> {
+        Ok(Base64(string.to_owned()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Base64, E> {
+        Ok(Base64(BASE64_STANDARD.encode(bytes)))
+    }
+}
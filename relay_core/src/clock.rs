@@ -0,0 +1,139 @@
+use std::{
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+const HOUR_IN_SECONDS: u64 = 60 * 60;
+const DEFAULT_MAX_SKEW_IN_SECONDS: u64 = 30;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// only moves when it's told to, clones share the same time
+#[derive(Clone, Debug)]
+pub struct VirtualClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl VirtualClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().expect("should be able to lock clock") = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().expect("should be able to lock clock");
+        *now += TimeDelta::from_std(by).expect("should be able to advance clock by any duration");
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().expect("should be able to lock clock")
+    }
+}
+
+// periods line up with the unix epoch, so relays with the same schedule agree on where each period
+// starts without talking to each other. their clocks won't agree exactly though, so payloads are
+// accepted for any period within max_skew of now
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeriodSchedule {
+    length: Duration,
+    max_skew: Duration,
+}
+
+impl PeriodSchedule {
+    pub const HOURLY: PeriodSchedule = PeriodSchedule {
+        length: Duration::from_secs(HOUR_IN_SECONDS),
+        max_skew: Duration::from_secs(DEFAULT_MAX_SKEW_IN_SECONDS),
+    };
+
+    // short periods get at most half a period of skew
+    pub fn every(length: Duration) -> Self {
+        assert!(
+            length.as_secs() > 0 && length.subsec_nanos() == 0,
+            "period length should be a whole number of seconds"
+        );
+
+        Self {
+            length,
+            max_skew: Duration::from_secs(DEFAULT_MAX_SKEW_IN_SECONDS).min(length / 2),
+        }
+    }
+
+    pub fn with_max_skew(self, max_skew: Duration) -> Self {
+        assert!(
+            max_skew < self.length,
+            "max clock skew should be shorter than a period"
+        );
+
+        Self { max_skew, ..self }
+    }
+
+    pub fn length(&self) -> Duration {
+        self.length
+    }
+
+    pub fn max_skew(&self) -> Duration {
+        self.max_skew
+    }
+
+    pub fn period_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let length = self.length.as_secs() as i64;
+        let start = now.timestamp().div_euclid(length) * length;
+
+        DateTime::from_timestamp(start, 0).expect("should be able to flatten any utc time")
+    }
+
+    pub fn next_period_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        self.period_start(now) + self.length
+    }
+
+    pub fn until_next_period(&self, now: DateTime<Utc>) -> Duration {
+        (self.next_period_start(now) - now)
+            .to_std()
+            .expect("should be able to get time until next period as duration")
+    }
+
+    // the current period, and the one before or after it when now is within max_skew of a boundary
+    pub fn accepted_periods(&self, now: DateTime<Utc>) -> RangeInclusive<DateTime<Utc>> {
+        let max_skew =
+            TimeDelta::from_std(self.max_skew).expect("should be able to use any max skew");
+
+        self.period_start(now - max_skew)..=self.period_start(now + max_skew)
+    }
+
+    pub fn follows(&self, previous: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        self.periods_between(previous, now) == 1
+    }
+
+    pub fn periods_between(&self, earlier: DateTime<Utc>, later: DateTime<Utc>) -> i64 {
+        let length = self.length.as_secs() as i64;
+
+        (self.period_start(later).timestamp() - self.period_start(earlier).timestamp()) / length
+    }
+}
+
+impl Default for PeriodSchedule {
+    fn default() -> Self {
+        Self::HOURLY
+    }
+}
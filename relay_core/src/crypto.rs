@@ -1,15 +1,29 @@
-use std::{fmt::Display, str::FromStr};
+use std::fmt::Display;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use base64::{Engine, prelude::BASE64_STANDARD};
-use ed25519_dalek::{Signature, SigningKey, VerifyingKey, ed25519::signature::SignerMut};
-use json_syntax::{Print, Value};
-use rand::rngs::OsRng;
+use bip39::Language;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, aead::Aead};
+use curve25519_dalek::{MontgomeryPoint, edwards::CompressedEdwardsY};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use rand::{RngCore, rngs::OsRng};
 use serde::{Deserialize, Serialize, de};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
+use zeroize::{ZeroizeOnDrop, Zeroizing};
+
+use crate::jcs;
 
 pub const PUBLIC_KEY_LENGTH: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
 pub const SECRET_KEY_LENGTH: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+const FINGERPRINT_DOMAIN: &[u8] = b"relay fingerprint";
+const SAFETY_NUMBER_DOMAIN: &[u8] = b"relay safety number";
+const SEALED_DOMAIN: &[u8] = b"relay sealed";
+const X25519_KEY_LENGTH: usize = 32;
+const FINGERPRINT_HEX_BYTES: usize = 10;
+const FINGERPRINT_WORDS: usize = 6;
+const SAFETY_NUMBER_GROUPS: usize = 6;
 
 #[derive(Error, Debug)]
 pub enum NewKeyError {
@@ -21,6 +35,20 @@ pub enum NewKeyError {
     InvalidKey,
 }
 
+#[derive(Error, Debug)]
+pub enum KeySuccessionError {
+    #[error("cannot verify key succession signature")]
+    CannotVerify,
+}
+
+#[derive(Error, Debug)]
+pub enum SealError {
+    #[error("cannot seal to a key with no usable x25519 form")]
+    WeakKey,
+    #[error("cannot open sealed bytes (are they for this key?)")]
+    CannotOpen,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
 pub struct PublicKey(VerifyingKey);
 
@@ -43,6 +71,33 @@ impl PublicKey {
         self.0.as_bytes()
     }
 
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint(
+            Sha256::new()
+                .chain_update(FINGERPRINT_DOMAIN)
+                .chain_update(self.as_bytes())
+                .finalize()
+                .into(),
+        )
+    }
+
+    pub fn safety_number(&self, other: &PublicKey) -> SafetyNumber {
+        let (first, second) = if self.as_bytes() <= other.as_bytes() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+
+        SafetyNumber(
+            Sha256::new()
+                .chain_update(SAFETY_NUMBER_DOMAIN)
+                .chain_update(first.as_bytes())
+                .chain_update(second.as_bytes())
+                .finalize()
+                .into(),
+        )
+    }
+
     pub(crate) fn verify(&self, message: &[u8], signature: &str) -> Result<()> {
         let signature_bytes = bytes_from_b64(signature)?;
         let signature = Signature::from_bytes(&signature_bytes);
@@ -50,6 +105,25 @@ impl PublicKey {
 
         Ok(())
     }
+
+    // sealed to the x25519 form of the key with a fresh ephemeral key, as ephemeral public key ||
+    // ciphertext. every ephemeral key is only used once so the nonce can stay fixed
+    pub(crate) fn seal(&self, plaintext: &[u8]) -> Result<String, SealError> {
+        let recipient = self.0.to_montgomery();
+
+        let mut ephemeral_secret = Zeroizing::new([0; X25519_KEY_LENGTH]);
+        OsRng.fill_bytes(ephemeral_secret.as_mut());
+        let ephemeral_public = MontgomeryPoint::mul_base_clamped(*ephemeral_secret);
+        let shared_secret = Zeroizing::new(recipient.mul_clamped(*ephemeral_secret).to_bytes());
+
+        let ciphertext = sealing_cipher(&shared_secret, &ephemeral_public, &recipient)?
+            .encrypt(&Nonce::default(), plaintext)
+            .expect("should be able to encrypt any plaintext");
+
+        Ok(b64_from_bytes(
+            &[ephemeral_public.as_bytes().as_slice(), &ciphertext].concat(),
+        ))
+    }
 }
 
 impl Serialize for PublicKey {
@@ -77,7 +151,66 @@ impl Display for PublicKey {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    pub fn to_hex(&self) -> String {
+        self.0[..FINGERPRINT_HEX_BYTES]
+            .chunks(2)
+            .map(|chunk| chunk.iter().map(|byte| format!("{byte:02x}")).collect())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    // each word from the bip39 english list encodes 11 bits of the fingerprint
+    pub fn to_words(&self) -> String {
+        let word_list = Language::English.word_list();
+
+        (0..FINGERPRINT_WORDS)
+            .map(|i| {
+                let index = (0..11).fold(0, |index, bit| {
+                    let position = i * 11 + bit;
+                    let bit_value = (self.0[position / 8] >> (7 - position % 8)) & 1;
+                    (index << 1) | bit_value as usize
+                });
+                word_list[index]
+            })
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+// same for both keys of a pair no matter which side computes it
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub struct SafetyNumber([u8; 32]);
+
+impl Display for SafetyNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let groups: Vec<String> = self
+            .0
+            .chunks(5)
+            .take(SAFETY_NUMBER_GROUPS)
+            .map(|chunk| {
+                let value = chunk
+                    .iter()
+                    .fold(0u64, |value, byte| (value << 8) | *byte as u64);
+                format!("{:05}", value % 100_000)
+            })
+            .collect();
+
+        write!(f, "{}", groups.join(" "))
+    }
+}
+
+// deliberately not Clone, so key material only lives where it was created and is wiped on drop
+#[derive(PartialEq, Eq, Debug)]
 pub struct SecretKey(SigningKey);
 
 impl SecretKey {
@@ -86,7 +219,7 @@ impl SecretKey {
     }
 
     pub fn new_from_b64(b64_string: &str) -> Result<Self, NewKeyError> {
-        bytes_from_b64(b64_string).map(|bytes| Self::new_from_bytes(&bytes))
+        bytes_from_b64(b64_string).map(|bytes| Self::new_from_bytes(&Zeroizing::new(bytes)))
     }
 
     pub fn new_from_bytes(bytes: &[u8; SECRET_KEY_LENGTH]) -> Self {
@@ -101,11 +234,43 @@ impl SecretKey {
         PublicKey(self.0.verifying_key())
     }
 
-    pub(crate) fn sign(&mut self, message: &[u8]) -> String {
+    pub(crate) fn sign(&self, message: &[u8]) -> String {
         b64_from_bytes(&self.0.sign(message).to_bytes())
     }
+
+    pub(crate) fn open(&self, sealed: &str) -> Result<Vec<u8>, SealError> {
+        let sealed = BASE64_STANDARD
+            .decode(sealed)
+            .map_err(|_| SealError::CannotOpen)?;
+        if sealed.len() < X25519_KEY_LENGTH {
+            return Err(SealError::CannotOpen);
+        }
+
+        let (ephemeral_public, ciphertext) = sealed.split_at(X25519_KEY_LENGTH);
+        let ephemeral_public = MontgomeryPoint(
+            ephemeral_public
+                .try_into()
+                .expect("should be able to get x25519 key from slice of its length"),
+        );
+        let shared_secret = Zeroizing::new(
+            ephemeral_public
+                .mul_clamped(*Zeroizing::new(self.0.to_scalar_bytes()))
+                .to_bytes(),
+        );
+
+        sealing_cipher(
+            &shared_secret,
+            &ephemeral_public,
+            &self.0.verifying_key().to_montgomery(),
+        )
+        .map_err(|_| SealError::CannotOpen)?
+        .decrypt(&Nonce::default(), ciphertext)
+        .map_err(|_| SealError::CannotOpen)
+    }
 }
 
+impl ZeroizeOnDrop for SecretKey {}
+
 impl Display for SecretKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", b64_from_bytes(self.as_bytes()))
@@ -117,7 +282,7 @@ impl Serialize for SecretKey {
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(&self.to_string())
+        serializer.serialize_str(&Zeroizing::new(self.to_string()))
     }
 }
 
@@ -126,11 +291,88 @@ impl<'de> Deserialize<'de> for SecretKey {
     where
         D: serde::Deserializer<'de>,
     {
-        let key_string = String::deserialize(deserializer)?;
+        let key_string = Zeroizing::new(String::deserialize(deserializer)?);
         SecretKey::new_from_b64(&key_string).map_err(de::Error::custom)
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeySuccession {
+    old_key: PublicKey,
+    new_key: PublicKey,
+    signature: String,
+}
+
+impl KeySuccession {
+    pub fn new(old_secret_key: &SecretKey, new_key: PublicKey) -> Self {
+        let old_key = old_secret_key.public_key();
+
+        let signature = old_secret_key.sign(&get_key_succession_bytes(&old_key, &new_key));
+
+        Self {
+            old_key,
+            new_key,
+            signature,
+        }
+    }
+
+    pub fn old_key(&self) -> &PublicKey {
+        &self.old_key
+    }
+
+    pub fn new_key(&self) -> &PublicKey {
+        &self.new_key
+    }
+
+    pub fn verify(&self) -> Result<(), KeySuccessionError> {
+        self.old_key
+            .verify(
+                &get_key_succession_bytes(&self.old_key, &self.new_key),
+                &self.signature,
+            )
+            .map_err(|_| KeySuccessionError::CannotVerify)
+    }
+}
+
+#[derive(Serialize)]
+struct KeySuccessionContents<'a> {
+    old_key: &'a PublicKey,
+    new_key: &'a PublicKey,
+}
+
+fn get_key_succession_bytes(old_key: &PublicKey, new_key: &PublicKey) -> Vec<u8> {
+    let contents_json = serde_json::to_string(&KeySuccessionContents { old_key, new_key })
+        .expect("should be able to serialize any key succession to json");
+
+    jcs::canonicalize(&contents_json)
+        .expect("should be able to get canon bytes for any json string")
+}
+
+fn sealing_cipher(
+    shared_secret: &[u8; X25519_KEY_LENGTH],
+    ephemeral_public: &MontgomeryPoint,
+    recipient: &MontgomeryPoint,
+) -> Result<ChaCha20Poly1305, SealError> {
+    // low order points give an all zero shared secret whatever the other key is
+    if shared_secret.iter().all(|byte| *byte == 0) {
+        return Err(SealError::WeakKey);
+    }
+
+    let mut key = Zeroizing::new([0; X25519_KEY_LENGTH]);
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand_multi_info(
+            &[
+                SEALED_DOMAIN,
+                ephemeral_public.as_bytes(),
+                recipient.as_bytes(),
+            ],
+            key.as_mut(),
+        )
+        .expect("should be able to expand any shared secret to a key");
+
+    Ok(ChaCha20Poly1305::new(key.as_ref().into()))
+}
+
 fn bytes_from_b64<const N: usize>(b64_string: &str) -> Result<[u8; N], NewKeyError> {
     match BASE64_STANDARD.decode(b64_string) {
         Ok(bytes_vec) => match bytes_vec.try_into() {
@@ -145,10 +387,44 @@ fn b64_from_bytes(bytes: &[u8]) -> String {
     BASE64_STANDARD.encode(bytes)
 }
 
-pub(crate) fn get_canon_json_bytes(json_string: &str) -> Result<Vec<u8>> {
-    let mut value = Value::from_str(json_string)?;
+// batch verification doesn't check what verify_strict does, so batches that it could accept
+// differently are rejected outright and left to the per-signature fallback
+pub(crate) fn verify_batch(items: &[(&[u8], &str, PublicKey)]) -> Result<()> {
+    let messages: Vec<&[u8]> = items.iter().map(|(message, _, _)| *message).collect();
+    let signatures = items
+        .iter()
+        .map(|(_, signature, _)| {
+            bytes_from_b64(signature).map(|bytes| Signature::from_bytes(&bytes))
+        })
+        .collect::<Result<Vec<Signature>, NewKeyError>>()?;
+    let verifying_keys: Vec<VerifyingKey> = items.iter().map(|(_, _, key)| key.0).collect();
+
+    if !signatures
+        .iter()
+        .zip(&verifying_keys)
+        .all(|(signature, key)| is_batchable(signature, key))
+    {
+        return Err(anyhow!(
+            "batch contains signature only verify_strict can check"
+        ));
+    }
+
+    ed25519_dalek::verify_batch(&messages, &signatures, &verifying_keys)?;
+
+    Ok(())
+}
 
-    value.canonicalize();
+// the batch equation weighs each signature by a random scalar, so a small order part in r or the
+// key can cancel out where verify_strict would catch it. verify_strict also compares r byte for
+// byte, so it has to be encoded the one canonical way
+fn is_batchable(signature: &Signature, key: &VerifyingKey) -> bool {
+    let r_bytes = signature.r_bytes();
+    let is_r_batchable = CompressedEdwardsY(*r_bytes).decompress().is_some_and(|r| {
+        r.compress().as_bytes() == r_bytes && !r.is_small_order() && r.is_torsion_free()
+    });
+    let is_key_batchable = CompressedEdwardsY(key.to_bytes())
+        .decompress()
+        .is_some_and(|key| !key.is_small_order() && key.is_torsion_free());
 
-    Ok(value.compact_print().to_string().as_bytes().into())
+    is_r_batchable && is_key_batchable
 }
@@ -0,0 +1,121 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize, de};
+
+use crate::{crypto::PublicKey, message::Envelope};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterAction {
+    Accept,
+    Drop,
+    Quarantine,
+}
+
+pub trait Filter {
+    fn check(&self, envelope: &Envelope) -> FilterAction;
+}
+
+impl<F> Filter for F
+where
+    F: Fn(&Envelope) -> FilterAction,
+{
+    fn check(&self, envelope: &Envelope) -> FilterAction {
+        self(envelope)
+    }
+}
+
+// drop rules win over quarantine rules when an envelope matches both
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filters {
+    #[serde(default)]
+    pub drop: FilterRules,
+    #[serde(default)]
+    pub quarantine: FilterRules,
+}
+
+impl Filters {
+    pub fn is_empty(&self) -> bool {
+        self.drop.is_empty() && self.quarantine.is_empty()
+    }
+}
+
+impl Filter for Filters {
+    fn check(&self, envelope: &Envelope) -> FilterAction {
+        if self.drop.matches(envelope) {
+            FilterAction::Drop
+        } else if self.quarantine.matches(envelope) {
+            FilterAction::Quarantine
+        } else {
+            FilterAction::Accept
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterRules {
+    #[serde(default)]
+    pub keys: Vec<PublicKey>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub lines: Vec<LinePattern>,
+}
+
+impl FilterRules {
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.authors.is_empty() && self.lines.is_empty()
+    }
+
+    // keys are matched against the key that signed the message, not the relays that forwarded it
+    pub fn matches(&self, envelope: &Envelope) -> bool {
+        let contents = &envelope.message.contents;
+
+        self.keys
+            .iter()
+            .any(|key| key.to_string() == envelope.message.certificate.key)
+            || self.authors.contains(&contents.author)
+            || self
+                .lines
+                .iter()
+                .any(|pattern| pattern.0.is_match(&contents.line))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LinePattern(Regex);
+
+impl LinePattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Regex::new(pattern).map(Self)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl PartialEq for LinePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for LinePattern {}
+
+impl Serialize for LinePattern {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for LinePattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+        LinePattern::new(&pattern).map_err(de::Error::custom)
+    }
+}
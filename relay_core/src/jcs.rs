@@ -0,0 +1,101 @@
+use std::fmt::Write;
+
+use serde_json::{Map, Number, Value};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum JcsError {
+    #[error("cannot parse json")]
+    CannotParseJson,
+    #[error("number cannot be represented as an ieee 754 double")]
+    NumberOutOfRange,
+}
+
+// json canonicalization scheme (rfc 8785), which is what every signature in relay is made over
+pub fn canonicalize(json_str: &str) -> Result<Vec<u8>, JcsError> {
+    let value: Value = serde_json::from_str(json_str).map_err(|_| JcsError::CannotParseJson)?;
+
+    let mut canonical = String::new();
+    write_value(&mut canonical, &value)?;
+
+    Ok(canonical.into_bytes())
+}
+
+fn write_value(canonical: &mut String, value: &Value) -> Result<(), JcsError> {
+    match value {
+        Value::Null => canonical.push_str("null"),
+        Value::Bool(true) => canonical.push_str("true"),
+        Value::Bool(false) => canonical.push_str("false"),
+        Value::Number(number) => write_number(canonical, number)?,
+        Value::String(string) => write_string(canonical, string),
+        Value::Array(array) => {
+            canonical.push('[');
+            for (i, element) in array.iter().enumerate() {
+                if i > 0 {
+                    canonical.push(',');
+                }
+                write_value(canonical, element)?;
+            }
+            canonical.push(']');
+        }
+        Value::Object(object) => write_object(canonical, object)?,
+    }
+
+    Ok(())
+}
+
+// numbers are serialized like ecmascript's Number.prototype.toString
+fn write_number(canonical: &mut String, number: &Number) -> Result<(), JcsError> {
+    let double = number.as_f64().ok_or(JcsError::NumberOutOfRange)?;
+
+    if !double.is_finite() {
+        return Err(JcsError::NumberOutOfRange);
+    }
+
+    if double == 0.0 {
+        canonical.push('0');
+    } else {
+        canonical.push_str(ryu_js::Buffer::new().format_finite(double));
+    }
+
+    Ok(())
+}
+
+fn write_string(canonical: &mut String, string: &str) {
+    canonical.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => canonical.push_str("\\\""),
+            '\\' => canonical.push_str("\\\\"),
+            '\u{8}' => canonical.push_str("\\b"),
+            '\t' => canonical.push_str("\\t"),
+            '\n' => canonical.push_str("\\n"),
+            '\u{c}' => canonical.push_str("\\f"),
+            '\r' => canonical.push_str("\\r"),
+            c if c < ' ' => {
+                write!(canonical, "\\u{:04x}", c as u32).expect("can always write to string")
+            }
+            c => canonical.push(c),
+        }
+    }
+    canonical.push('"');
+}
+
+// object members are sorted by the utf-16 code units of their names, not by utf-8 bytes
+fn write_object(canonical: &mut String, object: &Map<String, Value>) -> Result<(), JcsError> {
+    let mut members: Vec<(&String, &Value)> = object.iter().collect();
+    members.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+
+    canonical.push('{');
+    for (i, (name, value)) in members.into_iter().enumerate() {
+        if i > 0 {
+            canonical.push(',');
+        }
+        write_string(canonical, name);
+        canonical.push(':');
+        write_value(canonical, value)?;
+    }
+    canonical.push('}');
+
+    Ok(())
+}
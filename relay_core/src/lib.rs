@@ -1,4 +1,10 @@
+pub mod archive;
+pub mod cbor;
+pub mod clock;
 pub mod crypto;
+pub mod filter;
+pub mod jcs;
 pub mod mailroom;
 pub mod message;
 pub mod payload;
+pub mod version;
@@ -1,20 +1,33 @@
 use std::{
+    cmp::Reverse,
     collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    sync::Arc,
     time::Duration,
 };
 
-use chrono::{DateTime, Timelike, Utc};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
-    crypto::{PublicKey, SecretKey, get_canon_json_bytes},
-    message::{Certificate, Envelope, Message, MessageContents},
+    archive::{ArchivedEnvelope, MessageQuery},
+    clock::{Clock, PeriodSchedule, SystemClock},
+    crypto::{KeySuccession, PublicKey, SecretKey},
+    filter::{Filter, FilterAction},
+    jcs,
+    message::{
+        Certificate, DirectLine, Envelope, ForwardingHop, Message, MessageContents, MessageKind,
+    },
     payload::TrustedPayload,
+    version::INITIAL_TTL_MESSAGE_VERSION,
 };
 
 pub const DEFAULT_INITIAL_TTL: u8 = 8;
 pub const DEFAULT_MAX_FORWARDING_TTL: u8 = 8;
 const HOUR_IN_SECONDS: u64 = 60 * 60;
+pub const DEFAULT_MAX_MESSAGE_AGE: Duration = Duration::from_secs(24 * HOUR_IN_SECONDS);
+pub const DEFAULT_CARRY_OVER_PERIODS: u32 = 1;
 
 #[derive(Error, Debug)]
 pub enum MailroomError<E> {
@@ -27,96 +40,287 @@ pub enum MailroomError<E> {
 pub struct Mailroom<L: GetNextLine, A: Archive<Error = E>, E> {
     line_generator: L,
     archive: A,
-    secret_key: SecretKey,
-    flatten_time: fn(DateTime<Utc>) -> DateTime<Utc>,
-    interval: Duration,
+    secret_key: Arc<SecretKey>,
+    key_successions: Vec<KeySuccession>,
+    clock: Arc<dyn Clock>,
+    schedule: PeriodSchedule,
+    max_message_age: Duration,
+    initial_ttl: u8,
+    filter: Option<Box<dyn Filter + Send>>,
+    stats: MailroomStats,
+    forwarding_limits: ForwardingLimits,
+    carry_over_periods: u32,
     new_messages: HashSet<Message>,
     forwarding_received_this_hour: HashMap<PublicKey, Vec<Envelope>>,
     forwarding_received_last_hour: HashMap<PublicKey, Vec<Envelope>>,
+    // senders whose clock runs ahead, and whose payload for the next period already came in
+    received_next_period: HashSet<PublicKey>,
     pub current_message: Option<Message>,
+    pub current_direct_message: Option<Message>,
+    queued_retractions: Vec<Message>,
+    pub current_retractions: Vec<Message>,
     last_seen_time: Option<DateTime<Utc>>,
 }
 
 impl<L: GetNextLine, A: Archive<Error = E>, E> Mailroom<L, A, E> {
     pub fn new(line_generator: L, archive: A, secret_key: SecretKey) -> Self {
-        let flatten_time = |datetime: DateTime<Utc>| {
-            datetime
-                .with_minute(0)
-                .expect("should be able to set any utc time to minute 0")
-                .with_second(0)
-                .expect("should be able to set any utc time to second 0")
-                .with_nanosecond(0)
-                .expect("should be able to set any utc time to nanosecond 0")
-        };
+        Self::new_with_clock(
+            line_generator,
+            archive,
+            secret_key,
+            Arc::new(SystemClock),
+            PeriodSchedule::HOURLY,
+        )
+    }
+
+    pub fn new_with_clock(
+        line_generator: L,
+        archive: A,
+        secret_key: SecretKey,
+        clock: Arc<dyn Clock>,
+        schedule: PeriodSchedule,
+    ) -> Self {
+        let now = clock.now();
 
         let mut mailroom = Mailroom {
             line_generator,
             archive,
-            secret_key,
-            flatten_time,
-            interval: Duration::from_secs(HOUR_IN_SECONDS),
+            secret_key: Arc::new(secret_key),
+            key_successions: vec![],
+            clock,
+            schedule,
+            max_message_age: DEFAULT_MAX_MESSAGE_AGE,
+            initial_ttl: DEFAULT_INITIAL_TTL,
+            filter: None,
+            stats: MailroomStats::default(),
+            forwarding_limits: ForwardingLimits::default(),
+            carry_over_periods: DEFAULT_CARRY_OVER_PERIODS,
             new_messages: HashSet::new(),
             forwarding_received_this_hour: HashMap::new(),
+            received_next_period: HashSet::new(),
             forwarding_received_last_hour: HashMap::new(),
             current_message: None,
+            current_direct_message: None,
+            queued_retractions: vec![],
+            current_retractions: vec![],
             last_seen_time: None,
         };
 
-        mailroom.set_new_message();
+        mailroom.set_new_message(now);
 
         mailroom
     }
 
-    #[cfg(feature = "chrono")]
-    pub fn new_with_custom_time(
-        line_generator: L,
-        archive: A,
-        secret_key: SecretKey,
-        flatten_time: fn(DateTime<Utc>) -> DateTime<Utc>,
-        interval: Duration,
-    ) -> Mailroom<L, A, E> {
-        let mut mailroom = Self::new(line_generator, archive, secret_key);
+    pub fn public_key(&self) -> PublicKey {
+        self.secret_key.public_key()
+    }
 
-        mailroom.flatten_time = flatten_time;
-        mailroom.interval = interval;
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
 
-        mailroom
+    pub fn schedule(&self) -> PeriodSchedule {
+        self.schedule
     }
 
-    pub async fn receive_payload(
-        &mut self,
-        payload: &TrustedPayload,
-    ) -> Result<(), MailroomError<E>> {
-        self.receive_payload_internal(payload, Utc::now()).await
+    pub fn period_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        self.schedule.period_start(now)
     }
 
-    #[cfg(feature = "chrono")]
-    pub async fn receive_payload_at_time(
-        &mut self,
-        payload: &TrustedPayload,
-        now: DateTime<Utc>,
-    ) -> Result<(), MailroomError<E>> {
-        self.receive_payload_internal(payload, now).await
+    pub fn accepted_periods(&self, now: DateTime<Utc>) -> RangeInclusive<DateTime<Utc>> {
+        self.schedule.accepted_periods(now)
+    }
+
+    pub fn set_max_message_age(&mut self, max_message_age: Duration) {
+        self.max_message_age = max_message_age;
+    }
+
+    // the ttl new messages are signed with, from the next period on
+    pub fn set_initial_ttl(&mut self, initial_ttl: u8) {
+        self.initial_ttl = initial_ttl;
+    }
+
+    pub fn set_carry_over_periods(&mut self, carry_over_periods: u32) {
+        self.carry_over_periods = carry_over_periods;
+    }
+
+    pub fn set_forwarding_limits(&mut self, forwarding_limits: ForwardingLimits) {
+        self.forwarding_limits = forwarding_limits;
+    }
+
+    pub fn set_filter<F: Filter + Send + 'static>(&mut self, filter: F) {
+        self.filter = Some(Box::new(filter));
+    }
+
+    pub fn clear_filter(&mut self) {
+        self.filter = None;
+    }
+
+    pub fn stats(&self) -> &MailroomStats {
+        &self.stats
+    }
+
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+
+    pub fn snapshot(&self) -> MailroomSnapshot {
+        MailroomSnapshot {
+            new_messages: self.new_messages.clone(),
+            forwarding_received_this_hour: self.forwarding_received_this_hour.clone(),
+            forwarding_received_last_hour: self.forwarding_received_last_hour.clone(),
+            received_next_period: self.received_next_period.clone(),
+            current_message: self.current_message.clone(),
+            current_direct_message: self.current_direct_message.clone(),
+            queued_retractions: self.queued_retractions.clone(),
+            current_retractions: self.current_retractions.clone(),
+            last_seen_at: self.last_seen_time.map(|time| time.timestamp()),
+            stats: self.stats.clone(),
+        }
+    }
+
+    // a snapshot from this period picks up where it left off. from an earlier period only what's
+    // still due to be forwarded and retracted carries over, the rest was made fresh at startup
+    pub fn restore(&mut self, snapshot: MailroomSnapshot) {
+        let Some(last_seen_time) = snapshot
+            .last_seen_at
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        else {
+            return;
+        };
+        let now = self.clock.now();
+
+        if self.schedule.period_start(now) == self.schedule.period_start(last_seen_time) {
+            let own_key = self.secret_key.public_key().to_string();
+            let is_own = |message: &Message| message.certificate.key == own_key;
+
+            self.new_messages = snapshot.new_messages;
+            self.forwarding_received_this_hour = snapshot.forwarding_received_this_hour;
+            self.forwarding_received_last_hour = snapshot.forwarding_received_last_hour;
+            self.received_next_period = snapshot.received_next_period;
+            // anything signed before a key rotation is left for the fresh messages to replace
+            if snapshot.current_message.as_ref().is_some_and(is_own) {
+                self.current_message = snapshot.current_message;
+            }
+            if snapshot.current_direct_message.as_ref().is_some_and(is_own) {
+                self.current_direct_message = snapshot.current_direct_message;
+            }
+            self.current_retractions = snapshot.current_retractions;
+            self.queued_retractions = snapshot.queued_retractions;
+            self.stats = snapshot.stats;
+            self.last_seen_time = Some(last_seen_time);
+        } else {
+            self.forwarding_received_last_hour = self.carry_over(
+                snapshot.forwarding_received_this_hour,
+                self.schedule.periods_between(last_seen_time, now),
+            );
+            if self.schedule.follows(last_seen_time, now) {
+                self.mark_received_early(snapshot.received_next_period);
+            }
+            self.queued_retractions = snapshot.queued_retractions;
+            self.take_queued_retractions(now);
+            self.stats = snapshot.stats;
+            self.stats.start_period();
+            self.last_seen_time = Some(now);
+        }
+    }
+
+    pub fn key_successions(&self) -> &Vec<KeySuccession> {
+        &self.key_successions
+    }
+
+    pub fn set_key_successions(&mut self, key_successions: Vec<KeySuccession>) {
+        self.key_successions = key_successions;
     }
 
-    async fn receive_payload_internal(
+    pub fn rotate_secret_key(&mut self, new_secret_key: SecretKey) -> KeySuccession {
+        let key_succession = KeySuccession::new(&self.secret_key, new_secret_key.public_key());
+
+        self.key_successions.push(key_succession.clone());
+        self.secret_key = Arc::new(new_secret_key);
+
+        key_succession
+    }
+
+    // retractions go out from the next period on, like lines. only lines signed with the current key
+    // can be retracted, since the retraction has to be signed by the same key
+    pub async fn retract_line(&mut self, line: &str) -> Result<Vec<Message>, MailroomError<E>> {
+        let retracting = self
+            .archive
+            .find_messages_with_line(&self.public_key().to_string(), line)
+            .await
+            .map_err(|e| MailroomError::ArchiveFailure(e))?
+            .into_iter()
+            .filter(|message| message.contents.kind == MessageKind::Line)
+            .collect::<Vec<Message>>();
+
+        self.queued_retractions.extend(retracting.iter().cloned());
+
+        Ok(retracting)
+    }
+
+    pub async fn receive_payload(
         &mut self,
         payload: &TrustedPayload,
-        now: DateTime<Utc>,
-    ) -> Result<(), MailroomError<E>> {
+    ) -> Result<ReceivedEnvelopes, MailroomError<E>> {
+        let now = self.clock.now();
         self.handle_time(now);
 
-        if self
-            .forwarding_received_this_hour
-            .contains_key(&payload.public_key)
-        {
+        // a sender whose clock runs a little ahead may already be in the next period. its payload
+        // is taken in this one, but counts as the one it sends in the next
+        let is_next_period =
+            self.schedule.period_start(payload.period) > self.schedule.period_start(now);
+        let already_received = if is_next_period {
+            self.received_next_period.contains(&payload.public_key)
+        } else {
+            self.forwarding_received_this_hour
+                .contains_key(&payload.public_key)
+        };
+        if already_received {
             return Err(MailroomError::AlreadyReceivedFromKey);
         }
 
         let mut forwarding_from_this_key = vec![];
+        let mut duplicates = 0;
+        let mut received_envelopes = ReceivedEnvelopes {
+            envelopes: vec![],
+            expired_envelopes: vec![],
+            dropped_envelopes: vec![],
+            quarantined_envelopes: vec![],
+            direct_lines: vec![],
+        };
 
         for envelope in &payload.envelopes {
+            if self.is_expired(&envelope.message, now) {
+                received_envelopes.expired_envelopes.push(envelope.clone());
+                continue;
+            }
+
+            // filtered envelopes are never archived, so they're never forwarded either
+            match self
+                .filter
+                .as_ref()
+                .map_or(FilterAction::Accept, |filter| filter.check(envelope))
+            {
+                FilterAction::Accept => {}
+                FilterAction::Drop => {
+                    received_envelopes.dropped_envelopes.push(envelope.clone());
+                    continue;
+                }
+                FilterAction::Quarantine => {
+                    self.archive
+                        .add_envelope_to_quarantine(&payload.certificate.key, envelope)
+                        .await
+                        .map_err(|e| MailroomError::ArchiveFailure(e))?;
+                    received_envelopes
+                        .quarantined_envelopes
+                        .push(envelope.clone());
+                    continue;
+                }
+            }
+
             if self.new_messages.contains(&envelope.message) {
+                duplicates += 1;
                 forwarding_from_this_key.push(envelope.clone());
             } else if !self
                 .archive
@@ -126,18 +330,46 @@ impl<L: GetNextLine, A: Archive<Error = E>, E> Mailroom<L, A, E> {
             {
                 self.new_messages.insert(envelope.message.clone());
                 forwarding_from_this_key.push(envelope.clone());
+
+                if let Some(direct_line) = self.open_direct_message(&envelope.message) {
+                    received_envelopes.direct_lines.push(direct_line);
+                }
+            } else {
+                duplicates += 1;
             }
 
             self.archive
                 .add_envelope_to_archive(&payload.certificate.key, envelope)
                 .await
                 .map_err(|e| MailroomError::ArchiveFailure(e))?;
+
+            received_envelopes.envelopes.push(envelope.clone());
         }
 
+        // anything with a ttl of one made its last hop getting here
+        let ttl_exhausted = forwarding_from_this_key
+            .iter()
+            .filter(|envelope| envelope.ttl <= 1)
+            .count();
+        self.stats.count(|counts| {
+            *counts.received_from.entry(payload.public_key).or_default() +=
+                payload.envelopes.len() as u64;
+            counts.duplicates += duplicates;
+            counts.expired += received_envelopes.expired_envelopes.len() as u64;
+            counts.dropped += received_envelopes.dropped_envelopes.len() as u64;
+            counts.quarantined += received_envelopes.quarantined_envelopes.len() as u64;
+            counts.ttl_exhausted += ttl_exhausted as u64;
+        });
+
+        if is_next_period {
+            self.received_next_period.insert(payload.public_key);
+        }
         self.forwarding_received_this_hour
-            .insert(payload.public_key, forwarding_from_this_key);
+            .entry(payload.public_key)
+            .or_default()
+            .extend(forwarding_from_this_key);
 
-        Ok(())
+        Ok(received_envelopes)
     }
 
     pub async fn get_outgoing(
@@ -145,51 +377,78 @@ impl<L: GetNextLine, A: Archive<Error = E>, E> Mailroom<L, A, E> {
         sending_to: &PublicKey,
         ttl_config: TTLConfig,
     ) -> Result<OutgoingEnvelopes, MailroomError<E>> {
-        self.get_outgoing_internal(sending_to, ttl_config, Utc::now())
-            .await
-    }
-
-    #[cfg(feature = "chrono")]
-    pub async fn get_outgoing_at_time(
-        &mut self,
-        sending_to: &PublicKey,
-        ttl_config: TTLConfig,
-        now: DateTime<Utc>,
-    ) -> Result<OutgoingEnvelopes, MailroomError<E>> {
-        self.get_outgoing_internal(sending_to, ttl_config, now)
-            .await
-    }
-
-    async fn get_outgoing_internal(
-        &mut self,
-        sending_to: &PublicKey,
-        ttl_config: TTLConfig,
-        now: DateTime<Utc>,
-    ) -> Result<OutgoingEnvelopes, MailroomError<E>> {
+        let now = self.clock.now();
         self.handle_time(now);
 
-        let mut sending_envelopes: Vec<Envelope> = self
+        let sending_to_key = sending_to.to_string();
+        let mut suppressed_envelopes = 0;
+
+        let mut forwardable_by_origin: Vec<(&PublicKey, Vec<&Envelope>)> = self
             .forwarding_received_last_hour
             .iter()
             .filter(|(from_key, _)| *from_key != sending_to)
-            .flat_map(|(_, envelopes)| envelopes.iter().cloned())
-            .filter_map(|mut envelope| {
-                envelope.ttl = ttl_config.max_forwarding_ttl.min(envelope.ttl - 1);
+            .map(|(from_key, envelopes)| {
+                let forwardable = envelopes
+                    .iter()
+                    .filter(|envelope| !self.is_expired(&envelope.message, now))
+                    // the author and every relay that forwarded it already have it
+                    .filter(|envelope| {
+                        let on_path = envelope.message.certificate.key == sending_to_key
+                            || envelope
+                                .forwarded
+                                .iter()
+                                .any(|hop| hop.key == sending_to_key);
+                        if on_path {
+                            suppressed_envelopes += 1;
+                        }
+
+                        !on_path
+                    })
+                    .filter(|envelope| ttl_config.forwarding_ttl(envelope) > 0)
+                    .collect();
+
+                (from_key, forwardable)
+            })
+            .collect();
+        // the map is in no particular order, so this keeps which origin goes first stable
+        forwardable_by_origin.sort_by_key(|(from_key, _)| from_key.to_string());
+
+        let (selected_envelopes, capped_envelopes) =
+            self.forwarding_limits.select(forwardable_by_origin);
+
+        let mut sending_envelopes: Vec<Envelope> = selected_envelopes
+            .into_iter()
+            .map(|envelope| {
+                let ttl = ttl_config.forwarding_ttl(envelope);
+                let hop = ForwardingHop::new(
+                    &self.secret_key,
+                    &envelope.message.certificate.signature,
+                    envelope.forwarded.last(),
+                    ttl,
+                );
+
+                let mut envelope = envelope.clone();
+                envelope.forwarded.push(hop);
+                envelope.ttl = ttl;
+
                 envelope
-                    .forwarded
-                    .push(self.secret_key.public_key().to_string());
-                if envelope.ttl > 0 {
-                    Some(envelope)
-                } else {
-                    None
-                }
             })
             .collect();
 
-        if let Some(current_message) = &self.current_message {
+        for current_message in [&self.current_message, &self.current_direct_message]
+            .into_iter()
+            .flatten()
+            .chain(&self.current_retractions)
+        {
+            // never more than the message was signed with, when the initial ttl was lowered since
             let envelope = Envelope {
                 forwarded: vec![],
-                ttl: ttl_config.initial_ttl,
+                ttl: current_message
+                    .contents
+                    .ttl
+                    .map_or(ttl_config.initial_ttl, |ttl| {
+                        ttl.min(ttl_config.initial_ttl)
+                    }),
                 message: current_message.clone(),
             };
 
@@ -201,66 +460,265 @@ impl<L: GetNextLine, A: Archive<Error = E>, E> Mailroom<L, A, E> {
             sending_envelopes.push(envelope);
         }
 
+        self.stats.count(|counts| {
+            *counts.sent_to.entry(*sending_to).or_default() += sending_envelopes.len() as u64;
+            counts.suppressed += suppressed_envelopes as u64;
+            counts.capped += capped_envelopes as u64;
+        });
+
         Ok(OutgoingEnvelopes {
             envelopes: sending_envelopes,
-            secret_key: self.secret_key.clone(),
+            suppressed_envelopes,
+            capped_envelopes,
+            secret_key: Arc::clone(&self.secret_key),
+            key_successions: self.key_successions.clone(),
+            recipient: *sending_to,
+            period: self.period_start(now),
         })
     }
 
     fn handle_time(&mut self, now: DateTime<Utc>) {
         if let Some(last_seen_time) = self.last_seen_time {
-            let now_flattened = (self.flatten_time)(now);
-            let last_seen_flattened = (self.flatten_time)(last_seen_time);
+            let now_period = self.schedule.period_start(now);
+            let last_seen_period = self.schedule.period_start(last_seen_time);
 
-            if now_flattened != last_seen_flattened {
+            if now_period != last_seen_period {
+                let received = std::mem::take(&mut self.forwarding_received_this_hour);
+                let received_next_period = std::mem::take(&mut self.received_next_period);
                 self.forwarding_received_last_hour =
-                    if now_flattened == last_seen_flattened + self.interval {
-                        self.forwarding_received_this_hour.clone()
-                    } else {
-                        HashMap::new()
-                    };
-                self.forwarding_received_this_hour = HashMap::new();
+                    self.carry_over(received, self.schedule.periods_between(last_seen_time, now));
+                if self.schedule.follows(last_seen_time, now) {
+                    self.mark_received_early(received_next_period);
+                }
                 self.new_messages = HashSet::new();
-                self.set_new_message();
+                self.stats.start_period();
+                self.set_new_message(now);
             }
         }
 
         self.last_seen_time = Some(now);
     }
 
-    fn set_new_message(&mut self) {
-        self.current_message = if let Some(next_line) = self.line_generator.get_next_line() {
-            let contents = MessageContents {
-                uuid: uuid::Uuid::new_v4().hyphenated().to_string(),
-                author: next_line.author.clone(),
-                line: next_line.line,
-            };
+    // their envelopes went out with the last period's, this only keeps them from sending twice
+    fn mark_received_early(&mut self, received_next_period: HashSet<PublicKey>) {
+        for public_key in received_next_period {
+            self.forwarding_received_this_hour
+                .insert(public_key, vec![]);
+        }
+    }
 
-            let contents_json = serde_json::to_string(&contents)
-                .expect("should be able to serialize any message contents to json");
+    // envelopes received in a period normally go out in the next one. when periods were missed they
+    // still go out within the window, losing a ttl for every period they had to wait
+    fn carry_over(
+        &self,
+        received: HashMap<PublicKey, Vec<Envelope>>,
+        periods_since: i64,
+    ) -> HashMap<PublicKey, Vec<Envelope>> {
+        if periods_since < 1 || periods_since > self.carry_over_periods as i64 {
+            return HashMap::new();
+        }
+        let waited = u8::try_from(periods_since - 1).unwrap_or(u8::MAX);
+
+        received
+            .into_iter()
+            .map(|(from_key, envelopes)| {
+                let envelopes = envelopes
+                    .into_iter()
+                    .filter_map(|mut envelope| {
+                        envelope.ttl = envelope.ttl.checked_sub(waited).filter(|ttl| *ttl > 0)?;
+                        Some(envelope)
+                    })
+                    .collect();
+
+                (from_key, envelopes)
+            })
+            .collect()
+    }
 
-            let contents_bytes = get_canon_json_bytes(&contents_json)
-                .expect("should be able to get canon bytes for any json string");
+    // a message dated further ahead than clocks are allowed to disagree would otherwise never expire
+    fn is_expired(&self, message: &Message, now: DateTime<Utc>) -> bool {
+        let age = now.timestamp().saturating_sub(message.contents.created_at);
+        let max_age = i64::try_from(self.max_message_age.as_secs()).unwrap_or(i64::MAX);
+        let max_skew = i64::try_from(self.schedule.max_skew().as_secs()).unwrap_or(i64::MAX);
 
-            let signature = self.secret_key.clone().sign(&contents_bytes);
+        age > max_age || age < -max_skew
+    }
+
+    fn set_new_message(&mut self, now: DateTime<Utc>) {
+        self.current_message = self.line_generator.get_next_line().map(|next_line| {
+            self.create_message(
+                MessageKind::Line,
+                next_line.author,
+                next_line.line,
+                None,
+                now,
+            )
+        });
+
+        // direct lines that can't be sealed to their recipient are dropped
+        let next_direct_line = self.line_generator.get_next_direct_line();
+        self.current_direct_message = next_direct_line.and_then(|next_direct_line| {
+            let sealed_line = next_direct_line
+                .recipient
+                .seal(next_direct_line.line.as_bytes())
+                .ok()?;
+
+            Some(self.create_message(
+                MessageKind::Direct,
+                next_direct_line.author,
+                sealed_line,
+                None,
+                now,
+            ))
+        });
+
+        self.take_queued_retractions(now);
+    }
 
-            Some(Message {
-                certificate: Certificate {
-                    key: self.secret_key.public_key().to_string(),
-                    signature,
-                },
-                contents,
+    fn take_queued_retractions(&mut self, now: DateTime<Utc>) {
+        self.current_retractions = self
+            .queued_retractions
+            .drain(..)
+            .collect::<Vec<Message>>()
+            .into_iter()
+            .map(|retracting| {
+                self.create_message(
+                    MessageKind::Retraction,
+                    retracting.contents.author,
+                    String::new(),
+                    Some(retracting.certificate.signature),
+                    now,
+                )
             })
-        } else {
-            None
+            .collect();
+    }
+
+    fn create_message(
+        &self,
+        kind: MessageKind,
+        author: String,
+        line: String,
+        retracts: Option<String>,
+        now: DateTime<Utc>,
+    ) -> Message {
+        let contents = MessageContents {
+            version: kind.version().max(INITIAL_TTL_MESSAGE_VERSION),
+            kind,
+            uuid: uuid::Uuid::new_v4().hyphenated().to_string(),
+            author,
+            line,
+            created_at: now.timestamp(),
+            retracts,
+            ttl: Some(self.initial_ttl),
+        };
+
+        let contents_json = serde_json::to_string(&contents)
+            .expect("should be able to serialize any message contents to json");
+
+        let contents_bytes = jcs::canonicalize(&contents_json)
+            .expect("should be able to get canon bytes for any json string");
+
+        let signature = self.secret_key.sign(&contents_bytes);
+
+        Message {
+            certificate: Certificate {
+                key: self.secret_key.public_key().to_string(),
+                signature,
+            },
+            contents,
         }
     }
+
+    fn open_direct_message(&self, message: &Message) -> Option<DirectLine> {
+        if message.contents.kind != MessageKind::Direct {
+            return None;
+        }
+
+        let line = self.secret_key.open(&message.contents.line).ok()?;
+
+        Some(DirectLine {
+            message: message.clone(),
+            line: String::from_utf8(line).ok()?,
+        })
+    }
+}
+
+// everything a mailroom keeps in memory between exchanges in a period
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MailroomSnapshot {
+    pub new_messages: HashSet<Message>,
+    pub forwarding_received_this_hour: HashMap<PublicKey, Vec<Envelope>>,
+    pub forwarding_received_last_hour: HashMap<PublicKey, Vec<Envelope>>,
+    pub received_next_period: HashSet<PublicKey>,
+    pub current_message: Option<Message>,
+    pub current_direct_message: Option<Message>,
+    pub queued_retractions: Vec<Message>,
+    pub current_retractions: Vec<Message>,
+    pub last_seen_at: Option<i64>,
+    pub stats: MailroomStats,
+}
+
+// counts for the current period, the last one before it and since the relay first started. the
+// sender runs right as a period starts, so previous is the one worth showing then
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MailroomStats {
+    pub period: EnvelopeCounts,
+    pub previous: EnvelopeCounts,
+    pub total: EnvelopeCounts,
+}
+
+impl MailroomStats {
+    fn count<F: Fn(&mut EnvelopeCounts)>(&mut self, update: F) {
+        update(&mut self.period);
+        update(&mut self.total);
+    }
+
+    fn start_period(&mut self) {
+        self.previous = std::mem::take(&mut self.period);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvelopeCounts {
+    pub received_from: HashMap<PublicKey, u64>,
+    pub sent_to: HashMap<PublicKey, u64>,
+    pub duplicates: u64,
+    pub expired: u64,
+    pub dropped: u64,
+    pub quarantined: u64,
+    pub ttl_exhausted: u64,
+    pub suppressed: u64,
+    pub capped: u64,
+}
+
+impl EnvelopeCounts {
+    pub fn received(&self) -> u64 {
+        self.received_from.values().sum()
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent_to.values().sum()
+    }
 }
 
 #[derive(Clone)]
 pub struct OutgoingEnvelopes {
     pub envelopes: Vec<Envelope>,
-    pub(crate) secret_key: SecretKey,
+    pub suppressed_envelopes: usize,
+    pub capped_envelopes: usize,
+    pub(crate) secret_key: Arc<SecretKey>,
+    pub(crate) key_successions: Vec<KeySuccession>,
+    pub(crate) recipient: PublicKey,
+    pub(crate) period: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReceivedEnvelopes {
+    pub envelopes: Vec<Envelope>,
+    pub expired_envelopes: Vec<Envelope>,
+    pub dropped_envelopes: Vec<Envelope>,
+    pub quarantined_envelopes: Vec<Envelope>,
+    pub direct_lines: Vec<DirectLine>,
 }
 
 #[derive(Clone, Copy)]
@@ -278,6 +736,12 @@ impl TTLConfig {
     }
 }
 
+impl TTLConfig {
+    fn forwarding_ttl(&self, envelope: &Envelope) -> u8 {
+        self.max_forwarding_ttl.min(envelope.ttl.saturating_sub(1))
+    }
+}
+
 impl Default for TTLConfig {
     fn default() -> Self {
         Self {
@@ -287,14 +751,74 @@ impl Default for TTLConfig {
     }
 }
 
+// caps only apply to forwarded envelopes, the relay's own messages always go out on top
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ForwardingLimits {
+    pub max_envelopes_per_payload: Option<usize>,
+    pub max_envelopes_per_origin: Option<usize>,
+}
+
+impl ForwardingLimits {
+    // takes one envelope from each origin in turn so a chatty relay can't crowd out the rest, each
+    // round going highest ttl first so the envelopes with furthest left to go win any cut
+    fn select<'a>(
+        &self,
+        envelopes_by_origin: Vec<(&PublicKey, Vec<&'a Envelope>)>,
+    ) -> (Vec<&'a Envelope>, usize) {
+        let total = envelopes_by_origin
+            .iter()
+            .map(|(_, envelopes)| envelopes.len())
+            .sum::<usize>();
+        let max_per_origin = self.max_envelopes_per_origin.unwrap_or(usize::MAX);
+        let max_per_payload = self.max_envelopes_per_payload.unwrap_or(usize::MAX);
+
+        let mut queues: Vec<Vec<&Envelope>> = envelopes_by_origin
+            .into_iter()
+            .map(|(_, mut envelopes)| {
+                envelopes.sort_by_key(|envelope| Reverse(envelope.ttl));
+                envelopes.truncate(max_per_origin);
+                envelopes.reverse();
+                envelopes
+            })
+            .collect();
+
+        let mut selected = vec![];
+        while selected.len() < max_per_payload {
+            let mut round: Vec<&Envelope> = queues.iter_mut().filter_map(Vec::pop).collect();
+            if round.is_empty() {
+                break;
+            }
+
+            round.sort_by_key(|envelope| Reverse(envelope.ttl));
+            round.truncate(max_per_payload - selected.len());
+            selected.extend(round);
+        }
+
+        let capped = total - selected.len();
+        (selected, capped)
+    }
+}
+
 #[derive(Clone)]
 pub struct NextLine {
     pub line: String,
     pub author: String,
 }
 
+#[derive(Clone)]
+pub struct NextDirectLine {
+    pub recipient: PublicKey,
+    pub line: String,
+    pub author: String,
+}
+
 pub trait GetNextLine {
     fn get_next_line(&mut self) -> Option<NextLine>;
+
+    // asked for once at the start of each period, so one direct line goes out per period
+    fn get_next_direct_line(&mut self) -> Option<NextDirectLine> {
+        None
+    }
 }
 
 #[trait_variant::make(Archive: Send)]
@@ -308,4 +832,24 @@ pub trait ArchiveLocal {
         from: &str,
         envelope: &Envelope,
     ) -> Result<(), Self::Error>;
+
+    async fn add_envelope_to_quarantine(
+        &mut self,
+        from: &str,
+        envelope: &Envelope,
+    ) -> Result<(), Self::Error>;
+
+    async fn find_messages_with_line(
+        &self,
+        key: &str,
+        line: &str,
+    ) -> Result<Vec<Message>, Self::Error>;
+
+    async fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, Self::Error>;
+
+    // every envelope the message arrived in, each with the path it took
+    async fn find_envelopes_of_message(
+        &self,
+        signature: &str,
+    ) -> Result<Vec<ArchivedEnvelope>, Self::Error>;
 }
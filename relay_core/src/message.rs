@@ -1,8 +1,14 @@
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    crypto::SecretKey,
+    jcs,
+    version::{DIRECT_MESSAGE_VERSION, LINE_MESSAGE_VERSION, RETRACTION_MESSAGE_VERSION},
+};
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Envelope {
-    pub forwarded: Vec<String>,
+    pub forwarded: Vec<ForwardingHop>,
     pub ttl: u8,
     pub message: Message,
 }
@@ -15,9 +21,50 @@ pub struct Message {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct MessageContents {
+    pub version: u16,
+    #[serde(default, skip_serializing_if = "MessageKind::is_line")]
+    pub kind: MessageKind,
     pub uuid: String,
     pub author: String,
     pub line: String,
+    pub created_at: i64,
+    // certificate signature of the message a retraction takes back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retracts: Option<String>,
+    // signed by the author, so no relay on the way can send the message further than it was meant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u8>,
+}
+
+// line messages leave the kind out, so their contents are the same as before there were kinds
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    #[default]
+    Line,
+    Direct,
+    Retraction,
+}
+
+impl MessageKind {
+    pub fn is_line(&self) -> bool {
+        *self == MessageKind::Line
+    }
+
+    pub fn version(&self) -> u16 {
+        match self {
+            MessageKind::Line => LINE_MESSAGE_VERSION,
+            MessageKind::Direct => DIRECT_MESSAGE_VERSION,
+            MessageKind::Retraction => RETRACTION_MESSAGE_VERSION,
+        }
+    }
+}
+
+// a direct message opened by its recipient, the line in the message itself stays sealed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectLine {
+    pub message: Message,
+    pub line: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
@@ -25,3 +72,55 @@ pub struct Certificate {
     pub key: String,
     pub signature: String,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ForwardingHop {
+    pub key: String,
+    pub ttl: u8,
+    pub signature: String,
+}
+
+impl ForwardingHop {
+    pub(crate) fn new(
+        secret_key: &SecretKey,
+        message_signature: &str,
+        previous_hop: Option<&ForwardingHop>,
+        ttl: u8,
+    ) -> Self {
+        let signature = secret_key.sign(&get_hop_bytes(
+            message_signature,
+            previous_hop.map(|hop| hop.signature.as_str()),
+            ttl,
+        ));
+
+        Self {
+            key: secret_key.public_key().to_string(),
+            ttl,
+            signature,
+        }
+    }
+}
+
+// each hop signs the signature of the hop before it, so the whole path is chained to the message
+#[derive(Serialize)]
+struct HopSignedContents<'a> {
+    message_signature: &'a str,
+    previous_hop: Option<&'a str>,
+    ttl: u8,
+}
+
+pub(crate) fn get_hop_bytes(
+    message_signature: &str,
+    previous_hop_signature: Option<&str>,
+    ttl: u8,
+) -> Vec<u8> {
+    let contents_json = serde_json::to_string(&HopSignedContents {
+        message_signature,
+        previous_hop: previous_hop_signature,
+        ttl,
+    })
+    .expect("should be able to serialize any hop to json");
+
+    jcs::canonicalize(&contents_json)
+        .expect("should be able to get canon bytes for any json string")
+}
@@ -1,29 +1,50 @@
+use std::{iter, ops::RangeInclusive};
+
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 use thiserror::Error;
 
 use crate::{
-    crypto::{PublicKey, get_canon_json_bytes},
+    cbor,
+    crypto::{KeySuccession, PublicKey, verify_batch},
+    jcs,
     mailroom::OutgoingEnvelopes,
-    message::{Certificate, Envelope, Message},
+    message::{
+        Certificate, Envelope, ForwardingHop, Message, MessageContents, MessageKind, get_hop_bytes,
+    },
+    version::{self, PROTOCOL_VERSION},
 };
 
 #[derive(Error, Debug)]
 pub enum UntrustedPayloadError {
-    #[error("public key in certificate of payload or message is malformed")]
+    #[error("public key in certificate of payload is malformed")]
     MalformedPublicKey,
     #[error("public key in certificate of payload is not trusted")]
     PublicKeyNotTrusted,
+    #[error("key succession in payload cannot be verified")]
+    InvalidKeySuccession,
     #[error("cannot parse json")]
     CannotParseJson,
     #[error("cannot verify payload certificate")]
     CannotVerify,
+    #[error("payload is addressed to a different relay")]
+    WrongRecipient,
+    #[error("payload was created for a different period")]
+    StalePeriod,
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u16),
 }
 
 #[derive(Deserialize)]
 pub struct UntrustedPayload<'a> {
+    version: u16,
     certificate: Certificate,
+    #[serde(default)]
+    successions: Vec<KeySuccession>,
+    recipient: String,
+    period: i64,
     #[serde(rename(deserialize = "envelopes"))]
     #[serde(borrow)]
     envelopes_raw_value: &'a RawValue,
@@ -31,12 +52,52 @@ pub struct UntrustedPayload<'a> {
 
 impl<'a> UntrustedPayload<'a> {
     pub fn from_json(json_str: &'a str) -> Result<Self, UntrustedPayloadError> {
+        let versioned: Versioned =
+            serde_json::from_str(json_str).map_err(|_| UntrustedPayloadError::CannotParseJson)?;
+
+        if !version::is_supported(versioned.version) {
+            return Err(UntrustedPayloadError::UnsupportedVersion(versioned.version));
+        }
+
         serde_json::from_str(json_str).map_err(|_| UntrustedPayloadError::CannotParseJson)
     }
 
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
     pub fn try_trust<I>(
         self,
         trusted_public_keys: I,
+        recipient: &PublicKey,
+        accepted_periods: RangeInclusive<DateTime<Utc>>,
+    ) -> Result<TrustedPayload, UntrustedPayloadError>
+    where
+        I: IntoIterator<Item = PublicKey>,
+    {
+        self.try_trust_internal(trusted_public_keys, recipient, accepted_periods, true)
+    }
+
+    // only here so the bench can compare against batching
+    #[cfg(feature = "bench")]
+    pub fn try_trust_without_batching<I>(
+        self,
+        trusted_public_keys: I,
+        recipient: &PublicKey,
+        accepted_periods: RangeInclusive<DateTime<Utc>>,
+    ) -> Result<TrustedPayload, UntrustedPayloadError>
+    where
+        I: IntoIterator<Item = PublicKey>,
+    {
+        self.try_trust_internal(trusted_public_keys, recipient, accepted_periods, false)
+    }
+
+    fn try_trust_internal<I>(
+        self,
+        trusted_public_keys: I,
+        recipient: &PublicKey,
+        accepted_periods: RangeInclusive<DateTime<Utc>>,
+        batch_verification: bool,
     ) -> Result<TrustedPayload, UntrustedPayloadError>
     where
         I: IntoIterator<Item = PublicKey>,
@@ -45,65 +106,268 @@ impl<'a> UntrustedPayload<'a> {
             return Err(UntrustedPayloadError::MalformedPublicKey);
         };
 
-        if !trusted_public_keys
-            .into_iter()
-            .any(|key| key == claimed_public_key)
-        {
-            return Err(UntrustedPayloadError::PublicKeyNotTrusted);
-        }
+        let trusted_public_keys: Vec<PublicKey> = trusted_public_keys.into_iter().collect();
+
+        let succeeded_from = if trusted_public_keys.contains(&claimed_public_key) {
+            None
+        } else {
+            match self.find_trusted_predecessor(&trusted_public_keys, &claimed_public_key)? {
+                Some(trusted_key) => Some(trusted_key),
+                None => return Err(UntrustedPayloadError::PublicKeyNotTrusted),
+            }
+        };
+
+        let signed_contents_json = serde_json::to_string(&PayloadSignedContents {
+            version: self.version,
+            recipient: &self.recipient,
+            period: self.period,
+            envelopes: self.envelopes_raw_value,
+        })
+        .map_err(|_| UntrustedPayloadError::CannotParseJson)?;
 
         check_signature(
             &self.certificate.signature,
             claimed_public_key,
-            self.envelopes_raw_value,
+            &signed_contents_json,
         )?;
 
-        let unverified_envelopes: Vec<UnverifiedEnvelope> =
-            serde_json::from_str(self.envelopes_raw_value.get())
-                .map_err(|_| UntrustedPayloadError::CannotParseJson)?;
+        if PublicKey::new_from_b64(&self.recipient)
+            .map_err(|_| UntrustedPayloadError::MalformedPublicKey)?
+            != *recipient
+        {
+            return Err(UntrustedPayloadError::WrongRecipient);
+        }
+
+        let Some(period) = DateTime::from_timestamp(self.period, 0)
+            .filter(|period| accepted_periods.contains(period))
+        else {
+            return Err(UntrustedPayloadError::StalePeriod);
+        };
+
+        let raw_envelopes: Vec<&RawValue> = serde_json::from_str(self.envelopes_raw_value.get())
+            .map_err(|_| UntrustedPayloadError::CannotParseJson)?;
+
+        let sender_keys = self.sender_keys(&claimed_public_key);
+        let mut checkable_envelopes = vec![];
+        let mut rejected_envelopes = vec![];
+
+        for (index, raw_envelope) in raw_envelopes.into_iter().enumerate() {
+            match self.check_envelope(raw_envelope, &sender_keys) {
+                Ok(checkable_envelope) => checkable_envelopes.push((index, checkable_envelope)),
+                Err((certificate_key, reason)) => rejected_envelopes.push(RejectedEnvelope {
+                    index,
+                    certificate_key,
+                    reason,
+                }),
+            }
+        }
+
+        // batch verification is all or nothing, so if it fails we fall back to checking each
+        // envelope by itself to find out which ones are bad
+        let all_verified = batch_verification
+            && verify_batch(
+                &checkable_envelopes
+                    .iter()
+                    .flat_map(|(_, checkable_envelope)| {
+                        iter::once((
+                            checkable_envelope.contents_bytes.as_slice(),
+                            checkable_envelope.certificate.signature.as_str(),
+                            checkable_envelope.key,
+                        ))
+                        .chain(
+                            checkable_envelope
+                                .forwarded
+                                .iter()
+                                .zip(&checkable_envelope.hop_checks)
+                                .map(|(hop, (hop_bytes, hop_key))| {
+                                    (hop_bytes.as_slice(), hop.signature.as_str(), *hop_key)
+                                }),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .is_ok();
 
         let mut envelopes = vec![];
-        let mut unverified_messages_count = 0;
-
-        for unverified_envelope in unverified_envelopes {
-            match check_signature(
-                &unverified_envelope.unverified_message.certificate.signature,
-                PublicKey::new_from_b64(&unverified_envelope.unverified_message.certificate.key)
-                    .map_err(|_| UntrustedPayloadError::MalformedPublicKey)?,
-                unverified_envelope.unverified_message.contents_raw_json,
-            ) {
-                Ok(()) => envelopes.push(Envelope {
-                    forwarded: unverified_envelope.forwarded,
-                    ttl: unverified_envelope.ttl,
+
+        for (index, checkable_envelope) in checkable_envelopes {
+            let rejection_reason = if all_verified {
+                None
+            } else if checkable_envelope
+                .key
+                .verify(
+                    &checkable_envelope.contents_bytes,
+                    &checkable_envelope.certificate.signature,
+                )
+                .is_err()
+            {
+                Some(EnvelopeRejectionReason::BadSignature)
+            } else if checkable_envelope
+                .forwarded
+                .iter()
+                .zip(&checkable_envelope.hop_checks)
+                .any(|(hop, (hop_bytes, hop_key))| {
+                    hop_key.verify(hop_bytes, &hop.signature).is_err()
+                })
+            {
+                Some(EnvelopeRejectionReason::InvalidForwardingHops)
+            } else {
+                None
+            };
+
+            match rejection_reason {
+                None => envelopes.push(Envelope {
+                    forwarded: checkable_envelope.forwarded,
+                    ttl: checkable_envelope.ttl,
                     message: Message {
-                        certificate: unverified_envelope.unverified_message.certificate,
-                        contents: serde_json::from_str(
-                            unverified_envelope
-                                .unverified_message
-                                .contents_raw_json
-                                .get(),
-                        )
-                        .map_err(|_| UntrustedPayloadError::CannotParseJson)?,
+                        certificate: checkable_envelope.certificate,
+                        contents: checkable_envelope.contents,
                     },
                 }),
-                Err(UntrustedPayloadError::CannotVerify) => unverified_messages_count += 1,
-                Err(e) => return Err(e),
+                Some(reason) => rejected_envelopes.push(RejectedEnvelope {
+                    index,
+                    certificate_key: Some(checkable_envelope.certificate.key),
+                    reason,
+                }),
             }
         }
 
+        rejected_envelopes.sort_by_key(|rejected_envelope| rejected_envelope.index);
+
         Ok(TrustedPayload {
+            version: self.version,
             public_key: claimed_public_key,
             certificate: self.certificate,
+            period,
             envelopes,
-            unverified_messages_count,
+            rejected_envelopes,
+            succeeded_from,
+        })
+    }
+
+    fn check_envelope(
+        &self,
+        raw_envelope: &RawValue,
+        sender_keys: &[PublicKey],
+    ) -> Result<CheckableEnvelope, (Option<String>, EnvelopeRejectionReason)> {
+        let unverified_envelope: UnverifiedEnvelope = serde_json::from_str(raw_envelope.get())
+            .map_err(|_| (None, EnvelopeRejectionReason::UnparsableContents))?;
+        let certificate = unverified_envelope.unverified_message.certificate;
+        let reject = |reason| (Some(certificate.key.clone()), reason);
+
+        let ttl = match serde_json::from_str(unverified_envelope.ttl_raw_json.get()) {
+            Ok(ttl) if ttl > 0 => ttl,
+            _ => return Err(reject(EnvelopeRejectionReason::InvalidTtl)),
+        };
+
+        let key = PublicKey::new_from_b64(&certificate.key)
+            .map_err(|_| reject(EnvelopeRejectionReason::MalformedKey))?;
+
+        let contents_raw_json = unverified_envelope
+            .unverified_message
+            .contents_raw_json
+            .get();
+        let contents_bytes = jcs::canonicalize(contents_raw_json)
+            .map_err(|_| reject(EnvelopeRejectionReason::UnparsableContents))?;
+        let contents: MessageContents = serde_json::from_str(contents_raw_json)
+            .map_err(|_| reject(EnvelopeRejectionReason::UnparsableContents))?;
+
+        if !version::is_supported(contents.version) || contents.version > self.version {
+            return Err(reject(EnvelopeRejectionReason::UnsupportedVersion(
+                contents.version,
+            )));
+        }
+
+        // a kind the claimed version doesn't have would be mangled by relays on that version
+        if contents.version < contents.kind.version() {
+            return Err(reject(EnvelopeRejectionReason::UnparsableContents));
+        }
+
+        if (contents.kind == MessageKind::Retraction) != contents.retracts.is_some() {
+            return Err(reject(EnvelopeRejectionReason::UnparsableContents));
+        }
+
+        if (contents.version >= version::INITIAL_TTL_MESSAGE_VERSION) != contents.ttl.is_some() {
+            return Err(reject(EnvelopeRejectionReason::UnparsableContents));
+        }
+
+        let hop_checks = check_hops(
+            &certificate.signature,
+            &unverified_envelope.forwarded,
+            ttl,
+            contents.ttl,
+            key,
+            sender_keys,
+        )
+        .map_err(reject)?;
+
+        Ok(CheckableEnvelope {
+            forwarded: unverified_envelope.forwarded,
+            hop_checks,
+            ttl,
+            key,
+            certificate,
+            contents,
+            contents_bytes,
         })
     }
+
+    // keys the sender may have signed its own messages and hops with before rotating to its
+    // current key
+    fn sender_keys(&self, claimed_public_key: &PublicKey) -> Vec<PublicKey> {
+        let mut sender_keys = vec![*claimed_public_key];
+
+        while let Some(succession) = self.successions.iter().find(|succession| {
+            sender_keys.last() == Some(succession.new_key())
+                && !sender_keys.contains(succession.old_key())
+                && succession.verify().is_ok()
+        }) {
+            sender_keys.push(*succession.old_key());
+        }
+
+        sender_keys
+    }
+
+    fn find_trusted_predecessor(
+        &self,
+        trusted_public_keys: &[PublicKey],
+        claimed_public_key: &PublicKey,
+    ) -> Result<Option<PublicKey>, UntrustedPayloadError> {
+        let mut chain: Option<(PublicKey, PublicKey)> = None;
+
+        for succession in &self.successions {
+            succession
+                .verify()
+                .map_err(|_| UntrustedPayloadError::InvalidKeySuccession)?;
+
+            chain = match chain {
+                Some((trusted_key, latest_key)) if latest_key == *succession.old_key() => {
+                    Some((trusted_key, *succession.new_key()))
+                }
+                _ if trusted_public_keys.contains(succession.old_key()) => {
+                    Some((*succession.old_key(), *succession.new_key()))
+                }
+                chain => chain,
+            };
+        }
+
+        Ok(chain
+            .filter(|(_, latest_key)| latest_key == claimed_public_key)
+            .map(|(trusted_key, _)| trusted_key))
+    }
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    version: u16,
 }
 
 #[derive(Deserialize)]
 struct UnverifiedEnvelope<'a> {
-    forwarded: Vec<String>,
-    ttl: u8,
+    forwarded: Vec<ForwardingHop>,
+    #[serde(rename(deserialize = "ttl"))]
+    #[serde(borrow)]
+    ttl_raw_json: &'a RawValue,
     #[serde(rename(deserialize = "message"))]
     #[serde(borrow)]
     unverified_message: UnverifiedMessage<'a>,
@@ -117,14 +381,68 @@ struct UnverifiedMessage<'a> {
     contents_raw_json: &'a RawValue,
 }
 
+struct CheckableEnvelope {
+    forwarded: Vec<ForwardingHop>,
+    hop_checks: Vec<(Vec<u8>, PublicKey)>,
+    ttl: u8,
+    key: PublicKey,
+    certificate: Certificate,
+    contents: MessageContents,
+    contents_bytes: Vec<u8>,
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeRejectionReason {
+    #[error("message signature cannot be verified")]
+    BadSignature,
+    #[error("message certificate key is malformed")]
+    MalformedKey,
+    #[error("message contents cannot be parsed")]
+    UnparsableContents,
+    #[error("envelope ttl is invalid")]
+    InvalidTtl,
+    #[error("message has unsupported protocol version {0}")]
+    UnsupportedVersion(u16),
+    #[error("forwarding hops cannot be verified")]
+    InvalidForwardingHops,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RejectedEnvelope {
+    pub(crate) index: usize,
+    pub(crate) certificate_key: Option<String>,
+    pub(crate) reason: EnvelopeRejectionReason,
+}
+
+impl RejectedEnvelope {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn certificate_key(&self) -> Option<&str> {
+        self.certificate_key.as_deref()
+    }
+
+    pub fn reason(&self) -> EnvelopeRejectionReason {
+        self.reason
+    }
+}
+
 pub struct TrustedPayload {
+    pub(crate) version: u16,
     pub(crate) public_key: PublicKey,
     pub(crate) certificate: Certificate,
+    pub(crate) period: DateTime<Utc>,
     pub(crate) envelopes: Vec<Envelope>,
-    pub(crate) unverified_messages_count: u32,
+    pub(crate) rejected_envelopes: Vec<RejectedEnvelope>,
+    pub(crate) succeeded_from: Option<PublicKey>,
 }
 
 impl TrustedPayload {
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
     pub fn public_key(&self) -> &PublicKey {
         &self.public_key
     }
@@ -133,31 +451,66 @@ impl TrustedPayload {
         &self.certificate
     }
 
+    pub fn period(&self) -> DateTime<Utc> {
+        self.period
+    }
+
     pub fn envelopes(&self) -> &Vec<Envelope> {
         &self.envelopes
     }
 
-    pub fn unverified_messages_count(&self) -> u32 {
-        self.unverified_messages_count
+    pub fn rejected_envelopes(&self) -> &Vec<RejectedEnvelope> {
+        &self.rejected_envelopes
+    }
+
+    pub fn succeeded_from(&self) -> Option<&PublicKey> {
+        self.succeeded_from.as_ref()
     }
 }
 
 impl OutgoingEnvelopes {
     pub fn create_payload(&self) -> String {
-        let envelopes_json = serde_json::to_string(&self.envelopes)
-            .expect("should be able to serialize any envelopes to json");
+        self.create_payload_with_version(PROTOCOL_VERSION)
+    }
+
+    pub fn create_cbor_payload_with_version(&self, version: u16) -> Vec<u8> {
+        cbor::from_json(&self.create_payload_with_version(version))
+            .expect("should be able to encode any payload as cbor")
+    }
 
-        let envelopes_bytes = get_canon_json_bytes(&envelopes_json)
+    // messages newer than the payload version are left out, since the recipient can't read them
+    pub fn create_payload_with_version(&self, version: u16) -> String {
+        let recipient = self.recipient.to_string();
+        let period = self.period.timestamp();
+        let envelopes: Vec<&Envelope> = self
+            .envelopes
+            .iter()
+            .filter(|envelope| envelope.message.contents.version <= version)
+            .collect();
+
+        let signed_contents_json = serde_json::to_string(&PayloadSignedContents {
+            version,
+            recipient: &recipient,
+            period,
+            envelopes: &envelopes,
+        })
+        .expect("should be able to serialize any envelopes to json");
+
+        let signed_contents_bytes = jcs::canonicalize(&signed_contents_json)
             .expect("should be able to get canon bytes for any json string");
 
-        let signature = self.secret_key.clone().sign(&envelopes_bytes);
+        let signature = self.secret_key.sign(&signed_contents_bytes);
 
         let outgoing_payload = OutgoingPayload {
+            version,
             certificate: Certificate {
                 key: self.secret_key.public_key().to_string(),
                 signature,
             },
-            envelopes: &self.envelopes,
+            successions: &self.key_successions,
+            recipient,
+            period,
+            envelopes: &envelopes,
         };
 
         serde_json::to_string(&outgoing_payload)
@@ -167,17 +520,88 @@ impl OutgoingEnvelopes {
 
 #[derive(Serialize)]
 struct OutgoingPayload<'a> {
+    version: u16,
     certificate: Certificate,
-    envelopes: &'a Vec<Envelope>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    successions: &'a Vec<KeySuccession>,
+    recipient: String,
+    period: i64,
+    envelopes: &'a Vec<&'a Envelope>,
+}
+
+#[derive(Serialize)]
+struct PayloadSignedContents<'a, E: ?Sized> {
+    version: u16,
+    recipient: &'a str,
+    period: i64,
+    envelopes: &'a E,
+}
+
+// the hops have to chain from the message to the sender of the payload, each lowering the ttl from
+// the one the author signed, with the last one matching the ttl of the envelope. messages from
+// before the author signed a ttl can only be checked from the first hop on. returns what each hop
+// signature has to be checked against
+fn check_hops(
+    message_signature: &str,
+    hops: &[ForwardingHop],
+    ttl: u8,
+    initial_ttl: Option<u8>,
+    message_key: PublicKey,
+    sender_keys: &[PublicKey],
+) -> Result<Vec<(Vec<u8>, PublicKey)>, EnvelopeRejectionReason> {
+    let Some(last_hop) = hops.last() else {
+        if initial_ttl.is_some_and(|initial_ttl| ttl > initial_ttl) {
+            return Err(EnvelopeRejectionReason::InvalidTtl);
+        }
+
+        return match sender_keys.contains(&message_key) {
+            true => Ok(vec![]),
+            false => Err(EnvelopeRejectionReason::InvalidForwardingHops),
+        };
+    };
+
+    if last_hop.ttl != ttl {
+        return Err(EnvelopeRejectionReason::InvalidTtl);
+    }
+
+    let mut hop_checks = vec![];
+    let mut previous_hop: Option<&ForwardingHop> = None;
+
+    for hop in hops {
+        let hop_key = PublicKey::new_from_b64(&hop.key)
+            .map_err(|_| EnvelopeRejectionReason::InvalidForwardingHops)?;
+
+        let previous_ttl = previous_hop.map_or(initial_ttl, |previous_hop| Some(previous_hop.ttl));
+        if hop.ttl == 0 || previous_ttl.is_some_and(|previous_ttl| hop.ttl >= previous_ttl) {
+            return Err(EnvelopeRejectionReason::InvalidTtl);
+        }
+
+        hop_checks.push((
+            get_hop_bytes(
+                message_signature,
+                previous_hop.map(|previous_hop| previous_hop.signature.as_str()),
+                hop.ttl,
+            ),
+            hop_key,
+        ));
+        previous_hop = Some(hop);
+    }
+
+    match hop_checks
+        .last()
+        .is_some_and(|(_, hop_key)| sender_keys.contains(hop_key))
+    {
+        true => Ok(hop_checks),
+        false => Err(EnvelopeRejectionReason::InvalidForwardingHops),
+    }
 }
 
 fn check_signature(
     signature: &str,
     key: PublicKey,
-    raw_value: &RawValue,
+    json_str: &str,
 ) -> Result<(), UntrustedPayloadError> {
-    let bytes = get_canon_json_bytes(raw_value.get())
-        .map_err(|_| UntrustedPayloadError::CannotParseJson)?;
+    let bytes = jcs::canonicalize(json_str).map_err(|_| UntrustedPayloadError::CannotParseJson)?;
 
     key.verify(&bytes, signature)
         .map_err(|_| UntrustedPayloadError::CannotVerify)?;
@@ -0,0 +1,23 @@
+pub const PROTOCOL_VERSION: u16 = 3;
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u16] = &[1, 2, 3];
+// messages get the oldest version that can carry them, so peers on older versions still get every
+// message they can read
+pub const LINE_MESSAGE_VERSION: u16 = 1;
+pub const DIRECT_MESSAGE_VERSION: u16 = 2;
+pub const RETRACTION_MESSAGE_VERSION: u16 = 2;
+// from this version on every message carries the ttl its author sent it out with
+pub const INITIAL_TTL_MESSAGE_VERSION: u16 = 3;
+
+pub fn is_supported(version: u16) -> bool {
+    SUPPORTED_PROTOCOL_VERSIONS.contains(&version)
+}
+
+pub fn highest_common_version<I>(other_versions: I) -> Option<u16>
+where
+    I: IntoIterator<Item = u16>,
+{
+    other_versions
+        .into_iter()
+        .filter(|version| is_supported(*version))
+        .max()
+}
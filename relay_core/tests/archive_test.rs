@@ -0,0 +1,328 @@
+use std::{fs, io::Write, path::PathBuf};
+
+use relay_core::{
+    archive::{ArchivedEnvelope, JsonlArchive, MemoryArchive, MessageQuery},
+    crypto::SecretKey,
+    mailroom::{Archive, GetNextLine, Mailroom, NextLine, TTLConfig},
+    message::{Envelope, ForwardingHop, Message},
+    payload::UntrustedPayload,
+};
+
+struct Repeat(&'static str);
+
+impl GetNextLine for Repeat {
+    fn get_next_line(&mut self) -> Option<NextLine> {
+        Some(NextLine {
+            line: self.0.to_owned(),
+            author: "test".to_owned(),
+        })
+    }
+}
+
+async fn own_envelope() -> Envelope {
+    let mut mailroom = Mailroom::new(Repeat("hello"), MemoryArchive::new(), SecretKey::generate());
+    let outgoing = mailroom
+        .get_outgoing(&SecretKey::generate().public_key(), TTLConfig::default())
+        .await
+        .unwrap();
+
+    outgoing.envelopes[0].clone()
+}
+
+fn temp_path() -> PathBuf {
+    std::env::temp_dir().join(format!("relay-archive-{}.jsonl", uuid::Uuid::new_v4()))
+}
+
+#[tokio::test]
+async fn memory_archive_dedups_by_signature() {
+    let envelope = own_envelope().await;
+    let mut archive = MemoryArchive::new();
+
+    assert!(
+        !archive
+            .is_message_in_archive(&envelope.message)
+            .await
+            .unwrap()
+    );
+
+    archive
+        .add_envelope_to_archive("a", &envelope)
+        .await
+        .unwrap();
+    archive
+        .add_envelope_to_archive("b", &envelope)
+        .await
+        .unwrap();
+
+    assert!(
+        archive
+            .is_message_in_archive(&envelope.message)
+            .await
+            .unwrap()
+    );
+    assert_eq!(archive.messages().len(), 1);
+    assert_eq!(archive.envelopes().len(), 2);
+    assert_eq!(
+        archive
+            .find_messages_with_line(&envelope.message.certificate.key, "hello")
+            .await
+            .unwrap(),
+        vec![envelope.message]
+    );
+}
+
+#[tokio::test]
+async fn jsonl_archive_survives_reopening() {
+    let path = temp_path();
+    let envelope = own_envelope().await;
+    let quarantined = own_envelope().await;
+
+    let mut archive = JsonlArchive::open(&path).unwrap();
+    archive
+        .add_envelope_to_archive("a", &envelope)
+        .await
+        .unwrap();
+    archive
+        .add_envelope_to_quarantine("a", &quarantined)
+        .await
+        .unwrap();
+    drop(archive);
+
+    // as if the relay died halfway through a write
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(b"{\"archive\": \"envel")
+        .unwrap();
+
+    let mut archive = JsonlArchive::open(&path).unwrap();
+    assert!(
+        archive
+            .is_message_in_archive(&envelope.message)
+            .await
+            .unwrap()
+    );
+    assert!(
+        !archive
+            .is_message_in_archive(&quarantined.message)
+            .await
+            .unwrap()
+    );
+    assert_eq!(archive.memory().quarantined_envelopes().len(), 1);
+
+    archive
+        .add_envelope_to_archive("b", &envelope)
+        .await
+        .unwrap();
+    drop(archive);
+
+    let archive = JsonlArchive::open(&path).unwrap();
+    assert_eq!(archive.memory().messages().len(), 1);
+    assert_eq!(archive.memory().envelopes().len(), 2);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn jsonl_archive_keeps_last_record_without_newline() {
+    let path = temp_path();
+    let envelope = own_envelope().await;
+    let other_envelope = own_envelope().await;
+
+    let mut archive = JsonlArchive::open(&path).unwrap();
+    archive
+        .add_envelope_to_archive("a", &envelope)
+        .await
+        .unwrap();
+    drop(archive);
+
+    // as if the relay died right before writing the newline
+    let contents = fs::read_to_string(&path).unwrap();
+    fs::write(&path, contents.trim_end()).unwrap();
+
+    let mut archive = JsonlArchive::open(&path).unwrap();
+    assert!(
+        archive
+            .is_message_in_archive(&envelope.message)
+            .await
+            .unwrap()
+    );
+    archive
+        .add_envelope_to_archive("a", &other_envelope)
+        .await
+        .unwrap();
+    drop(archive);
+
+    let archive = JsonlArchive::open(&path).unwrap();
+    assert_eq!(archive.memory().messages().len(), 2);
+
+    fs::remove_file(path).unwrap();
+}
+
+// the archive doesn't check signatures, so one envelope can stand in for messages from any time
+fn envelope_created_at(envelope: &Envelope, created_at: i64) -> Envelope {
+    let mut envelope = envelope.clone();
+    envelope.message.contents.created_at = created_at;
+    envelope.message.contents.uuid = uuid::Uuid::new_v4().to_string();
+    envelope.message.certificate.signature = format!("signature-{created_at}");
+    envelope
+}
+
+fn created_at(messages: Vec<Message>) -> Vec<i64> {
+    messages
+        .into_iter()
+        .map(|message| message.contents.created_at)
+        .collect()
+}
+
+#[tokio::test]
+async fn archive_can_be_queried() {
+    let envelope = own_envelope().await;
+    let other = own_envelope().await;
+    let key = envelope.message.certificate.key.clone();
+    let mut archive = MemoryArchive::new();
+
+    for created_at in [30, 10, 20, 40] {
+        archive
+            .add_envelope_to_archive("a", &envelope_created_at(&envelope, created_at))
+            .await
+            .unwrap();
+    }
+    archive
+        .add_envelope_to_archive("a", &envelope_created_at(&other, 25))
+        .await
+        .unwrap();
+
+    let everything = archive
+        .find_messages(&MessageQuery::default())
+        .await
+        .unwrap();
+    assert_eq!(created_at(everything), vec![10, 20, 25, 30, 40]);
+
+    let by_key = MessageQuery {
+        key: Some(key),
+        ..MessageQuery::default()
+    };
+    assert_eq!(
+        created_at(archive.find_messages(&by_key).await.unwrap()),
+        vec![10, 20, 30, 40]
+    );
+
+    let in_range = MessageQuery {
+        created_from: Some(20),
+        created_until: Some(30),
+        ..MessageQuery::default()
+    };
+    assert_eq!(
+        created_at(archive.find_messages(&in_range).await.unwrap()),
+        vec![20, 25]
+    );
+
+    let first_page = MessageQuery {
+        limit: Some(2),
+        ..by_key
+    };
+    let second_page = first_page.next_page().unwrap();
+    let third_page = second_page.next_page().unwrap();
+    assert_eq!(
+        created_at(archive.find_messages(&first_page).await.unwrap()),
+        vec![10, 20]
+    );
+    assert_eq!(
+        created_at(archive.find_messages(&second_page).await.unwrap()),
+        vec![30, 40]
+    );
+    assert!(archive.find_messages(&third_page).await.unwrap().is_empty());
+    assert_eq!(MessageQuery::default().next_page(), None);
+
+    let uuid = archive.messages()[0].contents.uuid.clone();
+    let by_uuid = MessageQuery {
+        uuid: Some(uuid),
+        ..MessageQuery::default()
+    };
+    assert_eq!(
+        created_at(archive.find_messages(&by_uuid).await.unwrap()),
+        vec![30]
+    );
+}
+
+#[tokio::test]
+async fn archive_keeps_every_path_of_a_message() {
+    let envelope = own_envelope().await;
+    let mut forwarded = envelope.clone();
+    forwarded.ttl -= 1;
+    forwarded.forwarded.push(ForwardingHop {
+        key: "b".to_owned(),
+        ttl: envelope.ttl,
+        signature: "hop".to_owned(),
+    });
+    let mut archive = MemoryArchive::new();
+
+    archive
+        .add_envelope_to_archive(&envelope.message.certificate.key, &envelope)
+        .await
+        .unwrap();
+    archive
+        .add_envelope_to_archive("b", &forwarded)
+        .await
+        .unwrap();
+    archive
+        .add_envelope_to_archive("c", &own_envelope().await)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        archive
+            .find_envelopes_of_message(&envelope.message.certificate.signature)
+            .await
+            .unwrap(),
+        vec![
+            ArchivedEnvelope {
+                from: envelope.message.certificate.key.clone(),
+                envelope: envelope.clone(),
+            },
+            ArchivedEnvelope {
+                from: "b".to_owned(),
+                envelope: forwarded,
+            },
+        ]
+    );
+    assert!(
+        archive
+            .find_envelopes_of_message("unknown")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn mailroom_runs_on_memory_archive() {
+    let secret_key_a = SecretKey::generate();
+    let secret_key_b = SecretKey::generate();
+    let key_a = secret_key_a.public_key();
+    let key_b = secret_key_b.public_key();
+
+    let mut relay_a = Mailroom::new(Repeat("from a"), MemoryArchive::new(), secret_key_a);
+    let mut relay_b = Mailroom::new(Repeat("from b"), MemoryArchive::new(), secret_key_b);
+
+    let payload = relay_a
+        .get_outgoing(&key_b, TTLConfig::default())
+        .await
+        .unwrap()
+        .create_payload();
+    let trusted_payload = UntrustedPayload::from_json(&payload)
+        .unwrap()
+        .try_trust([key_a], &key_b, relay_b.accepted_periods(relay_b.now()))
+        .unwrap();
+    relay_b.receive_payload(&trusted_payload).await.unwrap();
+
+    assert!(
+        relay_b
+            .archive()
+            .messages()
+            .iter()
+            .any(|message| message.contents.line == "from a")
+    );
+}
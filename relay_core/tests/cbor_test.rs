@@ -0,0 +1,90 @@
+use base64::{Engine, prelude::BASE64_STANDARD};
+use relay_core::{cbor, crypto::SecretKey, jcs};
+
+// the cbor side doesn't check signatures, so any base64 of the right length will do
+fn payload_json(key: &str, signature: &str) -> String {
+    format!(
+        r#"{{"version":1,"certificate":{{"key":"{key}","signature":"{signature}"}},"recipient":"{key}","period":1700000000,"envelopes":[{{"forwarded":[{{"key":"{key}","ttl":7,"signature":"{signature}"}}],"ttl":7,"message":{{"certificate":{{"key":"{key}","signature":"{signature}"}},"contents":{{"version":1,"uuid":"7b0c8d2e-5f4a-4b1e-9c3d-2a6f8e1b0d4c","author":"a","line":"distant stations — été 📡","created_at":1700000000}}}}}}]}}"#
+    )
+}
+
+fn signature() -> String {
+    BASE64_STANDARD.encode([7; 64])
+}
+
+#[test]
+fn keys_and_signatures_are_byte_strings() {
+    let public_key = SecretKey::generate().public_key();
+    let json = payload_json(&public_key.to_string(), &signature());
+
+    let cbor = cbor::from_json(&json).unwrap();
+    let key_bytes = [[0x58, 0x20].as_slice(), public_key.as_bytes()].concat();
+    let signature_bytes = [[0x58, 0x40].as_slice(), &[7; 64]].concat();
+
+    assert!(
+        cbor.windows(key_bytes.len())
+            .any(|bytes| bytes == key_bytes)
+    );
+    assert!(
+        cbor.windows(signature_bytes.len())
+            .any(|bytes| bytes == signature_bytes)
+    );
+    assert!(
+        !cbor
+            .windows(44)
+            .any(|bytes| bytes == public_key.to_string().as_bytes())
+    );
+    assert_eq!(
+        jcs::canonicalize(&cbor::to_json(&cbor).unwrap()).unwrap(),
+        jcs::canonicalize(&json).unwrap()
+    );
+}
+
+#[test]
+fn encoding_is_deterministic() {
+    let public_key = SecretKey::generate().public_key().to_string();
+    let json = payload_json(&public_key, &signature());
+    let reordered = jcs::canonicalize(&json).unwrap();
+
+    assert_ne!(json.as_bytes(), reordered.as_slice());
+    assert_eq!(
+        cbor::from_json(&json).unwrap(),
+        cbor::from_json(std::str::from_utf8(&reordered).unwrap()).unwrap()
+    );
+    // map keys go shortest first, so a payload starts with its period
+    assert_eq!(
+        cbor::from_json(&json).unwrap()[..8],
+        [0xa5, 0x66, b'p', b'e', b'r', b'i', b'o', b'd']
+    );
+}
+
+// a key that only decodes leniently has to come back as the same string, or its signature breaks
+#[test]
+fn base64_that_does_not_round_trip_stays_text() {
+    let unpadded_key = SecretKey::generate()
+        .public_key()
+        .to_string()
+        .trim_end_matches('=')
+        .to_owned();
+    let json = payload_json(&unpadded_key, &signature());
+
+    let cbor = cbor::from_json(&json).unwrap();
+
+    assert!(
+        cbor.windows(unpadded_key.len())
+            .any(|bytes| bytes == unpadded_key.as_bytes())
+    );
+    assert_eq!(
+        jcs::canonicalize(&cbor::to_json(&cbor).unwrap()).unwrap(),
+        jcs::canonicalize(&json).unwrap()
+    );
+}
+
+#[test]
+fn reject_anything_but_a_payload() {
+    assert!(cbor::from_json(r#"{"fact": "this json is nonsense"}"#).is_err());
+    // a map with an integer key
+    assert!(cbor::to_json(&[0xa1, 0x01, 0x02]).is_err());
+    // a byte string
+    assert!(cbor::to_json(&[0x41, 0x00]).is_err());
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use relay_core::clock::{Clock, PeriodSchedule, VirtualClock};
+
+fn at(timestamp: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(timestamp, 0).unwrap()
+}
+
+#[test]
+fn hourly_periods_start_on_the_hour() {
+    let schedule = PeriodSchedule::HOURLY;
+    let now = DateTime::parse_from_rfc3339("2025-10-17T12:34:56.789Z")
+        .unwrap()
+        .to_utc();
+
+    assert_eq!(
+        schedule.period_start(now),
+        DateTime::parse_from_rfc3339("2025-10-17T12:00:00Z")
+            .unwrap()
+            .to_utc()
+    );
+    assert_eq!(
+        schedule.next_period_start(now),
+        DateTime::parse_from_rfc3339("2025-10-17T13:00:00Z")
+            .unwrap()
+            .to_utc()
+    );
+}
+
+#[test]
+fn short_periods_line_up_with_the_epoch() {
+    let schedule = PeriodSchedule::every(Duration::from_secs(10));
+
+    assert_eq!(schedule.period_start(at(1_700_000_007)), at(1_700_000_000));
+    assert_eq!(
+        schedule.until_next_period(at(1_700_000_007)),
+        Duration::from_secs(3)
+    );
+    assert!(schedule.follows(at(1_700_000_009), at(1_700_000_010)));
+    assert!(!schedule.follows(at(1_700_000_000), at(1_700_000_009)));
+    assert!(!schedule.follows(at(1_700_000_000), at(1_700_000_020)));
+    assert_eq!(
+        schedule.periods_between(at(1_700_000_009), at(1_700_000_035)),
+        3
+    );
+}
+
+#[test]
+fn accepted_periods_stretch_over_boundaries_within_skew() {
+    let schedule = PeriodSchedule::every(Duration::from_secs(10));
+
+    assert_eq!(schedule.max_skew(), Duration::from_secs(5));
+    assert_eq!(
+        schedule.accepted_periods(at(1_700_000_005)),
+        at(1_700_000_000)..=at(1_700_000_010)
+    );
+    assert_eq!(
+        schedule.accepted_periods(at(1_700_000_011)),
+        at(1_700_000_000)..=at(1_700_000_010)
+    );
+
+    let schedule = schedule.with_max_skew(Duration::from_secs(1));
+    assert_eq!(
+        schedule.accepted_periods(at(1_700_000_005)),
+        at(1_700_000_000)..=at(1_700_000_000)
+    );
+    assert_eq!(PeriodSchedule::HOURLY.max_skew(), Duration::from_secs(30));
+}
+
+#[test]
+fn virtual_clock_only_moves_when_told() {
+    let clock = VirtualClock::new(at(1_700_000_000));
+    let shared = clock.clone();
+
+    assert_eq!(clock.now(), at(1_700_000_000));
+
+    shared.advance(Duration::from_secs(90));
+    assert_eq!(clock.now(), at(1_700_000_090));
+
+    shared.set(at(1_600_000_000));
+    assert_eq!(clock.now(), at(1_600_000_000));
+}
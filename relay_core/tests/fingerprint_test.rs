@@ -0,0 +1,37 @@
+use relay_core::crypto::{PublicKey, SecretKey};
+
+// public keys from the first two test vectors in rfc 8032
+const KEY_A: &str = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+const KEY_B: &str = "PUAXw+hDiVqStwqnTRt+vJyYLM8uxJaMwM1V8Sr0Zgw=";
+
+#[test]
+fn fingerprint_is_stable() {
+    let fingerprint = PublicKey::new_from_b64(KEY_A).unwrap().fingerprint();
+
+    assert_eq!(fingerprint.to_hex(), "876b 6287 2e02 7ef1 2d5d");
+    assert_eq!(
+        fingerprint.to_words(),
+        "manual force peace foster child joy"
+    );
+}
+
+#[test]
+fn safety_number_is_stable() {
+    let key_a = PublicKey::new_from_b64(KEY_A).unwrap();
+    let key_b = PublicKey::new_from_b64(KEY_B).unwrap();
+
+    assert_eq!(
+        key_a.safety_number(&key_b).to_string(),
+        "99606 23129 82297 37614 94157 25465"
+    );
+}
+
+#[test]
+fn safety_number_is_symmetric() {
+    let key_a = SecretKey::generate().public_key();
+    let key_b = SecretKey::generate().public_key();
+    let key_c = SecretKey::generate().public_key();
+
+    assert_eq!(key_a.safety_number(&key_b), key_b.safety_number(&key_a));
+    assert_ne!(key_a.safety_number(&key_b), key_a.safety_number(&key_c));
+}
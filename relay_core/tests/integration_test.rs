@@ -4,9 +4,17 @@ use chrono::{DateTime, Utc};
 use itertools::Itertools;
 use mock::{MockReceivePayloadError, MockRelay};
 use relay_core::{
+    cbor,
+    clock::{Clock, PeriodSchedule, VirtualClock},
     crypto::SecretKey,
-    mailroom::{DEFAULT_INITIAL_TTL, MailroomError},
+    filter::{FilterAction, FilterRules, Filters, LinePattern},
+    mailroom::{
+        DEFAULT_INITIAL_TTL, DEFAULT_MAX_MESSAGE_AGE, ForwardingLimits, MailroomError,
+        ReceivedEnvelopes,
+    },
+    message::Envelope,
     payload::UntrustedPayloadError,
+    version::{self, PROTOCOL_VERSION},
 };
 
 mod mock;
@@ -20,7 +28,7 @@ async fn send_payload(
     from_relay: &mut MockRelay,
     to_relay: &mut MockRelay,
     at: DateTime<Utc>,
-) -> Result<(), MockReceivePayloadError> {
+) -> Result<ReceivedEnvelopes, MockReceivePayloadError> {
     let payload = from_relay.create_payload(to_relay.public_key, at).await;
     to_relay.receive_payload(&payload, at).await
 }
@@ -170,6 +178,360 @@ async fn relay_chain() {
     assert!(relay_c.has_forwarded_from(relay_b.public_key));
 }
 
+#[tokio::test]
+async fn direct_line_only_opens_for_recipient() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+    let mut relay_c = MockRelay::new("c");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+    mutually_trust(&mut relay_b, &mut relay_c);
+
+    let now = Utc::now();
+    send_payload(&mut relay_a, &mut relay_b, now).await.unwrap();
+    relay_a.queue_direct_line(relay_c.public_key, "only for c");
+
+    let an_hour_later = now + Duration::from_secs(3600);
+    let received_by_b = send_payload(&mut relay_a, &mut relay_b, an_hour_later)
+        .await
+        .unwrap();
+
+    assert!(received_by_b.direct_lines.is_empty());
+    assert!(!relay_b.has_message_with_line("only for c"));
+
+    let two_hours_later = an_hour_later + Duration::from_secs(3600);
+    let received_by_c = send_payload(&mut relay_b, &mut relay_c, two_hours_later)
+        .await
+        .unwrap();
+
+    assert_eq!(received_by_c.direct_lines.len(), 1);
+    assert_eq!(received_by_c.direct_lines[0].line, "only for c");
+    assert_eq!(
+        received_by_c.direct_lines[0].message.certificate.key,
+        relay_a.public_key.to_string()
+    );
+    assert!(!relay_c.has_message_with_line("only for c"));
+}
+
+#[tokio::test]
+async fn retraction_reaches_peers() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+
+    let now = Utc::now();
+    exchange_payloads(&mut relay_a, &mut relay_b, now)
+        .await
+        .unwrap();
+
+    let relay_a_line = relay_a.current_line().unwrap();
+    let relay_b_line = relay_b.current_line().unwrap();
+
+    assert_eq!(relay_a.retract_line(&relay_a_line).await, 1);
+    assert_eq!(relay_a.retract_line(&relay_b_line).await, 0);
+    assert!(!relay_b.has_retraction_of_line(&relay_a_line));
+
+    let an_hour_later = now + Duration::from_secs(3600);
+    exchange_payloads(&mut relay_a, &mut relay_b, an_hour_later)
+        .await
+        .unwrap();
+
+    assert!(relay_b.has_retraction_of_line(&relay_a_line));
+    assert!(!relay_a.has_retraction_of_line(&relay_b_line));
+}
+
+#[tokio::test]
+async fn filter_drops_and_quarantines() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+    let mut relay_c = MockRelay::new("c");
+
+    mutually_trust(&mut relay_a, &mut relay_c);
+    mutually_trust(&mut relay_b, &mut relay_c);
+
+    relay_c.set_filter(Filters {
+        drop: FilterRules {
+            authors: vec!["a".to_owned()],
+            ..Default::default()
+        },
+        quarantine: FilterRules {
+            lines: vec![LinePattern::new("^b: ").unwrap()],
+            ..Default::default()
+        },
+    });
+
+    let now = Utc::now();
+    let from_a = send_payload(&mut relay_a, &mut relay_c, now).await.unwrap();
+    let from_b = send_payload(&mut relay_b, &mut relay_c, now).await.unwrap();
+
+    assert_eq!(from_a.dropped_envelopes.len(), 1);
+    assert!(from_a.envelopes.is_empty());
+    assert_eq!(from_b.quarantined_envelopes.len(), 1);
+    assert!(from_b.envelopes.is_empty());
+    assert!(!relay_c.has_message_with_line(&relay_a.current_line().unwrap()));
+    assert!(!relay_c.has_message_with_line(&relay_b.current_line().unwrap()));
+
+    relay_c.set_filter(|_: &Envelope| FilterAction::Accept);
+
+    let an_hour_later = now + Duration::from_secs(3600);
+    send_payload(&mut relay_a, &mut relay_c, an_hour_later)
+        .await
+        .unwrap();
+
+    assert!(relay_c.has_message_with_line(&relay_a.current_line().unwrap()));
+}
+
+#[tokio::test]
+async fn forward_on_virtual_time() {
+    let clock = VirtualClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+    let schedule = PeriodSchedule::every(Duration::from_secs(10));
+
+    let mut relay_a = MockRelay::new_with_clock("a", clock.clone(), schedule);
+    let mut relay_b = MockRelay::new_with_clock("b", clock.clone(), schedule);
+    let mut relay_c = MockRelay::new_with_clock("c", clock.clone(), schedule);
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+    mutually_trust(&mut relay_b, &mut relay_c);
+
+    let payload = relay_a.create_payload_now(relay_b.public_key).await;
+    relay_b.receive_payload_now(&payload).await.unwrap();
+
+    // still in the same period, so b has nothing from a to forward yet
+    clock.advance(Duration::from_secs(1));
+    let payload = relay_b.create_payload_now(relay_c.public_key).await;
+    relay_c.receive_payload_now(&payload).await.unwrap();
+    assert!(!relay_c.has_message_from(relay_a.public_key));
+
+    clock.advance(schedule.until_next_period(clock.now()));
+    let payload = relay_b.create_payload_now(relay_c.public_key).await;
+    relay_c.receive_payload_now(&payload).await.unwrap();
+    assert!(relay_c.has_message_from(relay_a.public_key));
+}
+
+#[tokio::test]
+async fn restored_snapshot_keeps_period_state() {
+    let clock = VirtualClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+    let schedule = PeriodSchedule::every(Duration::from_secs(10));
+
+    let mut relay_a = MockRelay::new_with_clock("a", clock.clone(), schedule);
+    let mut relay_b = MockRelay::new_with_clock("b", clock.clone(), schedule);
+    let mut relay_c = MockRelay::new_with_clock("c", clock.clone(), schedule);
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+
+    let payload = relay_a.create_payload_now(relay_b.public_key).await;
+    relay_b.receive_payload_now(&payload).await.unwrap();
+
+    // the snapshot goes through json like it does in the daemon's archive
+    let snapshot = serde_json::from_str(&serde_json::to_string(&relay_b.snapshot()).unwrap());
+    let mut restarted_b = MockRelay::new_with_clock("b", clock.clone(), schedule);
+    restarted_b.restore(snapshot.unwrap());
+    assert_eq!(restarted_b.stats(), relay_b.stats());
+    mutually_trust(&mut relay_a, &mut restarted_b);
+    mutually_trust(&mut restarted_b, &mut relay_c);
+
+    let payload = relay_a.create_payload_now(restarted_b.public_key).await;
+    assert!(matches!(
+        restarted_b.receive_payload_now(&payload).await,
+        Err(MockReceivePayloadError::ReceiveInMailroom(
+            MailroomError::AlreadyReceivedFromKey
+        ))
+    ));
+
+    clock.advance(schedule.length());
+    let payload = restarted_b.create_payload_now(relay_c.public_key).await;
+    relay_c.receive_payload_now(&payload).await.unwrap();
+    assert!(relay_c.has_message_from(relay_a.public_key));
+
+    // a restart in a later period keeps the totals, and the period before is the last one seen
+    clock.advance(schedule.length());
+    let mut restarted_again_b = MockRelay::new_with_clock("b", clock.clone(), schedule);
+    restarted_again_b.restore(restarted_b.snapshot());
+    let stats = restarted_again_b.stats();
+    assert_eq!(stats.total, restarted_b.stats().total);
+    assert_eq!(stats.total.received(), 1);
+    assert_eq!(stats.previous.sent_to[&relay_c.public_key], 2);
+    assert_eq!(stats.period.sent(), 0);
+}
+
+#[tokio::test]
+async fn skip_relays_already_on_path() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+    let mut relay_c = MockRelay::new("c");
+    let mut relay_d = MockRelay::new("d");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+    mutually_trust(&mut relay_b, &mut relay_c);
+    mutually_trust(&mut relay_c, &mut relay_d);
+    mutually_trust(&mut relay_c, &mut relay_a);
+    mutually_trust(&mut relay_d, &mut relay_b);
+
+    let mut time = Utc::now();
+    send_payload(&mut relay_a, &mut relay_b, time)
+        .await
+        .unwrap();
+
+    time += Duration::from_secs(3600);
+    send_payload(&mut relay_b, &mut relay_c, time)
+        .await
+        .unwrap();
+
+    // a wrote it
+    time += Duration::from_secs(3600);
+    let received = send_payload(&mut relay_c, &mut relay_a, time)
+        .await
+        .unwrap();
+    assert!(
+        !received
+            .envelopes
+            .iter()
+            .any(|envelope| envelope.message.certificate.key == relay_a.public_key.to_string())
+    );
+    assert_eq!(relay_c.suppressed_envelopes(), 1);
+    send_payload(&mut relay_c, &mut relay_d, time)
+        .await
+        .unwrap();
+    assert!(relay_d.has_message_from(relay_a.public_key));
+
+    // b forwarded a's line and wrote its own
+    time += Duration::from_secs(3600);
+    send_payload(&mut relay_d, &mut relay_b, time)
+        .await
+        .unwrap();
+    assert_eq!(relay_d.suppressed_envelopes(), 2);
+}
+
+#[tokio::test]
+async fn forwarding_limits_share_payload_between_origins() {
+    let mut chatty = MockRelay::new("chatty");
+    let mut quiet = MockRelay::new("quiet");
+    let mut hub = MockRelay::new("hub");
+    let mut receiver = MockRelay::new("receiver");
+    let mut upstream = (0..4)
+        .map(|i| MockRelay::new(&format!("upstream {i}")))
+        .collect::<Vec<_>>();
+
+    for relay in &mut upstream {
+        mutually_trust(relay, &mut chatty);
+    }
+    mutually_trust(&mut chatty, &mut hub);
+    mutually_trust(&mut quiet, &mut hub);
+    mutually_trust(&mut hub, &mut receiver);
+
+    hub.set_forwarding_limits(ForwardingLimits {
+        max_envelopes_per_payload: Some(3),
+        max_envelopes_per_origin: Some(2),
+    });
+
+    let mut time = Utc::now();
+    for relay in &mut upstream {
+        send_payload(relay, &mut chatty, time).await.unwrap();
+    }
+
+    time += Duration::from_secs(3600);
+    send_payload(&mut chatty, &mut hub, time).await.unwrap();
+    send_payload(&mut quiet, &mut hub, time).await.unwrap();
+
+    time += Duration::from_secs(3600);
+    let received = send_payload(&mut hub, &mut receiver, time).await.unwrap();
+
+    // three forwarded plus the hub's own line
+    assert_eq!(received.envelopes.len(), 4);
+    assert!(receiver.has_message_from(quiet.public_key));
+    // chatty's own line has more ttl left than what it forwarded
+    assert!(receiver.has_message_from(chatty.public_key));
+}
+
+#[tokio::test]
+async fn carry_over_missed_periods() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+    let mut relay_c = MockRelay::new("c");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+    mutually_trust(&mut relay_b, &mut relay_c);
+
+    let now = Utc::now();
+    let two_hours_later = now + Duration::from_secs(2 * 3600);
+
+    // b sees nothing the hour after receiving, so by default it has nothing left to forward
+    send_payload(&mut relay_a, &mut relay_b, now).await.unwrap();
+    let received = send_payload(&mut relay_b, &mut relay_c, two_hours_later)
+        .await
+        .unwrap();
+    assert!(!relay_c.has_message_from(relay_a.public_key));
+    assert_eq!(received.envelopes.len(), 1);
+
+    let mut relay_b = MockRelay::new("b");
+    let mut relay_c = MockRelay::new("c");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+    mutually_trust(&mut relay_b, &mut relay_c);
+    relay_b.set_carry_over_periods(2);
+
+    let later = now + Duration::from_secs(3 * 3600);
+    let two_hours_after_that = later + Duration::from_secs(2 * 3600);
+
+    send_payload(&mut relay_a, &mut relay_b, later)
+        .await
+        .unwrap();
+    let received = send_payload(&mut relay_b, &mut relay_c, two_hours_after_that)
+        .await
+        .unwrap();
+    assert!(relay_c.has_message_from(relay_a.public_key));
+
+    // one ttl for the hop and one for the hour it waited
+    let envelope = received
+        .envelopes
+        .iter()
+        .find(|envelope| envelope.message.certificate.key == relay_a.public_key.to_string())
+        .unwrap();
+    assert_eq!(envelope.ttl, DEFAULT_INITIAL_TTL - 2);
+}
+
+#[tokio::test]
+async fn stats_count_per_period_and_in_total() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+    let mut relay_c = MockRelay::new("c");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+    mutually_trust(&mut relay_a, &mut relay_c);
+    mutually_trust(&mut relay_b, &mut relay_c);
+
+    let now = Utc::now();
+    send_payload(&mut relay_a, &mut relay_b, now).await.unwrap();
+    send_payload(&mut relay_a, &mut relay_c, now).await.unwrap();
+
+    // c already has a's line by the time b forwards it
+    let an_hour_later = now + Duration::from_secs(3600);
+    send_payload(&mut relay_b, &mut relay_c, an_hour_later)
+        .await
+        .unwrap();
+
+    let stats = relay_c.stats();
+    assert_eq!(stats.period.received_from[&relay_b.public_key], 2);
+    assert!(!stats.period.received_from.contains_key(&relay_a.public_key));
+    assert_eq!(stats.period.duplicates, 1);
+    assert_eq!(stats.total.received(), 3);
+    assert_eq!(relay_b.stats().period.sent_to[&relay_c.public_key], 2);
+    assert_eq!(relay_a.stats().total.sent(), 2);
+
+    let two_hours_later = now + Duration::from_secs(2 * 3600);
+    send_payload(&mut relay_a, &mut relay_c, two_hours_later)
+        .await
+        .unwrap();
+
+    let stats = relay_c.stats();
+    assert!(!stats.period.received_from.contains_key(&relay_b.public_key));
+    assert_eq!(stats.period.duplicates, 0);
+    assert_eq!(stats.previous.received_from[&relay_b.public_key], 2);
+    assert_eq!(stats.previous.duplicates, 1);
+    assert_eq!(stats.total.duplicates, 1);
+}
+
 #[tokio::test]
 async fn ttl_exhaustion() {
     let mut current_relay = MockRelay::new("origin");
@@ -194,3 +556,277 @@ async fn ttl_exhaustion() {
         .unwrap();
     assert!(!final_relay.has_message_from(origin_key));
 }
+
+#[tokio::test]
+async fn accept_key_succession() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+
+    relay_b.rotate_key();
+    relay_b.rotate_key();
+
+    send_payload(&mut relay_b, &mut relay_a, Utc::now())
+        .await
+        .unwrap();
+
+    assert!(relay_a.has_message_with_line(&relay_b.current_line().unwrap()));
+}
+
+#[tokio::test]
+async fn reject_forged_key_succession() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+    let mut relay_c = MockRelay::new("c");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+
+    let mut forged_succession = serde_json::to_value(relay_c.rotate_key()).unwrap();
+    forged_succession["old_key"] = relay_b.public_key.to_string().into();
+
+    let payload = relay_c.create_payload(relay_a.public_key, Utc::now()).await;
+    let mut payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+    payload["successions"] = serde_json::Value::Array(vec![forged_succession]);
+
+    assert!(matches!(
+        relay_a
+            .receive_payload(&payload.to_string(), Utc::now())
+            .await,
+        Err(MockReceivePayloadError::TrustPayload(
+            UntrustedPayloadError::InvalidKeySuccession
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn reject_payload_replayed_to_other_relay() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+    let mut relay_c = MockRelay::new("c");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+    mutually_trust(&mut relay_b, &mut relay_c);
+
+    let now = Utc::now();
+    let payload = relay_b.create_payload(relay_a.public_key, now).await;
+
+    assert!(matches!(
+        relay_c.receive_payload(&payload, now).await,
+        Err(MockReceivePayloadError::TrustPayload(
+            UntrustedPayloadError::WrongRecipient
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn reject_payload_replayed_in_later_period() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+
+    let now = Utc::now();
+    let payload = relay_b.create_payload(relay_a.public_key, now).await;
+    relay_a.receive_payload(&payload, now).await.unwrap();
+
+    assert!(matches!(
+        relay_a
+            .receive_payload(&payload, now + Duration::from_secs(3600))
+            .await,
+        Err(MockReceivePayloadError::TrustPayload(
+            UntrustedPayloadError::StalePeriod
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn accept_payloads_across_a_period_boundary() {
+    let schedule = PeriodSchedule::every(Duration::from_secs(10));
+    let sender_clock = VirtualClock::new(DateTime::from_timestamp(1_700_000_001, 0).unwrap());
+    let listener_clock = VirtualClock::new(DateTime::from_timestamp(1_700_000_001, 0).unwrap());
+
+    let mut sender = MockRelay::new_with_clock("sender", sender_clock.clone(), schedule);
+    let mut listener = MockRelay::new_with_clock("listener", listener_clock.clone(), schedule);
+
+    mutually_trust(&mut sender, &mut listener);
+
+    let payload = sender.create_payload_now(listener.public_key).await;
+    listener.receive_payload_now(&payload).await.unwrap();
+
+    // the sender's clock is two seconds ahead, so it's already in the next period
+    sender_clock.set(DateTime::from_timestamp(1_700_000_011, 0).unwrap());
+    listener_clock.set(DateTime::from_timestamp(1_700_000_009, 0).unwrap());
+
+    let payload = sender.create_payload_now(listener.public_key).await;
+    listener.receive_payload_now(&payload).await.unwrap();
+    let response = listener.create_payload_now(sender.public_key).await;
+    sender.receive_payload_now(&response).await.unwrap();
+
+    // the payload doesn't move the listener into the next period early
+    assert_eq!(listener.stats().previous.received(), 0);
+    assert_eq!(listener.stats().period.received_from[&sender.public_key], 2);
+    let payload = sender.create_payload_now(listener.public_key).await;
+    assert!(matches!(
+        listener.receive_payload_now(&payload).await,
+        Err(MockReceivePayloadError::ReceiveInMailroom(
+            MailroomError::AlreadyReceivedFromKey
+        ))
+    ));
+
+    // once the listener's clock catches up, the sender already sent for this period
+    listener_clock.set(DateTime::from_timestamp(1_700_000_010, 0).unwrap());
+    let payload = sender.create_payload_now(listener.public_key).await;
+    assert!(matches!(
+        listener.receive_payload_now(&payload).await,
+        Err(MockReceivePayloadError::ReceiveInMailroom(
+            MailroomError::AlreadyReceivedFromKey
+        ))
+    ));
+    assert!(listener.stats().previous.received() > 0);
+
+    // more than the allowed skew apart
+    sender_clock.set(DateTime::from_timestamp(1_700_000_021, 0).unwrap());
+    listener_clock.set(DateTime::from_timestamp(1_700_000_014, 0).unwrap());
+    let payload = sender.create_payload_now(listener.public_key).await;
+    assert!(matches!(
+        listener.receive_payload_now(&payload).await,
+        Err(MockReceivePayloadError::TrustPayload(
+            UntrustedPayloadError::StalePeriod
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn reject_unsupported_version() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+
+    let payload = relay_b.create_payload(relay_a.public_key, Utc::now()).await;
+    let mut payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+    payload["version"] = (PROTOCOL_VERSION + 1).into();
+
+    assert!(matches!(
+        relay_a
+            .receive_payload(&payload.to_string(), Utc::now())
+            .await,
+        Err(MockReceivePayloadError::ReadPayload(
+            UntrustedPayloadError::UnsupportedVersion(version)
+        )) if version == PROTOCOL_VERSION + 1
+    ));
+}
+
+#[test]
+fn negotiate_highest_common_version() {
+    assert_eq!(
+        version::highest_common_version([PROTOCOL_VERSION, PROTOCOL_VERSION + 1]),
+        Some(PROTOCOL_VERSION)
+    );
+    assert_eq!(
+        version::highest_common_version([PROTOCOL_VERSION + 1]),
+        None
+    );
+}
+
+#[tokio::test]
+async fn drop_expired_messages() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+
+    let expired_line = relay_b.current_line().unwrap();
+    let later = Utc::now() + DEFAULT_MAX_MESSAGE_AGE + Duration::from_secs(3600);
+    let received_envelopes = send_payload(&mut relay_b, &mut relay_a, later)
+        .await
+        .unwrap();
+
+    assert!(received_envelopes.envelopes.is_empty());
+    assert_eq!(received_envelopes.expired_envelopes.len(), 1);
+    assert!(!relay_a.has_message_with_line(&expired_line));
+}
+
+#[tokio::test]
+async fn drop_messages_from_the_future() {
+    let now = Utc::now();
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new_with_clock(
+        "b",
+        VirtualClock::new(now + Duration::from_secs(3 * 3600)),
+        PeriodSchedule::HOURLY,
+    );
+    let mut relay_c = MockRelay::new_with_clock(
+        "c",
+        VirtualClock::new(now + Duration::from_secs(10)),
+        PeriodSchedule::HOURLY,
+    );
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+    mutually_trust(&mut relay_a, &mut relay_c);
+
+    let future_line = relay_b.current_line().unwrap();
+    let received_envelopes = send_payload(&mut relay_b, &mut relay_a, now).await.unwrap();
+
+    assert!(received_envelopes.envelopes.is_empty());
+    assert_eq!(received_envelopes.expired_envelopes.len(), 1);
+    assert!(!relay_a.has_message_with_line(&future_line));
+
+    // a few seconds ahead is only clocks disagreeing
+    let skewed_line = relay_c.current_line().unwrap();
+    let received_envelopes = send_payload(&mut relay_c, &mut relay_a, now).await.unwrap();
+
+    assert!(received_envelopes.expired_envelopes.is_empty());
+    assert!(relay_a.has_message_with_line(&skewed_line));
+}
+
+#[tokio::test]
+async fn trust_payload_sent_as_cbor() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+    relay_a.add_trusted_key(relay_b.public_key);
+
+    let now = Utc::now();
+    let payload = relay_b.create_cbor_payload(relay_a.public_key, now).await;
+
+    relay_a
+        .receive_payload(&cbor::to_json(&payload).unwrap(), now)
+        .await
+        .unwrap();
+
+    assert!(relay_a.has_message_with_line(&relay_b.current_line().unwrap()));
+}
+
+#[tokio::test]
+async fn cbor_payload_is_smaller_than_json() {
+    let mut relay_a = MockRelay::new("a");
+    let mut relay_b = MockRelay::new("b");
+    let mut relay_c = MockRelay::new("c");
+    let mut relay_d = MockRelay::new("d");
+
+    mutually_trust(&mut relay_a, &mut relay_b);
+    mutually_trust(&mut relay_b, &mut relay_c);
+    mutually_trust(&mut relay_b, &mut relay_d);
+
+    let now = Utc::now();
+    exchange_payloads(&mut relay_c, &mut relay_b, now)
+        .await
+        .unwrap();
+    exchange_payloads(&mut relay_d, &mut relay_b, now)
+        .await
+        .unwrap();
+
+    let later = now + Duration::from_secs(3600);
+    let json = relay_b.create_payload(relay_a.public_key, later).await;
+    let cbor = relay_b.create_cbor_payload(relay_a.public_key, later).await;
+
+    // around 1700 bytes as json and 1300 as cbor, keys and signatures shrink by a quarter
+    assert!(cbor.len() * 5 < json.len() * 4);
+    relay_a
+        .receive_payload(&cbor::to_json(&cbor).unwrap(), later)
+        .await
+        .unwrap();
+    assert!(relay_a.has_message_from(relay_c.public_key));
+    assert!(relay_a.has_message_from(relay_d.public_key));
+}
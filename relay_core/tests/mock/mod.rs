@@ -1,54 +1,66 @@
 use std::{
-    collections::HashSet,
+    collections::{HashSet, VecDeque},
+    convert::Infallible,
     sync::{Arc, Mutex},
 };
 
 use chrono::{DateTime, Utc};
 use relay_core::{
-    crypto::{PublicKey, SecretKey},
-    mailroom::{Archive, GetNextLine, Mailroom, MailroomError, NextLine, TTLConfig},
-    message::{Envelope, Message},
+    archive::MemoryArchive,
+    clock::{PeriodSchedule, VirtualClock},
+    crypto::{KeySuccession, PublicKey, SecretKey},
+    filter::Filter,
+    mailroom::{
+        ForwardingLimits, GetNextLine, Mailroom, MailroomError, MailroomSnapshot, MailroomStats,
+        NextDirectLine, NextLine, ReceivedEnvelopes, TTLConfig,
+    },
+    message::MessageKind,
     payload::{UntrustedPayload, UntrustedPayloadError},
+    version::PROTOCOL_VERSION,
 };
 
 #[derive(Debug)]
 pub enum MockReceivePayloadError {
     ReadPayload(UntrustedPayloadError),
     TrustPayload(UntrustedPayloadError),
-    ReceiveInMailroom(MailroomError<()>),
+    ReceiveInMailroom(MailroomError<Infallible>),
 }
 
+// every relay has a clock of its own unless it's given one to share, and the methods taking a time
+// move that clock there first
 pub struct MockRelay {
     pub public_key: PublicKey,
-    mailroom: Mailroom<MockLineGenerator, MockArchive, ()>,
+    mailroom: Mailroom<MockLineGenerator, MemoryArchive, Infallible>,
+    clock: VirtualClock,
     trusted_keys: HashSet<PublicKey>,
-    #[allow(dead_code)]
-    envelopes: Arc<Mutex<Vec<Envelope>>>,
-    messages: Arc<Mutex<HashSet<Message>>>,
+    direct_lines: Arc<Mutex<VecDeque<(PublicKey, String)>>>,
 }
 
 impl MockRelay {
     pub fn new(name: &str) -> Self {
+        Self::new_with_clock(name, VirtualClock::new(Utc::now()), PeriodSchedule::HOURLY)
+    }
+
+    pub fn new_with_clock(name: &str, clock: VirtualClock, schedule: PeriodSchedule) -> Self {
         let secret_key = SecretKey::generate();
 
-        let envelopes = Arc::new(Mutex::new(vec![]));
-        let messages = Arc::new(Mutex::new(HashSet::new()));
+        let direct_lines = Arc::new(Mutex::new(VecDeque::new()));
 
         MockRelay {
             public_key: secret_key.public_key(),
-            mailroom: Mailroom::new(
+            mailroom: Mailroom::new_with_clock(
                 MockLineGenerator {
                     name: name.to_owned(),
+                    direct_lines: Arc::clone(&direct_lines),
                 },
-                MockArchive {
-                    envelopes: Arc::clone(&envelopes),
-                    messages: Arc::clone(&messages),
-                },
+                MemoryArchive::new(),
                 secret_key,
+                Arc::new(clock.clone()),
+                schedule,
             ),
+            clock,
             trusted_keys: HashSet::new(),
-            envelopes,
-            messages,
+            direct_lines,
         }
     }
 
@@ -56,54 +68,146 @@ impl MockRelay {
         self.trusted_keys.insert(key);
     }
 
+    // picked up at the start of the next hour, like lines
+    pub fn queue_direct_line(&mut self, recipient: PublicKey, line: &str) {
+        self.direct_lines
+            .lock()
+            .unwrap()
+            .push_back((recipient, line.to_owned()));
+    }
+
+    pub async fn retract_line(&mut self, line: &str) -> usize {
+        self.mailroom.retract_line(line).await.unwrap().len()
+    }
+
+    pub fn set_filter<F: Filter + Send + 'static>(&mut self, filter: F) {
+        self.mailroom.set_filter(filter);
+    }
+
+    pub fn set_carry_over_periods(&mut self, carry_over_periods: u32) {
+        self.mailroom.set_carry_over_periods(carry_over_periods);
+    }
+
+    pub fn set_forwarding_limits(&mut self, forwarding_limits: ForwardingLimits) {
+        self.mailroom.set_forwarding_limits(forwarding_limits);
+    }
+
+    pub fn stats(&self) -> MailroomStats {
+        self.mailroom.stats().clone()
+    }
+
+    pub fn suppressed_envelopes(&self) -> u64 {
+        self.mailroom.stats().total.suppressed
+    }
+
+    pub fn snapshot(&self) -> MailroomSnapshot {
+        self.mailroom.snapshot()
+    }
+
+    pub fn restore(&mut self, snapshot: MailroomSnapshot) {
+        self.mailroom.restore(snapshot);
+    }
+
+    pub fn rotate_key(&mut self) -> KeySuccession {
+        let key_succession = self.mailroom.rotate_secret_key(SecretKey::generate());
+        self.public_key = *key_succession.new_key();
+        key_succession
+    }
+
     pub async fn receive_payload(
         &mut self,
         payload: &str,
         at: DateTime<Utc>,
-    ) -> Result<(), MockReceivePayloadError> {
+    ) -> Result<ReceivedEnvelopes, MockReceivePayloadError> {
+        self.clock.set(at);
+        self.receive_payload_now(payload).await
+    }
+
+    // these leave the clock where it is
+    pub async fn receive_payload_now(
+        &mut self,
+        payload: &str,
+    ) -> Result<ReceivedEnvelopes, MockReceivePayloadError> {
         let unverified_payload =
             UntrustedPayload::from_json(payload).map_err(MockReceivePayloadError::ReadPayload)?;
         let verified_payload = unverified_payload
-            .try_trust(self.trusted_keys.clone())
+            .try_trust(
+                self.trusted_keys.clone(),
+                &self.mailroom.public_key(),
+                self.mailroom.accepted_periods(self.mailroom.now()),
+            )
             .map_err(MockReceivePayloadError::TrustPayload)?;
         self.mailroom
-            .receive_payload_at_time(&verified_payload, at)
+            .receive_payload(&verified_payload)
             .await
-            .map_err(MockReceivePayloadError::ReceiveInMailroom)?;
-        Ok(())
+            .map_err(MockReceivePayloadError::ReceiveInMailroom)
     }
 
-    pub async fn create_payload(&mut self, for_key: PublicKey, at: DateTime<Utc>) -> String {
+    pub async fn create_payload_now(&mut self, for_key: PublicKey) -> String {
         let outgoing_envelopes = self
             .mailroom
-            .get_outgoing_at_time(&for_key, TTLConfig::default(), at)
+            .get_outgoing(&for_key, TTLConfig::default())
             .await
             .unwrap();
         outgoing_envelopes.create_payload()
     }
 
+    pub async fn create_payload(&mut self, for_key: PublicKey, at: DateTime<Utc>) -> String {
+        self.clock.set(at);
+        self.create_payload_now(for_key).await
+    }
+
+    pub async fn create_cbor_payload(&mut self, for_key: PublicKey, at: DateTime<Utc>) -> Vec<u8> {
+        self.clock.set(at);
+        let outgoing_envelopes = self
+            .mailroom
+            .get_outgoing(&for_key, TTLConfig::default())
+            .await
+            .unwrap();
+        outgoing_envelopes.create_cbor_payload_with_version(PROTOCOL_VERSION)
+    }
+
     pub fn has_message_with_line(&self, line: &str) -> bool {
-        self.messages
-            .lock()
-            .unwrap()
+        self.mailroom
+            .archive()
+            .messages()
             .iter()
             .any(|message| message.contents.line == line)
     }
 
     pub fn has_message_from(&self, from_key: PublicKey) -> bool {
-        self.messages
-            .lock()
-            .unwrap()
+        self.mailroom
+            .archive()
+            .messages()
             .iter()
             .any(|message| *message.certificate.key == from_key.to_string())
     }
 
+    pub fn has_retraction_of_line(&self, line: &str) -> bool {
+        let messages = self.mailroom.archive().messages();
+        let retracted_signatures: HashSet<&String> = messages
+            .iter()
+            .filter(|message| message.contents.line == line)
+            .map(|message| &message.certificate.signature)
+            .collect();
+
+        messages.iter().any(|message| {
+            message.contents.kind == MessageKind::Retraction
+                && message
+                    .contents
+                    .retracts
+                    .as_ref()
+                    .is_some_and(|retracts| retracted_signatures.contains(retracts))
+        })
+    }
+
     pub fn has_forwarded_from(&self, from_key: PublicKey) -> bool {
-        self.envelopes.lock().unwrap().iter().any(|envelope| {
-            envelope
+        self.mailroom.archive().envelopes().iter().any(|archived| {
+            archived
+                .envelope
                 .forwarded
                 .iter()
-                .any(|forwarded| *forwarded == from_key.to_string())
+                .any(|hop| hop.key == from_key.to_string())
         })
     }
 
@@ -117,6 +221,7 @@ impl MockRelay {
 
 struct MockLineGenerator {
     name: String,
+    direct_lines: Arc<Mutex<VecDeque<(PublicKey, String)>>>,
 }
 
 impl GetNextLine for MockLineGenerator {
@@ -126,26 +231,14 @@ impl GetNextLine for MockLineGenerator {
             author: self.name.clone(),
         })
     }
-}
-
-struct MockArchive {
-    envelopes: Arc<Mutex<Vec<Envelope>>>,
-    messages: Arc<Mutex<HashSet<Message>>>,
-}
 
-impl Archive for MockArchive {
-    type Error = ();
+    fn get_next_direct_line(&mut self) -> Option<NextDirectLine> {
+        let (recipient, line) = self.direct_lines.lock().unwrap().pop_front()?;
 
-    async fn add_envelope_to_archive(&mut self, _: &str, envelope: &Envelope) -> Result<(), ()> {
-        self.envelopes.lock().unwrap().push(envelope.clone());
-        self.messages
-            .lock()
-            .unwrap()
-            .insert(envelope.message.clone());
-        Ok(())
-    }
-
-    async fn is_message_in_archive(&self, message: &Message) -> Result<bool, ()> {
-        Ok(self.messages.lock().unwrap().contains(message))
+        Some(NextDirectLine {
+            recipient,
+            line,
+            author: self.name.clone(),
+        })
     }
 }
@@ -0,0 +1,400 @@
+use std::{fs, path::Path};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::DateTime;
+use curve25519_dalek::Scalar;
+use ed25519_dalek::{Signer, SigningKey};
+use relay_core::{
+    crypto::PublicKey,
+    jcs,
+    payload::{EnvelopeRejectionReason, TrustedPayload, UntrustedPayload},
+    version::{INITIAL_TTL_MESSAGE_VERSION, PROTOCOL_VERSION},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha512};
+
+const PERIOD: i64 = 1745366400;
+
+#[derive(Deserialize)]
+struct Vector {
+    kind: String,
+    secret_key: String,
+    public_key: String,
+    input: String,
+    canonical: String,
+    signature: String,
+}
+
+impl Vector {
+    fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(
+            &BASE64_STANDARD
+                .decode(&self.secret_key)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+}
+
+fn read_vectors() -> Vec<(String, Vector)> {
+    let vectors_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors");
+
+    let vectors: Vec<(String, Vector)> = fs::read_dir(vectors_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .map(|path| {
+            (
+                path.file_name().unwrap().to_string_lossy().into_owned(),
+                serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap(),
+            )
+        })
+        .collect();
+
+    assert!(!vectors.is_empty());
+
+    vectors
+}
+
+fn payload_json(key: &str, signature: &str, signed_contents_json: &str) -> Value {
+    let mut payload: Value = serde_json::from_str(signed_contents_json).unwrap();
+    payload["certificate"] = json!({ "key": key, "signature": signature });
+    payload
+}
+
+#[test]
+fn canonical_bytes_match_vectors() {
+    for (name, vector) in read_vectors() {
+        assert_eq!(
+            jcs::canonicalize(&vector.input).unwrap(),
+            vector.canonical.as_bytes(),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn signatures_match_vectors() {
+    for (name, vector) in read_vectors() {
+        let signing_key = vector.signing_key();
+
+        assert_eq!(
+            BASE64_STANDARD.encode(signing_key.verifying_key().as_bytes()),
+            vector.public_key,
+            "{name}"
+        );
+        assert_eq!(
+            BASE64_STANDARD.encode(signing_key.sign(vector.canonical.as_bytes()).to_bytes()),
+            vector.signature,
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn payloads_from_vectors_are_trusted() {
+    for (name, vector) in read_vectors() {
+        let public_key = PublicKey::new_from_b64(&vector.public_key).unwrap();
+
+        let payload = match vector.kind.as_str() {
+            "contents" => {
+                let signed_contents_json = format!(
+                    r#"{{"version":1,"recipient":"{}","period":{},"envelopes":[{{"forwarded":[],"ttl":8,"message":{{"certificate":{{"key":"{}","signature":"{}"}},"contents":{}}}}}]}}"#,
+                    vector.public_key, PERIOD, vector.public_key, vector.signature, vector.input
+                );
+                let payload_signature = vector
+                    .signing_key()
+                    .sign(&jcs::canonicalize(&signed_contents_json).unwrap());
+
+                payload_json(
+                    &vector.public_key,
+                    &BASE64_STANDARD.encode(payload_signature.to_bytes()),
+                    &signed_contents_json,
+                )
+            }
+            "payload" => payload_json(&vector.public_key, &vector.signature, &vector.input),
+            _ => continue,
+        };
+
+        let recipient = PublicKey::new_from_b64(payload["recipient"].as_str().unwrap()).unwrap();
+        let period = DateTime::from_timestamp(payload["period"].as_i64().unwrap(), 0).unwrap();
+
+        let payload = payload.to_string();
+        let trusted_payload = UntrustedPayload::from_json(&payload)
+            .unwrap()
+            .try_trust([public_key], &recipient, period..=period)
+            .unwrap();
+
+        assert_eq!(trusted_payload.envelopes().len(), 1, "{name}");
+        assert!(trusted_payload.rejected_envelopes().is_empty(), "{name}");
+    }
+}
+
+fn find_vector(name: &str) -> Vector {
+    read_vectors()
+        .into_iter()
+        .find(|(vector_name, _)| vector_name == name)
+        .unwrap()
+        .1
+}
+
+fn envelope_json(key: &str, signature: &str, forwarded: &str, ttl: i64, contents: &str) -> String {
+    format!(
+        r#"{{"forwarded":{forwarded},"ttl":{ttl},"message":{{"certificate":{{"key":"{key}","signature":"{signature}"}},"contents":{contents}}}}}"#
+    )
+}
+
+fn trust_payload_from(sender: &Vector, envelopes: &[String]) -> TrustedPayload {
+    trust_payload_from_with_batching(sender, envelopes, true)
+}
+
+fn trust_payload_from_with_batching(
+    sender: &Vector,
+    envelopes: &[String],
+    batching: bool,
+) -> TrustedPayload {
+    let signed_contents_json = format!(
+        r#"{{"version":{},"recipient":"{}","period":{},"envelopes":[{}]}}"#,
+        PROTOCOL_VERSION,
+        sender.public_key,
+        PERIOD,
+        envelopes.join(",")
+    );
+    let payload_signature = sender
+        .signing_key()
+        .sign(&jcs::canonicalize(&signed_contents_json).unwrap());
+    let payload = payload_json(
+        &sender.public_key,
+        &BASE64_STANDARD.encode(payload_signature.to_bytes()),
+        &signed_contents_json,
+    )
+    .to_string();
+
+    let public_key = PublicKey::new_from_b64(&sender.public_key).unwrap();
+    let period = DateTime::from_timestamp(PERIOD, 0).unwrap();
+    let untrusted_payload = UntrustedPayload::from_json(&payload).unwrap();
+
+    if batching {
+        untrusted_payload.try_trust([public_key], &public_key, period..=period)
+    } else {
+        untrusted_payload.try_trust_without_batching([public_key], &public_key, period..=period)
+    }
+    .unwrap()
+}
+
+fn rejection_reasons(trusted_payload: &TrustedPayload) -> Vec<(usize, EnvelopeRejectionReason)> {
+    trusted_payload
+        .rejected_envelopes()
+        .iter()
+        .map(|rejected_envelope| (rejected_envelope.index(), rejected_envelope.reason()))
+        .collect()
+}
+
+#[test]
+fn bad_envelopes_are_rejected_individually() {
+    let vector = find_vector("contents_basic.json");
+    let envelope = |key: &str, ttl: i64, contents: &str| {
+        envelope_json(key, &vector.signature, "[]", ttl, contents)
+    };
+
+    let trusted_payload = trust_payload_from(
+        &vector,
+        &[
+            envelope(&vector.public_key, 8, &vector.input),
+            envelope("not a key", 8, &vector.input),
+            envelope(&vector.public_key, 0, &vector.input),
+            envelope(&vector.public_key, 300, &vector.input),
+            envelope(&vector.public_key, 8, r#"{"line":"?"}"#),
+            envelope(
+                &vector.public_key,
+                8,
+                r#"{"version":1,"uuid":"67e55044-10b1-426f-9247-bb680e5fe0c8","author":"relay","line":"you taught me why","created_at":1745366400}"#,
+            ),
+        ],
+    );
+
+    assert_eq!(trusted_payload.envelopes().len(), 1);
+    assert_eq!(
+        rejection_reasons(&trusted_payload),
+        vec![
+            (1, EnvelopeRejectionReason::MalformedKey),
+            (2, EnvelopeRejectionReason::InvalidTtl),
+            (3, EnvelopeRejectionReason::InvalidTtl),
+            (4, EnvelopeRejectionReason::UnparsableContents),
+            (5, EnvelopeRejectionReason::BadSignature),
+        ]
+    );
+}
+
+#[test]
+fn tampered_forwarding_hops_are_rejected() {
+    let message = find_vector("contents_basic.json");
+    let hop = find_vector("hop_first.json");
+    let forwarded = |ttl: i64| {
+        format!(
+            r#"[{{"key":"{}","ttl":{},"signature":"{}"}}]"#,
+            hop.public_key, ttl, hop.signature
+        )
+    };
+    let envelope = |forwarded: &str, ttl: i64| {
+        envelope_json(
+            &message.public_key,
+            &message.signature,
+            forwarded,
+            ttl,
+            &message.input,
+        )
+    };
+
+    let trusted_payload = trust_payload_from(
+        &hop,
+        &[
+            envelope(&forwarded(7), 7),
+            envelope(&forwarded(7), 255),
+            envelope(&forwarded(8), 8),
+            envelope("[]", 8),
+        ],
+    );
+
+    assert_eq!(trusted_payload.envelopes().len(), 1);
+    assert_eq!(
+        rejection_reasons(&trusted_payload),
+        vec![
+            (1, EnvelopeRejectionReason::InvalidTtl),
+            (2, EnvelopeRejectionReason::InvalidForwardingHops),
+            (3, EnvelopeRejectionReason::InvalidForwardingHops),
+        ]
+    );
+}
+
+fn sign(signing_key: &SigningKey, json: &str) -> String {
+    BASE64_STANDARD.encode(
+        signing_key
+            .sign(&jcs::canonicalize(json).unwrap())
+            .to_bytes(),
+    )
+}
+
+// a relay that drops the hops before it can't start the message over at a higher ttl, since the
+// first hop has to stay under the ttl the author signed
+#[test]
+fn ttl_above_the_signed_one_is_rejected() {
+    let author = find_vector("contents_basic.json");
+    let hop = find_vector("hop_first.json");
+    let contents = |ttl: Option<u8>| {
+        let mut contents: Value = serde_json::from_str(&author.input).unwrap();
+        contents["version"] = json!(INITIAL_TTL_MESSAGE_VERSION);
+        if let Some(ttl) = ttl {
+            contents["ttl"] = json!(ttl);
+        }
+        contents.to_string()
+    };
+    let envelope = |contents: &str, hop_ttl: Option<u8>, ttl: u8| {
+        let message_signature = sign(&author.signing_key(), contents);
+        let forwarded = match hop_ttl {
+            Some(hop_ttl) => {
+                let hop_json = json!({
+                    "message_signature": message_signature,
+                    "previous_hop": null,
+                    "ttl": hop_ttl,
+                })
+                .to_string();
+                format!(
+                    r#"[{{"key":"{}","ttl":{},"signature":"{}"}}]"#,
+                    hop.public_key,
+                    hop_ttl,
+                    sign(&hop.signing_key(), &hop_json)
+                )
+            }
+            None => "[]".to_owned(),
+        };
+
+        envelope_json(
+            &author.public_key,
+            &message_signature,
+            &forwarded,
+            ttl.into(),
+            contents,
+        )
+    };
+
+    let forwarded_payload = trust_payload_from(
+        &hop,
+        &[
+            envelope(&contents(Some(8)), Some(7), 7),
+            envelope(&contents(Some(8)), Some(255), 255),
+            envelope(&contents(Some(8)), Some(8), 8),
+            envelope(&contents(None), Some(7), 7),
+        ],
+    );
+    assert_eq!(forwarded_payload.envelopes().len(), 1);
+    assert_eq!(
+        rejection_reasons(&forwarded_payload),
+        vec![
+            (1, EnvelopeRejectionReason::InvalidTtl),
+            (2, EnvelopeRejectionReason::InvalidTtl),
+            (3, EnvelopeRejectionReason::UnparsableContents),
+        ]
+    );
+
+    let authored_payload = trust_payload_from(
+        &author,
+        &[
+            envelope(&contents(Some(8)), None, 8),
+            envelope(&contents(Some(8)), None, 255),
+        ],
+    );
+    assert_eq!(authored_payload.envelopes().len(), 1);
+    assert_eq!(
+        rejection_reasons(&authored_payload),
+        vec![(1, EnvelopeRejectionReason::InvalidTtl)]
+    );
+}
+
+// with r of order 2 and s = k * a, r + k * a = s * b is off by exactly r, which the random
+// weights in a batch cancel out about half the time. verify_strict always rejects small order r,
+// so the batch path has to too
+#[test]
+fn small_order_r_is_rejected_with_and_without_batching() {
+    let vector = find_vector("contents_basic.json");
+    let signing_key = vector.signing_key();
+
+    let mut small_order_r = [0xff; 32];
+    small_order_r[0] = 0xec;
+    small_order_r[31] = 0x7f;
+
+    for i in 0..8 {
+        let mut contents: Value = serde_json::from_str(&vector.input).unwrap();
+        contents["line"] = json!(format!("small order {i}"));
+        let contents_json = contents.to_string();
+
+        let k = Scalar::from_hash(
+            Sha512::new()
+                .chain_update(small_order_r)
+                .chain_update(signing_key.verifying_key().as_bytes())
+                .chain_update(jcs::canonicalize(&contents_json).unwrap()),
+        );
+        let s = k * signing_key.to_scalar();
+        let signature = BASE64_STANDARD.encode([small_order_r, s.to_bytes()].concat());
+        let envelope = envelope_json(&vector.public_key, &signature, "[]", 8, &contents_json);
+
+        for batching in [true, false] {
+            let trusted_payload = trust_payload_from_with_batching(
+                &vector,
+                std::slice::from_ref(&envelope),
+                batching,
+            );
+
+            assert_eq!(
+                rejection_reasons(&trusted_payload),
+                vec![(0, EnvelopeRejectionReason::BadSignature)],
+                "{i} with batching {batching}"
+            );
+        }
+    }
+}
@@ -0,0 +1,2 @@
+ALTER TABLE "forwards" ADD COLUMN "ttl" INTEGER;
+ALTER TABLE "forwards" ADD COLUMN "signature" TEXT;
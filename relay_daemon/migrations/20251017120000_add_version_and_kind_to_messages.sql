@@ -0,0 +1,2 @@
+ALTER TABLE "messages" ADD COLUMN "version" INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE "messages" ADD COLUMN "kind" TEXT NOT NULL DEFAULT 'line';
@@ -0,0 +1,2 @@
+ALTER TABLE "messages" ADD COLUMN "retracts" TEXT;
+ALTER TABLE "messages" ADD COLUMN "retracted" INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+CREATE TABLE "quarantined_envelopes" (
+    "id" INTEGER NOT NULL UNIQUE,
+    "from_key" TEXT NOT NULL,
+    "signature" TEXT NOT NULL,
+    "author" TEXT NOT NULL,
+    "line" TEXT NOT NULL,
+    "envelope" TEXT NOT NULL,
+    "received_at" INTEGER NOT NULL,
+    PRIMARY KEY("id" AUTOINCREMENT)
+);
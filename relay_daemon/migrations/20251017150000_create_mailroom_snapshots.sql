@@ -0,0 +1,6 @@
+CREATE TABLE "mailroom_snapshots" (
+    "id" INTEGER NOT NULL CHECK ("id" = 1),
+    "snapshot" TEXT NOT NULL,
+    "saved_at" INTEGER NOT NULL,
+    PRIMARY KEY("id")
+);
@@ -0,0 +1,4 @@
+CREATE INDEX "messages_from_key_created_at" ON "messages" ("from_key", "created_at");
+CREATE INDEX "messages_created_at" ON "messages" ("created_at");
+CREATE INDEX "envelopes_message_id" ON "envelopes" ("message_id");
+CREATE INDEX "forwards_envelope_id" ON "forwards" ("envelope_id");
@@ -0,0 +1 @@
+UPDATE "messages" SET "created_at" = "received_at" WHERE "created_at" IS NULL;
@@ -1,6 +1,12 @@
-use std::str::FromStr;
+use std::{fmt::Display, str::FromStr, time::Duration};
 
-use relay_core::crypto::PublicKey;
+use relay_core::{
+    crypto::PublicKey,
+    filter::Filters,
+    mailroom::{
+        DEFAULT_CARRY_OVER_PERIODS, DEFAULT_INITIAL_TTL, DEFAULT_MAX_MESSAGE_AGE, ForwardingLimits,
+    },
+};
 use reqwest::Url;
 use serde::{Deserialize, Serialize, ser::SerializeStruct};
 use thiserror::Error;
@@ -10,12 +16,45 @@ pub struct DaemonConfig {
     pub trusted_relays: Vec<RelayData>,
     pub custom_initial_ttl: Option<u8>,
     pub custom_max_forwarding_ttl: Option<u8>,
+    pub custom_max_message_age: Option<Duration>,
+    pub custom_carry_over_periods: Option<u32>,
+    pub forwarding_limits: ForwardingLimits,
+    pub filters: Filters,
 }
 
 impl DaemonConfig {
     pub(crate) fn trusted_public_keys(&self) -> Vec<PublicKey> {
         self.trusted_relays.iter().map(|relay| relay.key).collect()
     }
+
+    pub(crate) fn initial_ttl(&self) -> u8 {
+        self.custom_initial_ttl.unwrap_or(DEFAULT_INITIAL_TTL)
+    }
+
+    pub(crate) fn max_message_age(&self) -> Duration {
+        self.custom_max_message_age
+            .unwrap_or(DEFAULT_MAX_MESSAGE_AGE)
+    }
+
+    pub(crate) fn carry_over_periods(&self) -> u32 {
+        self.custom_carry_over_periods
+            .unwrap_or(DEFAULT_CARRY_OVER_PERIODS)
+    }
+
+    pub(crate) fn succeed_relay_key(
+        &mut self,
+        old_key: &PublicKey,
+        new_key: PublicKey,
+    ) -> Option<RelayData> {
+        let relay = self
+            .trusted_relays
+            .iter_mut()
+            .find(|relay| relay.key == *old_key)?;
+
+        relay.key = new_key;
+
+        Some(relay.clone())
+    }
 }
 
 #[derive(Clone)]
@@ -29,11 +68,29 @@ pub enum RelayDataError {
     UrlNotValid,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RelayData {
     pub key: PublicKey,
     pub nickname: Option<String>,
     pub(crate) endpoint: Option<Url>,
+    pub compression: Compression,
 }
 
 impl RelayData {
@@ -51,6 +108,7 @@ impl RelayData {
             key,
             nickname,
             endpoint,
+            compression: Compression::default(),
         })
     }
 
@@ -64,13 +122,14 @@ impl Serialize for RelayData {
     where
         S: serde::Serializer,
     {
-        let mut state = serializer.serialize_struct("RelayData", 3)?;
+        let mut state = serializer.serialize_struct("RelayData", 4)?;
         state.serialize_field("key", &self.key)?;
         state.serialize_field("nickname", &self.nickname)?;
         state.serialize_field(
             "endpoint",
             &self.endpoint.clone().map(|url| url.to_string()),
         )?;
+        state.serialize_field("compression", &self.compression)?;
         state.end()
     }
 }
@@ -85,6 +144,8 @@ impl<'de> Deserialize<'de> for RelayData {
             key: PublicKey,
             nickname: Option<String>,
             endpoint: Option<String>,
+            #[serde(default)]
+            compression: Compression,
         }
 
         let intermediate = RelayDataIntermediate::deserialize(deserializer)?;
@@ -99,6 +160,7 @@ impl<'de> Deserialize<'de> for RelayData {
             key: intermediate.key,
             nickname: intermediate.nickname,
             endpoint,
+            compression: intermediate.compression,
         })
     }
 }
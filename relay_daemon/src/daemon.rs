@@ -1,28 +1,41 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use archive::{DBArchive, DBError};
-use axum::{Router, extract::State, response::IntoResponse, routing};
-use chrono::{DateTime, Timelike, Utc};
+use axum::{
+    Router,
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, header::CONTENT_TYPE},
+    response::IntoResponse,
+    routing,
+};
+use exchange::{ListenerFormats, WireFormat};
 use relay_core::{
-    crypto::SecretKey,
-    mailroom::{GetNextLine, Mailroom},
+    archive::{ArchivedEnvelope, MessageQuery},
+    clock::{Clock, PeriodSchedule, SystemClock},
+    crypto::{KeySuccession, SecretKey},
+    mailroom::{Archive, GetNextLine, Mailroom, MailroomStats},
+    message::Message,
 };
 use thiserror::Error;
 use tokio::{
     net::TcpListener,
     sync::{Mutex, RwLock},
+    time,
 };
-use tokio_cron_scheduler::{Job, JobScheduler};
+use tower_http::{compression::CompressionLayer, decompression::RequestDecompressionLayer};
 
 use crate::{
     config::DaemonConfig,
     event::{Event, EventSender},
 };
 
-mod archive;
+pub mod archive;
 mod exchange;
 
 pub const DEFAULT_LISTENING_PORT: u16 = 7070;
+const FAST_PERIOD_LENGTH: Duration = Duration::from_secs(10);
+const CLOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Error, Debug)]
 pub enum DaemonError {
@@ -30,8 +43,10 @@ pub enum DaemonError {
     CannotConnectToDB,
     #[error("cannot bind port {0} (is it in use?)")]
     CannotBindPort(u16),
-    #[error("cannot start sender for some reason")]
-    CannotStartSender,
+    #[error("cannot read from db")]
+    CannotReadDB,
+    #[error("cannot write to db")]
+    CannotWriteDB,
 }
 
 pub struct Daemon<L>
@@ -39,9 +54,13 @@ where
     L: GetNextLine,
 {
     mailroom: Arc<Mutex<Mailroom<L, DBArchive, DBError>>>,
+    // lookups read the db through their own handle, so they don't wait on the mailroom lock
+    archive: DBArchive,
     event_sender: EventSender,
     config: Arc<RwLock<DaemonConfig>>,
-    fast_mode: bool,
+    listener_formats: ListenerFormats,
+    clock: Arc<dyn Clock>,
+    schedule: PeriodSchedule,
 }
 
 impl<L> Daemon<L>
@@ -55,24 +74,16 @@ where
         db_url: &str,
         config: DaemonConfig,
     ) -> Result<Self, DaemonError> {
-        let db_archive = DBArchive::new(db_url, event_sender.clone())
-            .await
-            .map_err(|_| DaemonError::CannotConnectToDB)?;
-
-        let mailroom = Arc::new(Mutex::new(Mailroom::new(
+        Self::new_with_clock(
             line_generator,
-            db_archive,
-            secret_key,
-        )));
-
-        let config = Arc::new(RwLock::new(config));
-
-        Ok(Self {
-            mailroom,
             event_sender,
+            secret_key,
+            db_url,
             config,
-            fast_mode: false,
-        })
+            Arc::new(SystemClock),
+            PeriodSchedule::HOURLY,
+        )
+        .await
     }
 
     pub async fn new_fast(
@@ -82,80 +93,90 @@ where
         db_url: &str,
         config: DaemonConfig,
     ) -> Result<Self, DaemonError> {
-        let flatten_time = |datetime: DateTime<Utc>| {
-            datetime
-                .with_second(datetime.second() / 10 * 10)
-                .expect("should be able to set seconds to a multiple of 10")
-                .with_nanosecond(0)
-                .expect("should be able to set any utc time to nanosecond 0")
-        };
-        let interval = Duration::from_secs(10);
+        Self::new_with_clock(
+            line_generator,
+            event_sender,
+            secret_key,
+            db_url,
+            config,
+            Arc::new(SystemClock),
+            PeriodSchedule::every(FAST_PERIOD_LENGTH),
+        )
+        .await
+    }
 
-        let db_archive = DBArchive::new(db_url, event_sender.clone())
+    pub async fn new_with_clock(
+        line_generator: L,
+        event_sender: EventSender,
+        secret_key: SecretKey,
+        db_url: &str,
+        config: DaemonConfig,
+        clock: Arc<dyn Clock>,
+        schedule: PeriodSchedule,
+    ) -> Result<Self, DaemonError> {
+        let db_archive = DBArchive::new(db_url, event_sender.clone(), Arc::clone(&clock))
             .await
             .map_err(|_| DaemonError::CannotConnectToDB)?;
 
-        let mailroom = Arc::new(Mutex::new(Mailroom::new_with_custom_time(
+        let snapshot = db_archive
+            .load_snapshot()
+            .await
+            .map_err(|_| DaemonError::CannotReadDB)?;
+
+        let archive = db_archive.clone();
+        let mut mailroom = Mailroom::new_with_clock(
             line_generator,
             db_archive,
             secret_key,
-            flatten_time,
-            interval,
-        )));
+            Arc::clone(&clock),
+            schedule,
+        );
+        mailroom.set_max_message_age(config.max_message_age());
+        mailroom.set_initial_ttl(config.initial_ttl());
+        mailroom.set_carry_over_periods(config.carry_over_periods());
+        mailroom.set_forwarding_limits(config.forwarding_limits);
+        mailroom.set_filter(config.filters.clone());
+        if let Some(snapshot) = snapshot {
+            mailroom.restore(snapshot);
+            event_sender.send(Event::RestoredMailroomSnapshot).ok();
+        }
+        let mailroom = Arc::new(Mutex::new(mailroom));
 
         let config = Arc::new(RwLock::new(config));
 
         Ok(Self {
             mailroom,
+            archive,
             event_sender,
             config,
-            fast_mode: true,
+            listener_formats: Arc::new(Mutex::new(HashMap::new())),
+            clock,
+            schedule,
         })
     }
 
-    pub async fn start_sender(&self) -> Result<(), DaemonError> {
-        let scheduler = JobScheduler::new()
-            .await
-            .map_err(|_| DaemonError::CannotStartSender)?;
-
+    pub async fn start_sender(&self) {
         let mailroom = Arc::clone(&self.mailroom);
         let config = Arc::clone(&self.config);
+        let listener_formats = Arc::clone(&self.listener_formats);
         let event_sender = self.event_sender.clone();
-        scheduler
-            .add(
-                Job::new_async(
-                    match self.fast_mode {
-                        true => "*/10 * * * * *",
-                        false => "0 0 * * * *",
-                    },
-                    move |_, _| {
-                        let mailroom = Arc::clone(&mailroom);
-                        let config = Arc::clone(&config);
-                        let event_sender = event_sender.clone();
-                        Box::pin(async move {
-                            let config = config.read().await.to_owned();
-                            exchange::send_to_listeners(
-                                Arc::clone(&mailroom),
-                                &config,
-                                event_sender.clone(),
-                            )
-                            .await;
-                        })
-                    },
-                )
-                .map_err(|_| DaemonError::CannotStartSender)?,
-            )
-            .await
-            .map_err(|_| DaemonError::CannotStartSender)?;
+        let clock = Arc::clone(&self.clock);
+        let schedule = self.schedule;
+        tokio::spawn(async move {
+            loop {
+                wait_for_next_period(clock.as_ref(), schedule).await;
 
-        scheduler
-            .start()
-            .await
-            .map_err(|_| DaemonError::CannotStartSender)?;
+                exchange::send_to_listeners(
+                    Arc::clone(&mailroom),
+                    Arc::clone(&config),
+                    Arc::clone(&listener_formats),
+                    event_sender.clone(),
+                )
+                .await;
+            }
+        });
 
         self.event_sender.send(Event::SenderStartedSchedule).ok();
-
-        Ok(())
     }
 
     pub async fn start_listener(&self, custom_port: Option<u16>) -> Result<(), DaemonError> {
@@ -166,6 +187,9 @@ where
         });
         let router = Router::new()
             .route("/", routing::post(Self::handle_request))
+            // the body limit applies to decompressed bodies, so compressed ones can't get around it
+            .layer(RequestDecompressionLayer::new())
+            .layer(CompressionLayer::new())
             .with_state(listener_state);
 
         let port = custom_port.unwrap_or(DEFAULT_LISTENING_PORT);
@@ -190,21 +214,99 @@ where
 
     async fn handle_request(
         State(state): State<Arc<ListenerState<L>>>,
-        body: String,
+        headers: HeaderMap,
+        body: Bytes,
     ) -> impl IntoResponse {
-        let config = &state.config.read().await.to_owned();
-        exchange::respond_to_sender(
-            &body,
-            Arc::clone(&state.mailroom),
-            config,
-            state.event_sender.clone(),
+        let response_format = WireFormat::from_accept(&headers);
+        let response = match exchange::read_request_body(&headers, &body) {
+            Ok(payload) => exchange::respond_to_sender(
+                &payload,
+                exchange::read_versions_header(&headers),
+                Arc::clone(&state.mailroom),
+                Arc::clone(&state.config),
+                state.event_sender.clone(),
+            )
+            .await
+            .map(|payload| {
+                (
+                    [(CONTENT_TYPE, response_format.content_type())],
+                    response_format.encode(payload),
+                )
+            }),
+            Err(error) => {
+                state
+                    .event_sender
+                    .send(Event::ListenerReceivedBadPayload)
+                    .ok();
+                Err(error)
+            }
+        };
+
+        (
+            [(
+                exchange::PROTOCOL_VERSIONS_HEADER,
+                exchange::versions_header(),
+            )],
+            response,
         )
-        .await
     }
 
-    pub async fn update_config(&mut self, config: DaemonConfig) {
+    pub async fn retract_line(&self, line: &str) -> Result<Vec<Message>, DaemonError> {
+        let mut mailroom = self.mailroom.lock().await;
+        let retracting = mailroom
+            .retract_line(line)
+            .await
+            .map_err(|_| DaemonError::CannotReadDB)?;
+
+        // queued retractions would otherwise be lost if the relay stops before the next period
+        mailroom
+            .archive()
+            .save_snapshot(&mailroom.snapshot())
+            .await
+            .map_err(|_| DaemonError::CannotWriteDB)?;
+
+        Ok(retracting)
+    }
+
+    pub async fn update_config(&self, config: DaemonConfig) {
+        {
+            let mut mailroom = self.mailroom.lock().await;
+            mailroom.set_max_message_age(config.max_message_age());
+            mailroom.set_initial_ttl(config.initial_ttl());
+            mailroom.set_carry_over_periods(config.carry_over_periods());
+            mailroom.set_forwarding_limits(config.forwarding_limits);
+            mailroom.set_filter(config.filters.clone());
+        }
         *self.config.write().await = config;
     }
+
+    pub async fn stats(&self) -> MailroomStats {
+        self.mailroom.lock().await.stats().clone()
+    }
+
+    pub async fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, DaemonError> {
+        self.archive
+            .find_messages(query)
+            .await
+            .map_err(|_| DaemonError::CannotReadDB)
+    }
+
+    pub async fn find_envelopes_of_message(
+        &self,
+        signature: &str,
+    ) -> Result<Vec<ArchivedEnvelope>, DaemonError> {
+        self.archive
+            .find_envelopes_of_message(signature)
+            .await
+            .map_err(|_| DaemonError::CannotReadDB)
+    }
+
+    pub async fn set_key_successions(&self, key_successions: Vec<KeySuccession>) {
+        self.mailroom
+            .lock()
+            .await
+            .set_key_successions(key_successions);
+    }
 }
 
 struct ListenerState<L: GetNextLine> {
@@ -212,3 +314,19 @@ struct ListenerState<L: GetNextLine> {
     event_sender: EventSender,
     config: Arc<RwLock<DaemonConfig>>,
 }
+
+// sleeps in short steps and asks the clock each time, so wall clock jumps and virtual clocks that
+// are moved by hand still wake the sender once the period turns over
+async fn wait_for_next_period(clock: &dyn Clock, schedule: PeriodSchedule) {
+    let next_period_start = schedule.next_period_start(clock.now());
+
+    loop {
+        let now = clock.now();
+        if now >= next_period_start {
+            break;
+        }
+
+        let remaining = (next_period_start - now).to_std().unwrap_or_default();
+        time::sleep(remaining.min(CLOCK_POLL_INTERVAL)).await;
+    }
+}
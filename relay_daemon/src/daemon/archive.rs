@@ -1,5 +1,11 @@
-use chrono::Utc;
-use relay_core::{mailroom::Archive, message::Message};
+use std::sync::Arc;
+
+use relay_core::{
+    archive::{ArchivedEnvelope, MessageQuery},
+    clock::Clock,
+    mailroom::{Archive, MailroomSnapshot},
+    message::{Certificate, Envelope, ForwardingHop, Message, MessageContents, MessageKind},
+};
 use sqlx::{
     Error as SqlxError, Sqlite, SqlitePool,
     migrate::{MigrateDatabase, MigrateError},
@@ -9,7 +15,7 @@ use thiserror::Error;
 use crate::event::{Event, EventSender};
 
 #[derive(Error, Debug)]
-pub(crate) enum DBError {
+pub enum DBError {
     #[error("cannot create db: {0}")]
     Create(#[source] SqlxError),
     #[error("cannot connect to db: {0}")]
@@ -18,15 +24,61 @@ pub(crate) enum DBError {
     Migration(#[source] MigrateError),
     #[error("db query failed: {0}")]
     Query(#[from] SqlxError),
+    #[error("unknown message kind in db: {0}")]
+    UnknownKind(String),
+}
+
+struct MessageRow {
+    id: i64,
+    from_key: String,
+    signature: String,
+    version: i64,
+    kind: String,
+    uuid: String,
+    author: String,
+    line: String,
+    created_at: Option<i64>,
+    retracts: Option<String>,
+    ttl: Option<i64>,
 }
 
-pub(crate) struct DBArchive {
+impl TryFrom<MessageRow> for Message {
+    type Error = DBError;
+
+    fn try_from(row: MessageRow) -> Result<Self, Self::Error> {
+        Ok(Message {
+            certificate: Certificate {
+                key: row.from_key,
+                signature: row.signature,
+            },
+            contents: MessageContents {
+                version: row.version as u16,
+                kind: kind_from_name(&row.kind)?,
+                uuid: row.uuid,
+                author: row.author,
+                line: row.line,
+                created_at: row.created_at.unwrap_or_default(),
+                retracts: row.retracts,
+                ttl: row.ttl.map(|ttl| ttl as u8),
+            },
+        })
+    }
+}
+
+// the pool is a shared handle, so a clone reads from the same db without going through the mailroom
+#[derive(Clone)]
+pub struct DBArchive {
     pool: SqlitePool,
     event_sender: EventSender,
+    clock: Arc<dyn Clock>,
 }
 
 impl DBArchive {
-    pub(crate) async fn new(db_url: &str, event_sender: EventSender) -> Result<Self, DBError> {
+    pub async fn new(
+        db_url: &str,
+        event_sender: EventSender,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, DBError> {
         let db_url = format!("sqlite:{db_url}");
 
         if !Sqlite::database_exists(&db_url).await.unwrap_or(false) {
@@ -44,7 +96,93 @@ impl DBArchive {
             .await
             .map_err(DBError::Migration)?;
 
-        Ok(Self { pool, event_sender })
+        Ok(Self {
+            pool,
+            event_sender,
+            clock,
+        })
+    }
+
+    // only the latest snapshot is kept
+    pub(crate) async fn save_snapshot(&self, snapshot: &MailroomSnapshot) -> Result<(), DBError> {
+        let timestamp = self.clock.now().timestamp();
+        let snapshot_json =
+            serde_json::to_string(snapshot).expect("should be able to serialize any snapshot");
+
+        sqlx::query!(
+            "
+            INSERT INTO mailroom_snapshots (id, snapshot, saved_at)
+            VALUES (1, ?, ?)
+            ON CONFLICT (id) DO UPDATE SET snapshot = excluded.snapshot, saved_at = excluded.saved_at
+            ",
+            snapshot_json,
+            timestamp
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // a snapshot that can't be read anymore is as good as none, the mailroom just starts fresh
+    pub(crate) async fn load_snapshot(&self) -> Result<Option<MailroomSnapshot>, DBError> {
+        Ok(sqlx::query!(
+            "
+            SELECT snapshot
+            FROM mailroom_snapshots
+            WHERE id = 1
+            "
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .and_then(|row| serde_json::from_str(&row.snapshot).ok()))
+    }
+
+    // retractions and the lines they take back can come in either order, and only count when both
+    // are signed by the same key
+    async fn mark_retracted(&self, message: &Message) -> Result<(), DBError> {
+        let marked = match message.contents.kind {
+            MessageKind::Retraction => sqlx::query!(
+                "
+                UPDATE messages
+                SET retracted = 1
+                WHERE signature = ? AND from_key = ? AND kind = 'line' AND retracted = 0
+                RETURNING line
+                ",
+                message.contents.retracts,
+                message.certificate.key
+            )
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| (message.clone(), row.line)),
+            MessageKind::Line => sqlx::query!(
+                "
+                UPDATE messages
+                SET retracted = 1
+                WHERE signature = ? AND EXISTS (
+                    SELECT id
+                    FROM messages
+                    WHERE kind = 'retraction' AND retracts = ? AND from_key = ?
+                )
+                RETURNING line
+                ",
+                message.certificate.signature,
+                message.certificate.signature,
+                message.certificate.key
+            )
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| (message.clone(), row.line)),
+            MessageKind::Direct => None,
+        };
+
+        if let Some((message, line)) = marked {
+            self.event_sender
+                .send(Event::RetractedLineInArchive(message, line))
+                .ok();
+        }
+
+        Ok(())
     }
 }
 
@@ -69,9 +207,9 @@ impl Archive for DBArchive {
     async fn add_envelope_to_archive(
         &mut self,
         from: &str,
-        envelope: &relay_core::message::Envelope,
+        envelope: &Envelope,
     ) -> Result<(), Self::Error> {
-        let timestamp = Utc::now().timestamp();
+        let timestamp = self.clock.now().timestamp();
 
         let message_id = if let Some(found_message) = sqlx::query!(
             "
@@ -91,21 +229,32 @@ impl Archive for DBArchive {
                 .send(Event::AddedMessageToArchive(envelope.message.clone()))
                 .ok();
 
-            sqlx::query!(
+            let kind = kind_name(envelope.message.contents.kind);
+
+            let message_id = sqlx::query!(
                 "
-                INSERT INTO messages (from_key, signature, uuid, author, line, received_at)
-                VALUES (?, ?, ?, ?, ?, ?)
+                INSERT INTO messages (from_key, signature, version, kind, uuid, author, line, created_at, retracts, ttl, received_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
                 ",
                 envelope.message.certificate.key,
                 envelope.message.certificate.signature,
+                envelope.message.contents.version,
+                kind,
                 envelope.message.contents.uuid,
                 envelope.message.contents.author,
                 envelope.message.contents.line,
+                envelope.message.contents.created_at,
+                envelope.message.contents.retracts,
+                envelope.message.contents.ttl,
                 timestamp
             )
             .execute(&self.pool)
             .await?
-            .last_insert_rowid()
+            .last_insert_rowid();
+
+            self.mark_retracted(&envelope.message).await?;
+
+            message_id
         };
 
         let envelope_id = sqlx::query!(
@@ -122,13 +271,15 @@ impl Archive for DBArchive {
         .await?
         .last_insert_rowid();
 
-        for forwarding_key in &envelope.forwarded {
+        for hop in &envelope.forwarded {
             sqlx::query!(
                 "
-                INSERT INTO forwards (from_key, envelope_id)
-                VALUES (?, ?)
+                INSERT INTO forwards (from_key, ttl, signature, envelope_id)
+                VALUES (?, ?, ?, ?)
                 ",
-                forwarding_key,
+                hop.key,
+                hop.ttl,
+                hop.signature,
                 envelope_id
             )
             .execute(&self.pool)
@@ -137,4 +288,186 @@ impl Archive for DBArchive {
 
         Ok(())
     }
+
+    // quarantined envelopes are kept whole so they can be looked at later, but never forwarded
+    async fn add_envelope_to_quarantine(
+        &mut self,
+        from: &str,
+        envelope: &Envelope,
+    ) -> Result<(), Self::Error> {
+        let timestamp = self.clock.now().timestamp();
+        let envelope_json =
+            serde_json::to_string(envelope).expect("should be able to serialize any envelope");
+
+        sqlx::query!(
+            "
+            INSERT INTO quarantined_envelopes (from_key, signature, author, line, envelope, received_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ",
+            from,
+            envelope.message.certificate.signature,
+            envelope.message.contents.author,
+            envelope.message.contents.line,
+            envelope_json,
+            timestamp
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn find_messages_with_line(
+        &self,
+        key: &str,
+        line: &str,
+    ) -> Result<Vec<Message>, Self::Error> {
+        Ok(sqlx::query!(
+            "
+            SELECT from_key, signature, version, uuid, author, line, created_at, ttl
+            FROM messages
+            WHERE from_key = ? AND line = ? AND kind = 'line'
+            ",
+            key,
+            line
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| Message {
+            certificate: Certificate {
+                key: row.from_key,
+                signature: row.signature,
+            },
+            contents: MessageContents {
+                version: row.version as u16,
+                kind: MessageKind::Line,
+                uuid: row.uuid,
+                author: row.author,
+                line: row.line,
+                created_at: row.created_at.unwrap_or_default(),
+                retracts: None,
+                ttl: row.ttl.map(|ttl| ttl as u8),
+            },
+        })
+        .collect())
+    }
+
+    // messages from before created_at was stored have their received_at filled in by a migration.
+    // each numbered parameter is bound once and can be used more than once, and a negative limit is
+    // no limit in sqlite
+    async fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, Self::Error> {
+        sqlx::query_as!(
+            MessageRow,
+            "
+            SELECT id, from_key, signature, version, kind, uuid, author, line, created_at, retracts, ttl
+            FROM messages
+            WHERE (?1 IS NULL OR from_key = ?1)
+                AND (?2 IS NULL OR uuid = ?2)
+                AND (?3 IS NULL OR created_at >= ?3)
+                AND (?4 IS NULL OR created_at < ?4)
+            ORDER BY created_at, id
+            LIMIT COALESCE(?5, -1) OFFSET ?6
+            ",
+            query.key,
+            query.uuid,
+            query.created_from,
+            query.created_until,
+            query.limit,
+            query.offset
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(Message::try_from)
+        .collect()
+    }
+
+    async fn find_envelopes_of_message(
+        &self,
+        signature: &str,
+    ) -> Result<Vec<ArchivedEnvelope>, Self::Error> {
+        let Some(row) = sqlx::query_as!(
+            MessageRow,
+            "
+            SELECT id, from_key, signature, version, kind, uuid, author, line, created_at, retracts, ttl
+            FROM messages
+            WHERE signature = ?
+            LIMIT 1
+            ",
+            signature
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(vec![]);
+        };
+        let message_id = row.id;
+        let message = Message::try_from(row)?;
+
+        // one row per hop, or a single row without one for an envelope that wasn't forwarded. hops
+        // stored before their ttl and signature were kept come back without them
+        let rows = sqlx::query!(
+            r#"
+            SELECT envelopes.id AS envelope_id, envelopes.from_key, envelopes.ttl,
+                forwards.from_key AS "hop_key?", forwards.ttl AS hop_ttl,
+                forwards.signature AS hop_signature
+            FROM envelopes
+            LEFT JOIN forwards ON forwards.envelope_id = envelopes.id
+            WHERE envelopes.message_id = ?
+            ORDER BY envelopes.id, forwards.rowid
+            "#,
+            message_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut envelopes: Vec<ArchivedEnvelope> = vec![];
+        let mut last_envelope_id = None;
+        for row in rows {
+            if last_envelope_id != Some(row.envelope_id) {
+                last_envelope_id = Some(row.envelope_id);
+                envelopes.push(ArchivedEnvelope {
+                    from: row.from_key,
+                    envelope: Envelope {
+                        forwarded: vec![],
+                        ttl: row.ttl as u8,
+                        message: message.clone(),
+                    },
+                });
+            }
+
+            if let Some(hop_key) = row.hop_key {
+                envelopes
+                    .last_mut()
+                    .expect("should be able to find the envelope of a hop")
+                    .envelope
+                    .forwarded
+                    .push(ForwardingHop {
+                        key: hop_key,
+                        ttl: row.hop_ttl.unwrap_or_default() as u8,
+                        signature: row.hop_signature.unwrap_or_default(),
+                    });
+            }
+        }
+
+        Ok(envelopes)
+    }
+}
+
+fn kind_from_name(name: &str) -> Result<MessageKind, DBError> {
+    match name {
+        "line" => Ok(MessageKind::Line),
+        "direct" => Ok(MessageKind::Direct),
+        "retraction" => Ok(MessageKind::Retraction),
+        _ => Err(DBError::UnknownKind(name.to_owned())),
+    }
+}
+
+fn kind_name(kind: MessageKind) -> &'static str {
+    match kind {
+        MessageKind::Line => "line",
+        MessageKind::Direct => "direct",
+        MessageKind::Retraction => "retraction",
+    }
 }
@@ -1,34 +1,137 @@
-use std::sync::Arc;
+use std::{collections::HashMap, io::Write, sync::Arc};
 
-use axum::http::StatusCode;
-use chrono::Utc;
+use axum::http::{HeaderMap, StatusCode};
+use flate2::write::GzEncoder;
 use futures::future;
 use relay_core::{
+    cbor,
+    crypto::PublicKey,
     mailroom::{GetNextLine, Mailroom, MailroomError, TTLConfig},
-    payload::UntrustedPayload,
+    payload::{TrustedPayload, UntrustedPayload, UntrustedPayloadError},
+    version::{self, PROTOCOL_VERSION, SUPPORTED_PROTOCOL_VERSIONS},
 };
-use reqwest::{Client, header::CONTENT_TYPE};
-use tokio::sync::Mutex;
+use reqwest::{
+    Client, Response,
+    header::{ACCEPT, CONTENT_ENCODING, CONTENT_TYPE},
+};
+use tokio::sync::{Mutex, RwLock};
 
 use crate::{
-    config::DaemonConfig,
+    config::{Compression, DaemonConfig},
     event::{Event, EventSender},
 };
 
 use super::archive::{DBArchive, DBError};
 
+pub const PROTOCOL_VERSIONS_HEADER: &str = "relay-protocol-versions";
+const JSON_CONTENT_TYPE: &str = "application/json";
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+const ACCEPTED_CONTENT_TYPES: &str = "application/cbor, application/json;q=0.9";
+const GZIP_CONTENT_ENCODING: &str = "gzip";
+
+pub type ListenerFormats = Arc<Mutex<HashMap<PublicKey, WireFormat>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Cbor,
+}
+
+impl WireFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            WireFormat::Json => JSON_CONTENT_TYPE,
+            WireFormat::Cbor => CBOR_CONTENT_TYPE,
+        }
+    }
+
+    // bodies without a content type are json, like before cbor was supported
+    pub fn from_content_type(headers: &HeaderMap) -> Option<Self> {
+        let Some(content_type) = headers.get(CONTENT_TYPE) else {
+            return Some(WireFormat::Json);
+        };
+
+        match media_type(content_type.to_str().ok()?).as_str() {
+            JSON_CONTENT_TYPE => Some(WireFormat::Json),
+            CBOR_CONTENT_TYPE => Some(WireFormat::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn from_accept(headers: &HeaderMap) -> Self {
+        let Some(accept) = headers.get(ACCEPT).and_then(|accept| accept.to_str().ok()) else {
+            return WireFormat::Json;
+        };
+
+        let quality = |format: WireFormat| quality(accept, format.content_type());
+
+        match quality(WireFormat::Cbor) > quality(WireFormat::Json) {
+            true => WireFormat::Cbor,
+            false => WireFormat::Json,
+        }
+    }
+
+    pub fn encode(&self, payload: String) -> Vec<u8> {
+        match self {
+            WireFormat::Json => payload.into_bytes(),
+            WireFormat::Cbor => {
+                cbor::from_json(&payload).expect("should be able to encode any payload as cbor")
+            }
+        }
+    }
+
+    pub fn decode(&self, body: &[u8]) -> Option<String> {
+        match self {
+            WireFormat::Json => String::from_utf8(body.to_vec()).ok(),
+            WireFormat::Cbor => cbor::to_json(body).ok(),
+        }
+    }
+}
+
+impl Compression {
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some(GZIP_CONTENT_ENCODING),
+        }
+    }
+
+    pub fn compress(&self, body: Vec<u8>) -> Vec<u8> {
+        match self {
+            Compression::None => body,
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(&body)
+                    .expect("should be able to compress into memory");
+                encoder
+                    .finish()
+                    .expect("should be able to compress into memory")
+            }
+        }
+    }
+}
+
 pub async fn send_to_listeners<L>(
     mailroom: Arc<Mutex<Mailroom<L, DBArchive, DBError>>>,
-    config: &DaemonConfig,
+    shared_config: Arc<RwLock<DaemonConfig>>,
+    listener_formats: ListenerFormats,
     event_sender: EventSender,
 ) where
     L: GetNextLine + Send + 'static,
 {
     event_sender.send(Event::SenderBeginningRun).ok();
 
-    let now = Utc::now();
     let client = Client::new();
-    let ttl_config = create_ttl_config(config);
+    let config = shared_config.read().await.to_owned();
+    let ttl_config = create_ttl_config(&config);
+    let (public_key, accepted_periods) = {
+        let mailroom = mailroom.lock().await;
+        (
+            mailroom.public_key(),
+            mailroom.accepted_periods(mailroom.now()),
+        )
+    };
 
     let handles: Vec<_> = config
         .trusted_relays
@@ -36,15 +139,18 @@ pub async fn send_to_listeners<L>(
         .filter_map(|relay| relay.endpoint.as_ref().map(|endpoint| (relay, endpoint)))
         .map(|(relay, endpoint)| {
             let client = client.clone();
+            let accepted_periods = accepted_periods.clone();
             let mailroom = Arc::clone(&mailroom);
             let config = config.clone();
+            let shared_config = Arc::clone(&shared_config);
+            let listener_formats = Arc::clone(&listener_formats);
             let event_sender = event_sender.clone();
 
             async move {
                 let outgoing_envelopes = match mailroom
                     .lock()
                     .await
-                    .get_outgoing_at_time(&relay.key, ttl_config, now)
+                    .get_outgoing(&relay.key, ttl_config)
                     .await
                 {
                     Ok(outgoing_envelopes) => outgoing_envelopes,
@@ -56,13 +162,62 @@ pub async fn send_to_listeners<L>(
                     }
                 };
 
-                match client
-                    .post(endpoint.clone())
-                    .header(CONTENT_TYPE, "application/json")
-                    .body(outgoing_envelopes.create_payload())
-                    .send()
+                if outgoing_envelopes.suppressed_envelopes > 0 {
+                    event_sender
+                        .send(Event::SenderSuppressedEnvelopes(
+                            relay.clone(),
+                            outgoing_envelopes.suppressed_envelopes,
+                        ))
+                        .ok();
+                }
+
+                let send = async |version, format: WireFormat| {
+                    let mut request = client
+                        .post(endpoint.clone())
+                        .header(CONTENT_TYPE, format.content_type())
+                        .header(ACCEPT, ACCEPTED_CONTENT_TYPES)
+                        .header(PROTOCOL_VERSIONS_HEADER, versions_header());
+
+                    if let Some(content_encoding) = relay.compression.content_encoding() {
+                        request = request.header(CONTENT_ENCODING, content_encoding);
+                    }
+
+                    request
+                        .body(relay.compression.compress(
+                            format.encode(outgoing_envelopes.create_payload_with_version(version)),
+                        ))
+                        .send()
+                        .await
+                };
+
+                // cbor is only sent to listeners that have answered in cbor before
+                let mut format = listener_formats
+                    .lock()
                     .await
+                    .get(&relay.key)
+                    .copied()
+                    .unwrap_or(WireFormat::Json);
+                let mut sent = send(PROTOCOL_VERSION, format).await;
+
+                if format == WireFormat::Cbor
+                    && let Ok(response) = &sent
+                    && response.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE
+                {
+                    format = WireFormat::Json;
+                    listener_formats.lock().await.insert(relay.key, format);
+                    sent = send(PROTOCOL_VERSION, format).await;
+                }
+
+                if let Ok(response) = &sent
+                    && let Some(version) = fallback_version(response, PROTOCOL_VERSION)
                 {
+                    event_sender
+                        .send(Event::SenderFellBackToVersion(relay.clone(), version))
+                        .ok();
+                    sent = send(version, format).await;
+                }
+
+                match sent {
                     Ok(response) => {
                         event_sender
                             .send(Event::SenderSentToListener(
@@ -83,28 +238,89 @@ pub async fn send_to_listeners<L>(
                                 ));
                             }
 
-                            let response_text = response
-                                .text()
+                            let response_format = WireFormat::from_content_type(response.headers())
+                                .ok_or_else(|| Event::SenderReceivedBadResponse(relay.clone()))?;
+                            let response_body = response
+                                .bytes()
                                 .await
                                 .map_err(|_| Event::SenderReceivedBadResponse(relay.clone()))?;
+                            let response_text = response_format
+                                .decode(&response_body)
+                                .ok_or_else(|| Event::SenderReceivedBadResponse(relay.clone()))?;
+
+                            listener_formats
+                                .lock()
+                                .await
+                                .insert(relay.key, response_format);
 
                             let untrusted_payload = UntrustedPayload::from_json(&response_text)
                                 .map_err(|_| Event::SenderReceivedBadResponse(relay.clone()))?;
 
                             let trusted_payload = untrusted_payload
-                                .try_trust(config.trusted_public_keys())
+                                .try_trust(
+                                    config.trusted_public_keys(),
+                                    &public_key,
+                                    accepted_periods.clone(),
+                                )
                                 .map_err(|_| Event::SenderReceivedBadResponse(relay.clone()))?;
 
+                            apply_key_succession(&trusted_payload, &shared_config, &event_sender)
+                                .await;
+
+                            if !trusted_payload.rejected_envelopes().is_empty() {
+                                event_sender
+                                    .send(Event::SenderRejectedEnvelopes(
+                                        relay.clone(),
+                                        trusted_payload.rejected_envelopes().clone(),
+                                    ))
+                                    .ok();
+                            }
+
                             match mailroom
                                 .lock()
                                 .await
-                                .receive_payload_at_time(&trusted_payload, now)
+                                .receive_payload(&trusted_payload)
                                 .await
                             {
-                                Ok(()) => Ok(Event::SenderReceivedFromListener(
-                                    relay.clone(),
-                                    trusted_payload.envelopes().clone(),
-                                )),
+                                Ok(received_envelopes) => {
+                                    if !received_envelopes.expired_envelopes.is_empty() {
+                                        event_sender
+                                            .send(Event::SenderDroppedExpiredEnvelopes(
+                                                relay.clone(),
+                                                received_envelopes.expired_envelopes,
+                                            ))
+                                            .ok();
+                                    }
+
+                                    if !received_envelopes.dropped_envelopes.is_empty() {
+                                        event_sender
+                                            .send(Event::SenderDroppedFilteredEnvelopes(
+                                                relay.clone(),
+                                                received_envelopes.dropped_envelopes,
+                                            ))
+                                            .ok();
+                                    }
+
+                                    if !received_envelopes.quarantined_envelopes.is_empty() {
+                                        event_sender
+                                            .send(Event::SenderQuarantinedEnvelopes(
+                                                relay.clone(),
+                                                received_envelopes.quarantined_envelopes,
+                                            ))
+                                            .ok();
+                                    }
+
+                                    for direct_line in received_envelopes.direct_lines {
+                                        event_sender
+                                            .send(Event::ReceivedDirectLine(direct_line))
+                                            .ok();
+                                    }
+
+                                    Ok(Event::SenderReceivedFromListener(
+                                        relay.clone(),
+                                        received_envelopes.envelopes,
+                                    ))
+                                }
                                 Err(MailroomError::AlreadyReceivedFromKey) => {
                                     Ok(Event::SenderAlreadyReceivedFromListener(relay.clone()))
                                 }
@@ -129,74 +345,178 @@ pub async fn send_to_listeners<L>(
 
     future::join_all(handles).await;
 
+    let mailroom = mailroom.lock().await;
+    if let Err(error) = mailroom.archive().save_snapshot(&mailroom.snapshot()).await {
+        event_sender
+            .send(Event::SenderDBError(error.to_string()))
+            .ok();
+    }
+
+    event_sender
+        .send(Event::SenderUpdatedStats(mailroom.stats().clone()))
+        .ok();
     event_sender.send(Event::SenderFinishedRun).ok();
 }
 
 pub async fn respond_to_sender<L>(
     payload: &str,
+    sender_versions: Option<Vec<u16>>,
     mailroom: Arc<Mutex<Mailroom<L, DBArchive, DBError>>>,
-    config: &DaemonConfig,
+    shared_config: Arc<RwLock<DaemonConfig>>,
     event_sender: EventSender,
 ) -> Result<String, (StatusCode, String)>
 where
     L: GetNextLine,
 {
-    let now = Utc::now();
+    let config = &shared_config.read().await.to_owned();
+    let (public_key, accepted_periods) = {
+        let mailroom = mailroom.lock().await;
+        (
+            mailroom.public_key(),
+            mailroom.accepted_periods(mailroom.now()),
+        )
+    };
 
     let trusted_payload = match UntrustedPayload::from_json(payload) {
-        Ok(untrusted_payload) => match untrusted_payload.try_trust(config.trusted_public_keys()) {
-            Ok(trusted_payload) => trusted_payload,
-            Err(_) => {
-                event_sender
-                    .send(Event::ListenerReceivedFromUntrustedSender)
-                    .ok();
-                return Err((
-                    StatusCode::FORBIDDEN,
-                    "payload certificate key not trusted".to_owned(),
-                ));
+        Ok(untrusted_payload) => {
+            match untrusted_payload.try_trust(
+                config.trusted_public_keys(),
+                &public_key,
+                accepted_periods,
+            ) {
+                Ok(trusted_payload) => trusted_payload,
+                Err(
+                    error @ (UntrustedPayloadError::WrongRecipient
+                    | UntrustedPayloadError::StalePeriod),
+                ) => {
+                    event_sender
+                        .send(Event::ListenerReceivedMisaddressedPayload(
+                            error.to_string(),
+                        ))
+                        .ok();
+                    return Err((StatusCode::FORBIDDEN, error.to_string()));
+                }
+                Err(_) => {
+                    event_sender
+                        .send(Event::ListenerReceivedFromUntrustedSender)
+                        .ok();
+                    return Err((
+                        StatusCode::FORBIDDEN,
+                        "payload certificate key not trusted".to_owned(),
+                    ));
+                }
             }
-        },
+        }
+        Err(UntrustedPayloadError::UnsupportedVersion(version)) => {
+            event_sender
+                .send(Event::ListenerReceivedUnsupportedVersion(version))
+                .ok();
+            return Err((
+                StatusCode::BAD_REQUEST,
+                UntrustedPayloadError::UnsupportedVersion(version).to_string(),
+            ));
+        }
         Err(_) => {
             event_sender.send(Event::ListenerReceivedBadPayload).ok();
             return Err((StatusCode::BAD_REQUEST, "payload malformed".to_owned()));
         }
     };
 
-    let relay_data = config
+    apply_key_succession(&trusted_payload, &shared_config, &event_sender).await;
+
+    let relay_data = shared_config
+        .read()
+        .await
         .trusted_relays
         .iter()
         .find(|relay| relay.key.to_string() == trusted_payload.certificate().key)
         .cloned();
 
+    if !trusted_payload.rejected_envelopes().is_empty() {
+        event_sender
+            .send(Event::ListenerRejectedEnvelopes(
+                relay_data.clone(),
+                trusted_payload.rejected_envelopes().clone(),
+            ))
+            .ok();
+    }
+
     let mut mailroom = mailroom.lock().await;
 
-    match mailroom
-        .receive_payload_at_time(&trusted_payload, now)
-        .await
-    {
-        Ok(()) => {
+    match mailroom.receive_payload(&trusted_payload).await {
+        Ok(received_envelopes) => {
             event_sender
                 .send(Event::ListenerReceivedFromSender(
                     relay_data.clone(),
-                    trusted_payload.envelopes().clone(),
+                    received_envelopes.envelopes,
                 ))
                 .ok();
 
-            let outgoing_envelopes = mailroom.get_outgoing_at_time(
-                trusted_payload.public_key(),
-                create_ttl_config(config),
-                now,
-            );
+            if !received_envelopes.expired_envelopes.is_empty() {
+                event_sender
+                    .send(Event::ListenerDroppedExpiredEnvelopes(
+                        relay_data.clone(),
+                        received_envelopes.expired_envelopes,
+                    ))
+                    .ok();
+            }
+
+            if !received_envelopes.dropped_envelopes.is_empty() {
+                event_sender
+                    .send(Event::ListenerDroppedFilteredEnvelopes(
+                        relay_data.clone(),
+                        received_envelopes.dropped_envelopes,
+                    ))
+                    .ok();
+            }
+
+            if !received_envelopes.quarantined_envelopes.is_empty() {
+                event_sender
+                    .send(Event::ListenerQuarantinedEnvelopes(
+                        relay_data.clone(),
+                        received_envelopes.quarantined_envelopes,
+                    ))
+                    .ok();
+            }
+
+            for direct_line in received_envelopes.direct_lines {
+                event_sender
+                    .send(Event::ReceivedDirectLine(direct_line))
+                    .ok();
+            }
+
+            let outgoing_envelopes =
+                mailroom.get_outgoing(trusted_payload.public_key(), create_ttl_config(config));
 
             match outgoing_envelopes.await {
                 Ok(outgoing_envelopes) => {
+                    if let Err(error) = mailroom.archive().save_snapshot(&mailroom.snapshot()).await
+                    {
+                        event_sender
+                            .send(Event::ListenerDBError(error.to_string()))
+                            .ok();
+                    }
+
+                    if outgoing_envelopes.suppressed_envelopes > 0 {
+                        event_sender
+                            .send(Event::ListenerSuppressedEnvelopes(
+                                relay_data.clone(),
+                                outgoing_envelopes.suppressed_envelopes,
+                            ))
+                            .ok();
+                    }
+
                     event_sender
                         .send(Event::ListenerSentToSender(
                             relay_data,
                             outgoing_envelopes.envelopes.clone(),
                         ))
                         .ok();
-                    Ok(outgoing_envelopes.create_payload())
+                    Ok(outgoing_envelopes.create_payload_with_version(
+                        sender_versions
+                            .and_then(version::highest_common_version)
+                            .unwrap_or(trusted_payload.version()),
+                    ))
                 }
                 Err(error) => {
                     event_sender
@@ -230,6 +550,89 @@ where
     }
 }
 
+async fn apply_key_succession(
+    trusted_payload: &TrustedPayload,
+    shared_config: &RwLock<DaemonConfig>,
+    event_sender: &EventSender,
+) {
+    if let Some(old_key) = trusted_payload.succeeded_from()
+        && let Some(relay_data) = shared_config
+            .write()
+            .await
+            .succeed_relay_key(old_key, *trusted_payload.public_key())
+    {
+        event_sender
+            .send(Event::RelayKeySucceeded(relay_data, *old_key))
+            .ok();
+    }
+}
+
+pub fn read_request_body(headers: &HeaderMap, body: &[u8]) -> Result<String, (StatusCode, String)> {
+    WireFormat::from_content_type(headers)
+        .ok_or((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "payload must be json or cbor".to_owned(),
+        ))?
+        .decode(body)
+        .ok_or((StatusCode::BAD_REQUEST, "payload malformed".to_owned()))
+}
+
+fn media_type(media_range: &str) -> String {
+    media_range
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+// quality of a value in an accept style header, 0 if the header doesn't list it
+fn quality(header: &str, value: &str) -> f32 {
+    header
+        .split(',')
+        .filter(|entry| media_type(entry) == value)
+        .map(|entry| {
+            entry
+                .split(';')
+                .skip(1)
+                .find_map(|parameter| parameter.trim().strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0)
+        })
+        .next()
+        .unwrap_or(0.0)
+}
+
+pub fn versions_header() -> String {
+    SUPPORTED_PROTOCOL_VERSIONS
+        .iter()
+        .map(u16::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub fn read_versions_header(headers: &HeaderMap) -> Option<Vec<u16>> {
+    headers
+        .get(PROTOCOL_VERSIONS_HEADER)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(|version| version.trim().parse().ok())
+        .collect()
+}
+
+// a listener that turned down our payload but shares an older version with us gets it again in that
+// version
+fn fallback_version(response: &Response, sent_version: u16) -> Option<u16> {
+    if response.status().is_success() {
+        return None;
+    }
+
+    read_versions_header(response.headers())
+        .and_then(version::highest_common_version)
+        .filter(|version| *version != sent_version)
+}
+
 fn create_ttl_config(config: &DaemonConfig) -> TTLConfig {
     TTLConfig::new(config.custom_initial_ttl, config.custom_max_forwarding_ttl)
 }
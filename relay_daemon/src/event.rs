@@ -1,4 +1,9 @@
-use relay_core::message::{Envelope, Message};
+use relay_core::{
+    crypto::PublicKey,
+    mailroom::MailroomStats,
+    message::{DirectLine, Envelope, Message},
+    payload::RejectedEnvelope,
+};
 use tokio::sync::mpsc::UnboundedSender;
 
 use crate::config::RelayData;
@@ -7,21 +12,39 @@ pub enum Event {
     ListenerStartedListening(u16),
     ListenerReceivedFromSender(Option<RelayData>, Vec<Envelope>),
     ListenerSentToSender(Option<RelayData>, Vec<Envelope>),
+    ListenerSuppressedEnvelopes(Option<RelayData>, usize),
+    ListenerDroppedExpiredEnvelopes(Option<RelayData>, Vec<Envelope>),
+    ListenerDroppedFilteredEnvelopes(Option<RelayData>, Vec<Envelope>),
+    ListenerQuarantinedEnvelopes(Option<RelayData>, Vec<Envelope>),
+    ListenerRejectedEnvelopes(Option<RelayData>, Vec<RejectedEnvelope>),
     ListenerReceivedBadPayload,
     ListenerReceivedFromUntrustedSender,
+    ListenerReceivedMisaddressedPayload(String),
+    ListenerReceivedUnsupportedVersion(u16),
     ListenerDBError(String),
     ListenerAlreadyReceivedFromSender(Option<RelayData>),
     SenderStartedSchedule,
     SenderBeginningRun,
     SenderDBError(String),
     SenderSentToListener(RelayData, Vec<Envelope>),
+    SenderSuppressedEnvelopes(RelayData, usize),
     SenderReceivedFromListener(RelayData, Vec<Envelope>),
+    SenderDroppedExpiredEnvelopes(RelayData, Vec<Envelope>),
+    SenderDroppedFilteredEnvelopes(RelayData, Vec<Envelope>),
+    SenderQuarantinedEnvelopes(RelayData, Vec<Envelope>),
+    SenderRejectedEnvelopes(RelayData, Vec<RejectedEnvelope>),
     SenderFailedSending(RelayData, String),
     SenderReceivedHttpError(RelayData, String),
     SenderReceivedBadResponse(RelayData),
     SenderAlreadyReceivedFromListener(RelayData),
+    SenderFellBackToVersion(RelayData, u16),
+    SenderUpdatedStats(MailroomStats),
     SenderFinishedRun,
     AddedMessageToArchive(Message),
+    RestoredMailroomSnapshot,
+    RetractedLineInArchive(Message, String),
+    ReceivedDirectLine(DirectLine),
+    RelayKeySucceeded(RelayData, PublicKey),
 }
 
 pub type EventSender = UnboundedSender<Event>;
@@ -0,0 +1,231 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use relay_core::{
+    archive::{ArchivedEnvelope, MessageQuery},
+    clock::VirtualClock,
+    mailroom::Archive,
+    message::{Certificate, Envelope, ForwardingHop, Message, MessageContents, MessageKind},
+    version::INITIAL_TTL_MESSAGE_VERSION,
+};
+use relay_daemon::daemon::archive::{DBArchive, DBError};
+use sqlx::SqlitePool;
+use tokio::sync::mpsc;
+
+async fn db_archive(db_url: &str) -> DBArchive {
+    let (event_sender, _) = mpsc::unbounded_channel();
+
+    DBArchive::new(
+        db_url,
+        event_sender,
+        Arc::new(VirtualClock::new(Utc::now())),
+    )
+    .await
+    .unwrap()
+}
+
+// the archive doesn't check signatures, so any unique string will do
+fn message(key: &str, kind: MessageKind, created_at: i64) -> Message {
+    Message {
+        certificate: Certificate {
+            key: key.to_owned(),
+            signature: format!("signature-{key}-{created_at}"),
+        },
+        contents: MessageContents {
+            version: INITIAL_TTL_MESSAGE_VERSION,
+            kind,
+            uuid: format!("uuid-{key}-{created_at}"),
+            author: "test".to_owned(),
+            line: format!("line at {created_at}"),
+            created_at,
+            retracts: None,
+            ttl: Some(8),
+        },
+    }
+}
+
+fn envelope(message: &Message, forwarded_by: &[&str]) -> Envelope {
+    Envelope {
+        forwarded: forwarded_by
+            .iter()
+            .enumerate()
+            .map(|(i, key)| ForwardingHop {
+                key: (*key).to_owned(),
+                ttl: 7 - i as u8,
+                signature: format!("hop-{key}"),
+            })
+            .collect(),
+        ttl: 7 - forwarded_by.len() as u8,
+        message: message.clone(),
+    }
+}
+
+fn created_at(messages: Vec<Message>) -> Vec<i64> {
+    messages
+        .into_iter()
+        .map(|message| message.contents.created_at)
+        .collect()
+}
+
+#[tokio::test]
+async fn db_archive_can_be_queried() {
+    let mut archive = db_archive(":memory:").await;
+
+    for (kind, created_at) in [
+        (MessageKind::Line, 30),
+        (MessageKind::Direct, 10),
+        (MessageKind::Line, 20),
+        (MessageKind::Retraction, 40),
+    ] {
+        let message = message("a", kind, created_at);
+        archive
+            .add_envelope_to_archive("a", &envelope(&message, &[]))
+            .await
+            .unwrap();
+    }
+    let other = message("b", MessageKind::Line, 25);
+    archive
+        .add_envelope_to_archive("b", &envelope(&other, &[]))
+        .await
+        .unwrap();
+
+    let everything = archive
+        .find_messages(&MessageQuery::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        everything
+            .iter()
+            .map(|message| message.contents.kind)
+            .collect::<Vec<MessageKind>>(),
+        [
+            MessageKind::Direct,
+            MessageKind::Line,
+            MessageKind::Line,
+            MessageKind::Line,
+            MessageKind::Retraction
+        ]
+    );
+    assert_eq!(everything[2], other);
+
+    let by_key = MessageQuery {
+        key: Some("a".to_owned()),
+        ..MessageQuery::default()
+    };
+    assert_eq!(
+        created_at(archive.find_messages(&by_key).await.unwrap()),
+        vec![10, 20, 30, 40]
+    );
+
+    let in_range = MessageQuery {
+        created_from: Some(20),
+        created_until: Some(30),
+        ..MessageQuery::default()
+    };
+    assert_eq!(
+        created_at(archive.find_messages(&in_range).await.unwrap()),
+        vec![20, 25]
+    );
+
+    let first_page = MessageQuery {
+        limit: Some(3),
+        ..by_key
+    };
+    let second_page = first_page.next_page().unwrap();
+    assert_eq!(
+        created_at(archive.find_messages(&first_page).await.unwrap()),
+        vec![10, 20, 30]
+    );
+    assert_eq!(
+        created_at(archive.find_messages(&second_page).await.unwrap()),
+        vec![40]
+    );
+    let skipped = MessageQuery {
+        offset: 2,
+        ..MessageQuery::default()
+    };
+    assert_eq!(
+        created_at(archive.find_messages(&skipped).await.unwrap()),
+        vec![25, 30, 40]
+    );
+
+    let by_uuid = MessageQuery {
+        uuid: Some(other.contents.uuid.clone()),
+        ..MessageQuery::default()
+    };
+    assert_eq!(archive.find_messages(&by_uuid).await.unwrap(), vec![other]);
+}
+
+#[tokio::test]
+async fn db_archive_keeps_every_path_of_a_message() {
+    let mut archive = db_archive(":memory:").await;
+    let message = message("a", MessageKind::Line, 10);
+    let direct = envelope(&message, &[]);
+    let forwarded = envelope(&message, &["b", "c"]);
+    let other = envelope(&self::message("b", MessageKind::Line, 20), &["a"]);
+
+    archive.add_envelope_to_archive("a", &direct).await.unwrap();
+    archive
+        .add_envelope_to_archive("c", &forwarded)
+        .await
+        .unwrap();
+    archive.add_envelope_to_archive("a", &other).await.unwrap();
+
+    assert_eq!(
+        archive
+            .find_envelopes_of_message(&message.certificate.signature)
+            .await
+            .unwrap(),
+        vec![
+            ArchivedEnvelope {
+                from: "a".to_owned(),
+                envelope: direct,
+            },
+            ArchivedEnvelope {
+                from: "c".to_owned(),
+                envelope: forwarded,
+            },
+        ]
+    );
+    assert!(
+        archive
+            .find_envelopes_of_message("unknown signature")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn db_archive_rejects_unknown_kinds() {
+    let path = std::env::temp_dir().join(format!("relay-db-archive-{}.db", std::process::id()));
+    let db_url = path.to_string_lossy();
+    let mut archive = db_archive(&db_url).await;
+    let message = message("a", MessageKind::Line, 10);
+    archive
+        .add_envelope_to_archive("a", &envelope(&message, &[]))
+        .await
+        .unwrap();
+
+    // as if a newer version of the relay had written it
+    let pool = SqlitePool::connect(&format!("sqlite:{db_url}"))
+        .await
+        .unwrap();
+    sqlx::query("UPDATE messages SET kind = 'shout'")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        archive.find_messages(&MessageQuery::default()).await,
+        Err(DBError::UnknownKind(kind)) if kind == "shout"
+    ));
+    assert!(matches!(
+        archive
+            .find_envelopes_of_message(&message.certificate.signature)
+            .await,
+        Err(DBError::UnknownKind(_))
+    ));
+
+    std::fs::remove_file(path).unwrap();
+}
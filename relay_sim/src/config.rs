@@ -0,0 +1,140 @@
+use std::{fs, io, path::Path};
+
+use relay_core::mailroom::{
+    DEFAULT_INITIAL_TTL, DEFAULT_MAX_FORWARDING_TTL, ForwardingLimits, TTLConfig,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SimConfigError {
+    #[error("cannot read config file: {0}")]
+    Read(#[source] io::Error),
+    #[error("cannot parse config file: {0}")]
+    Parse(#[source] toml::de::Error),
+    #[error("a network needs at least two relays")]
+    TooFewRelays,
+    #[error("{0} should be between 0 and 1")]
+    NotProbability(&'static str),
+    #[error("neighbours should be even and fewer than the number of relays")]
+    InvalidNeighbours,
+    #[error("periods should leave the last lines posted initial_ttl periods to spread")]
+    TooFewPeriods,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Topology {
+    // every relay is linked to the one before and after it
+    Ring,
+    // every possible link exists with the same probability
+    Random {
+        link_probability: f64,
+    },
+    // a ring where every relay is linked to its nearest neighbours, with some links moved to a
+    // random relay elsewhere
+    SmallWorld {
+        neighbours: usize,
+        rewiring_probability: f64,
+    },
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SimConfig {
+    pub seed: u64,
+    pub relays: usize,
+    pub periods: u32,
+    // every relay posts a new line in each of the first posting_periods periods
+    pub posting_periods: u32,
+    pub initial_ttl: u8,
+    pub max_forwarding_ttl: u8,
+    pub max_envelopes_per_payload: Option<usize>,
+    pub max_envelopes_per_origin: Option<usize>,
+    pub topology: Topology,
+}
+
+impl SimConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SimConfigError> {
+        let contents = fs::read_to_string(path).map_err(SimConfigError::Read)?;
+        let config: SimConfig = toml::from_str(&contents).map_err(SimConfigError::Parse)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), SimConfigError> {
+        if self.relays < 2 {
+            return Err(SimConfigError::TooFewRelays);
+        }
+        // a line only gets as far as it can by initial_ttl periods after it was posted, so lines
+        // posted any later would make the report look worse than the network is
+        let last_posting_period = self.posting_periods.saturating_sub(1);
+        if last_posting_period.saturating_add(u32::from(self.initial_ttl)) > self.periods {
+            return Err(SimConfigError::TooFewPeriods);
+        }
+
+        match self.topology {
+            Topology::Ring => Ok(()),
+            Topology::Random { link_probability } => {
+                check_probability("link_probability", link_probability)
+            }
+            Topology::SmallWorld {
+                neighbours,
+                rewiring_probability,
+            } => {
+                if neighbours == 0 || neighbours % 2 != 0 || neighbours >= self.relays {
+                    return Err(SimConfigError::InvalidNeighbours);
+                }
+                check_probability("rewiring_probability", rewiring_probability)
+            }
+        }
+    }
+
+    // the same ttl is used to start and to cap forwarding, so a run shows what that one value does
+    pub fn with_ttl(&self, ttl: u8) -> Self {
+        Self {
+            initial_ttl: ttl,
+            max_forwarding_ttl: ttl,
+            ..self.clone()
+        }
+    }
+
+    pub fn ttl_config(&self) -> TTLConfig {
+        TTLConfig::new(Some(self.initial_ttl), Some(self.max_forwarding_ttl))
+    }
+
+    pub fn forwarding_limits(&self) -> ForwardingLimits {
+        ForwardingLimits {
+            max_envelopes_per_payload: self.max_envelopes_per_payload,
+            max_envelopes_per_origin: self.max_envelopes_per_origin,
+        }
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            relays: 50,
+            periods: 24,
+            posting_periods: 1,
+            initial_ttl: DEFAULT_INITIAL_TTL,
+            max_forwarding_ttl: DEFAULT_MAX_FORWARDING_TTL,
+            max_envelopes_per_payload: None,
+            max_envelopes_per_origin: None,
+            topology: Topology::SmallWorld {
+                neighbours: 4,
+                rewiring_probability: 0.1,
+            },
+        }
+    }
+}
+
+fn check_probability(name: &'static str, probability: f64) -> Result<(), SimConfigError> {
+    if (0.0..=1.0).contains(&probability) {
+        Ok(())
+    } else {
+        Err(SimConfigError::NotProbability(name))
+    }
+}
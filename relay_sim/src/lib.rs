@@ -0,0 +1,4 @@
+pub mod config;
+pub mod report;
+pub mod simulation;
+pub mod topology;
@@ -0,0 +1,109 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+use relay_sim::{
+    config::SimConfig,
+    report::{MessageReport, SimulationReport},
+    simulation::Simulation,
+};
+
+#[derive(Parser)]
+#[command(version)]
+struct RelaySimCli {
+    /// Simulation config, defaults are used without one
+    config: Option<PathBuf>,
+    /// Run once for each TTL, used as both initial and max forwarding TTL
+    #[arg(short, long)]
+    ttl: Vec<u8>,
+    /// Seed for building the topology
+    #[arg(short, long)]
+    seed: Option<u64>,
+    /// Also show every message
+    #[arg(short, long)]
+    per_message: bool,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let cli = RelaySimCli::parse();
+
+    let mut config = match &cli.config {
+        Some(path) => SimConfig::load(path)?,
+        None => SimConfig::default(),
+    };
+    if let Some(seed) = cli.seed {
+        config.seed = seed;
+    }
+
+    let configs = if cli.ttl.is_empty() {
+        vec![config]
+    } else {
+        cli.ttl.iter().map(|ttl| config.with_ttl(*ttl)).collect()
+    };
+
+    for config in configs {
+        let report = Simulation::new(config)?.run().await;
+        print_report(&report);
+
+        if cli.per_message {
+            print_messages(&report.messages);
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &SimulationReport) {
+    println!(
+        "ttl {}/{}: {} relays, {} links{}, {} periods",
+        report.initial_ttl,
+        report.max_forwarding_ttl,
+        report.relays,
+        report.links,
+        if report.connected {
+            ""
+        } else {
+            " (not connected)"
+        },
+        report.periods
+    );
+    println!("  delivery    {:.1}%", report.delivery_ratio() * 100.0);
+    println!(
+        "  hops        mean {:.2}, max {}",
+        report.mean_hops(),
+        report.max_hops()
+    );
+    println!(
+        "  latency     mean {:.2}, max {} periods",
+        report.mean_latency(),
+        report.max_latency()
+    );
+    println!(
+        "  duplicates  {:.1}% of {} envelopes",
+        report.duplicate_rate() * 100.0,
+        report.envelopes()
+    );
+}
+
+fn print_messages(messages: &[MessageReport]) {
+    println!(
+        "  {:<16} {:>7} {:>9} {:>11} {:>14} {:>11}",
+        "line", "period", "delivery", "hops", "latency", "duplicates"
+    );
+
+    for message in messages {
+        println!(
+            "  {:<16} {:>7} {:>8.1}% {:>6.2}/{:<4} {:>9.2}/{:<4} {:>10.1}%",
+            message.line,
+            message.created_period,
+            message.delivery_ratio() * 100.0,
+            message.mean_hops(),
+            message.max_hops(),
+            message.mean_latency(),
+            message.max_latency(),
+            message.duplicate_rate() * 100.0
+        );
+    }
+}
@@ -0,0 +1,140 @@
+// latency is counted in periods from the one a message was created in, so a relay that got it
+// straight from its author in the same period has a latency of 0
+#[derive(Clone, Debug, PartialEq)]
+pub struct MessageReport {
+    pub origin: usize,
+    pub line: String,
+    pub created_period: u32,
+    // one entry for every relay the message reached, taken from the first envelope to get there
+    pub hops: Vec<u32>,
+    pub latencies: Vec<u32>,
+    // every envelope of the message received by any relay other than its origin
+    pub envelopes: u64,
+    pub(crate) other_relays: usize,
+}
+
+impl MessageReport {
+    pub fn reached(&self) -> usize {
+        self.hops.len()
+    }
+
+    pub fn delivery_ratio(&self) -> f64 {
+        ratio(self.reached() as u64, self.other_relays as u64)
+    }
+
+    pub fn mean_hops(&self) -> f64 {
+        mean(&self.hops)
+    }
+
+    pub fn max_hops(&self) -> u32 {
+        self.hops.iter().copied().max().unwrap_or(0)
+    }
+
+    pub fn mean_latency(&self) -> f64 {
+        mean(&self.latencies)
+    }
+
+    pub fn max_latency(&self) -> u32 {
+        self.latencies.iter().copied().max().unwrap_or(0)
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.envelopes - self.reached() as u64
+    }
+
+    pub fn duplicate_rate(&self) -> f64 {
+        ratio(self.duplicates(), self.envelopes)
+    }
+}
+
+// totals are taken over every delivery, so a message that reached more relays weighs more
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationReport {
+    pub relays: usize,
+    pub links: usize,
+    pub connected: bool,
+    pub periods: u32,
+    pub initial_ttl: u8,
+    pub max_forwarding_ttl: u8,
+    pub messages: Vec<MessageReport>,
+}
+
+impl SimulationReport {
+    pub fn delivery_ratio(&self) -> f64 {
+        let reached = self
+            .messages
+            .iter()
+            .map(MessageReport::reached)
+            .sum::<usize>();
+        let possible = self
+            .messages
+            .iter()
+            .map(|message| message.other_relays)
+            .sum::<usize>();
+
+        ratio(reached as u64, possible as u64)
+    }
+
+    pub fn mean_hops(&self) -> f64 {
+        mean(&self.all(|message| &message.hops))
+    }
+
+    pub fn max_hops(&self) -> u32 {
+        self.messages
+            .iter()
+            .map(MessageReport::max_hops)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn mean_latency(&self) -> f64 {
+        mean(&self.all(|message| &message.latencies))
+    }
+
+    pub fn max_latency(&self) -> u32 {
+        self.messages
+            .iter()
+            .map(MessageReport::max_latency)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn envelopes(&self) -> u64 {
+        self.messages.iter().map(|message| message.envelopes).sum()
+    }
+
+    pub fn duplicate_rate(&self) -> f64 {
+        let duplicates = self
+            .messages
+            .iter()
+            .map(MessageReport::duplicates)
+            .sum::<u64>();
+
+        ratio(duplicates, self.envelopes())
+    }
+
+    fn all<F>(&self, values: F) -> Vec<u32>
+    where
+        F: Fn(&MessageReport) -> &Vec<u32>,
+    {
+        self.messages
+            .iter()
+            .flat_map(|message| values(message).iter().copied())
+            .collect()
+    }
+}
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+fn mean(values: &[u32]) -> f64 {
+    ratio(
+        values.iter().map(|value| *value as u64).sum(),
+        values.len() as u64,
+    )
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    convert::Infallible,
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use relay_core::{
+    archive::MemoryArchive,
+    clock::{PeriodSchedule, VirtualClock},
+    crypto::{PublicKey, SecretKey},
+    mailroom::{GetNextLine, Mailroom, NextLine, TTLConfig},
+    payload::UntrustedPayload,
+};
+
+use crate::{
+    config::{SimConfig, SimConfigError},
+    report::{MessageReport, SimulationReport},
+    topology::Graph,
+};
+
+// 2026-01-01, any period start would do
+const SIMULATION_START: i64 = 1_767_225_600;
+
+struct SimLines {
+    name: String,
+    posts_left: u32,
+    posted: u32,
+}
+
+impl GetNextLine for SimLines {
+    fn get_next_line(&mut self) -> Option<NextLine> {
+        if self.posts_left == 0 {
+            return None;
+        }
+        self.posts_left -= 1;
+        self.posted += 1;
+
+        Some(NextLine {
+            line: format!("{} #{}", self.name, self.posted),
+            author: self.name.clone(),
+        })
+    }
+}
+
+struct SimRelay {
+    public_key: PublicKey,
+    mailroom: Mailroom<SimLines, MemoryArchive, Infallible>,
+    // how much of the archive has been looked at already
+    archived_seen: usize,
+}
+
+struct MessageTrace {
+    origin: usize,
+    line: String,
+    created_period: u32,
+    // the period and hop count of the first envelope to reach each relay
+    deliveries: HashMap<usize, (u32, u32)>,
+    envelopes: u64,
+}
+
+pub struct Simulation {
+    config: SimConfig,
+    graph: Graph,
+    clock: VirtualClock,
+    schedule: PeriodSchedule,
+    ttl_config: TTLConfig,
+    relays: Vec<SimRelay>,
+    relay_by_key: HashMap<String, usize>,
+    traces: HashMap<String, MessageTrace>,
+    period: u32,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Result<Self, SimConfigError> {
+        config.validate()?;
+
+        let graph = Graph::build(&config.topology, config.relays, config.seed);
+        let start =
+            DateTime::from_timestamp(SIMULATION_START, 0).expect("should be able to start at 2026");
+        let clock = VirtualClock::new(start);
+        let schedule = PeriodSchedule::HOURLY;
+
+        let relays: Vec<SimRelay> = (0..config.relays)
+            .map(|i| {
+                let secret_key = SecretKey::generate();
+                let public_key = secret_key.public_key();
+                let lines = SimLines {
+                    name: format!("relay {i}"),
+                    posts_left: config.posting_periods,
+                    posted: 0,
+                };
+
+                let mut mailroom = Mailroom::new_with_clock(
+                    lines,
+                    MemoryArchive::new(),
+                    secret_key,
+                    Arc::new(clock.clone()),
+                    schedule,
+                );
+                mailroom.set_initial_ttl(config.initial_ttl);
+                mailroom.set_forwarding_limits(config.forwarding_limits());
+
+                SimRelay {
+                    public_key,
+                    mailroom,
+                    archived_seen: 0,
+                }
+            })
+            .collect();
+        let relay_by_key = relays
+            .iter()
+            .enumerate()
+            .map(|(i, relay)| (relay.public_key.to_string(), i))
+            .collect();
+
+        Ok(Self {
+            ttl_config: config.ttl_config(),
+            config,
+            graph,
+            clock,
+            schedule,
+            relays,
+            relay_by_key,
+            traces: HashMap::new(),
+            period: 0,
+        })
+    }
+
+    pub fn graph(&self) -> &Graph {
+        &self.graph
+    }
+
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    pub async fn run(mut self) -> SimulationReport {
+        while self.period < self.config.periods {
+            self.run_period().await;
+        }
+
+        self.report()
+    }
+
+    // every relay sends one payload to each of its neighbours, like a sender and listener would
+    // over a period. what's received now only goes out again next period, so the order doesn't
+    // matter
+    pub async fn run_period(&mut self) {
+        let now = self.period_start(self.period);
+        self.clock.set(now);
+
+        for from in 0..self.relays.len() {
+            let sender = self.relays[from].public_key;
+
+            for to in self.graph.neighbours(from).clone() {
+                let recipient = self.relays[to].public_key;
+                let payload = self.relays[from]
+                    .mailroom
+                    .get_outgoing(&recipient, self.ttl_config)
+                    .await
+                    .expect("should be able to get outgoing envelopes")
+                    .create_payload();
+
+                let trusted_payload = UntrustedPayload::from_json(&payload)
+                    .expect("should be able to read own payload")
+                    .try_trust([sender], &recipient, self.schedule.accepted_periods(now))
+                    .expect("should be able to trust payload from a neighbour");
+                self.relays[to]
+                    .mailroom
+                    .receive_payload(&trusted_payload)
+                    .await
+                    .expect("should be able to receive one payload from each neighbour");
+            }
+        }
+
+        self.trace_period();
+        self.period += 1;
+    }
+
+    pub fn report(&self) -> SimulationReport {
+        let mut messages: Vec<MessageReport> = self
+            .traces
+            .values()
+            .map(|trace| {
+                let mut deliveries = trace.deliveries.values().collect::<Vec<_>>();
+                deliveries.sort();
+
+                MessageReport {
+                    origin: trace.origin,
+                    line: trace.line.clone(),
+                    created_period: trace.created_period,
+                    hops: deliveries.iter().map(|(_, hops)| *hops).collect(),
+                    latencies: deliveries
+                        .iter()
+                        .map(|(period, _)| period - trace.created_period)
+                        .collect(),
+                    envelopes: trace.envelopes,
+                    other_relays: self.relays.len() - 1,
+                }
+            })
+            .collect();
+        messages.sort_by_key(|message| (message.created_period, message.origin));
+
+        SimulationReport {
+            relays: self.relays.len(),
+            links: self.graph.links(),
+            connected: self.graph.is_connected(),
+            periods: self.period,
+            initial_ttl: self.config.initial_ttl,
+            max_forwarding_ttl: self.config.max_forwarding_ttl,
+            messages,
+        }
+    }
+
+    fn period_start(&self, period: u32) -> DateTime<Utc> {
+        let length = self.schedule.length().as_secs() as i64;
+
+        DateTime::from_timestamp(SIMULATION_START + period as i64 * length, 0)
+            .expect("should be able to get the start of any simulated period")
+    }
+
+    fn period_of(&self, created_at: i64) -> u32 {
+        let length = self.schedule.length().as_secs() as i64;
+
+        ((created_at - SIMULATION_START) / length) as u32
+    }
+
+    // messages are picked up as they're created, so ones from relays nobody links to still count
+    fn trace_period(&mut self) {
+        for (i, relay) in self.relays.iter().enumerate() {
+            if let Some(message) = &relay.mailroom.current_message {
+                let created_period = self.period_of(message.contents.created_at);
+
+                self.traces
+                    .entry(message.certificate.signature.clone())
+                    .or_insert_with(|| MessageTrace {
+                        origin: i,
+                        line: message.contents.line.clone(),
+                        created_period,
+                        deliveries: HashMap::new(),
+                        envelopes: 0,
+                    });
+            }
+        }
+
+        for (i, relay) in self.relays.iter_mut().enumerate() {
+            let archived = &relay.mailroom.archive().envelopes()[relay.archived_seen..];
+            relay.archived_seen += archived.len();
+
+            for archived in archived {
+                let message = &archived.envelope.message;
+                if self.relay_by_key.get(&message.certificate.key) == Some(&i) {
+                    continue;
+                }
+                let Some(trace) = self.traces.get_mut(&message.certificate.signature) else {
+                    continue;
+                };
+
+                let hops = archived.envelope.forwarded.len() as u32 + 1;
+                trace.envelopes += 1;
+                match trace.deliveries.entry(i) {
+                    Entry::Occupied(mut delivery) => {
+                        let (period, first_hops) = delivery.get_mut();
+                        if *period == self.period && hops < *first_hops {
+                            *first_hops = hops;
+                        }
+                    }
+                    Entry::Vacant(delivery) => {
+                        delivery.insert((self.period, hops));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::BTreeSet;
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::config::Topology;
+
+// links go both ways. neighbours are kept sorted so a seed always gives the same exchange order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Graph {
+    neighbours: Vec<BTreeSet<usize>>,
+}
+
+impl Graph {
+    pub fn build(topology: &Topology, relays: usize, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        match *topology {
+            Topology::Ring => Self::ring(relays),
+            Topology::Random { link_probability } => {
+                Self::random(relays, link_probability, &mut rng)
+            }
+            Topology::SmallWorld {
+                neighbours,
+                rewiring_probability,
+            } => Self::small_world(relays, neighbours, rewiring_probability, &mut rng),
+        }
+    }
+
+    pub fn ring(relays: usize) -> Self {
+        Self::lattice(relays, 2)
+    }
+
+    pub fn random<R: Rng>(relays: usize, link_probability: f64, rng: &mut R) -> Self {
+        let mut graph = Self::empty(relays);
+
+        for a in 0..relays {
+            for b in a + 1..relays {
+                if rng.gen_bool(link_probability) {
+                    graph.link(a, b);
+                }
+            }
+        }
+
+        graph
+    }
+
+    // watts-strogatz: each lattice link is moved to a random relay with the given probability,
+    // unless the relay is already linked to every other one
+    pub fn small_world<R: Rng>(
+        relays: usize,
+        neighbours: usize,
+        rewiring_probability: f64,
+        rng: &mut R,
+    ) -> Self {
+        let mut graph = Self::lattice(relays, neighbours);
+
+        for distance in 1..=neighbours / 2 {
+            for a in 0..relays {
+                let b = (a + distance) % relays;
+                // an earlier rewire from b can already have moved this link
+                if !rng.gen_bool(rewiring_probability)
+                    || !graph.neighbours[a].contains(&b)
+                    || graph.neighbours[a].len() >= relays - 1
+                {
+                    continue;
+                }
+
+                let new_b = loop {
+                    let candidate = rng.gen_range(0..relays);
+                    if candidate != a && !graph.neighbours[a].contains(&candidate) {
+                        break candidate;
+                    }
+                };
+                graph.unlink(a, b);
+                graph.link(a, new_b);
+            }
+        }
+
+        graph
+    }
+
+    pub fn relays(&self) -> usize {
+        self.neighbours.len()
+    }
+
+    pub fn neighbours(&self, relay: usize) -> &BTreeSet<usize> {
+        &self.neighbours[relay]
+    }
+
+    pub fn links(&self) -> usize {
+        self.neighbours.iter().map(BTreeSet::len).sum::<usize>() / 2
+    }
+
+    pub fn is_connected(&self) -> bool {
+        let mut reached = vec![false; self.relays()];
+        let mut queue = vec![0];
+        reached[0] = true;
+
+        while let Some(relay) = queue.pop() {
+            for &neighbour in &self.neighbours[relay] {
+                if !reached[neighbour] {
+                    reached[neighbour] = true;
+                    queue.push(neighbour);
+                }
+            }
+        }
+
+        reached.into_iter().all(|reached| reached)
+    }
+
+    fn empty(relays: usize) -> Self {
+        Self {
+            neighbours: vec![BTreeSet::new(); relays],
+        }
+    }
+
+    fn lattice(relays: usize, neighbours: usize) -> Self {
+        let mut graph = Self::empty(relays);
+
+        for a in 0..relays {
+            for distance in 1..=neighbours / 2 {
+                graph.link(a, (a + distance) % relays);
+            }
+        }
+
+        graph
+    }
+
+    fn link(&mut self, a: usize, b: usize) {
+        if a != b {
+            self.neighbours[a].insert(b);
+            self.neighbours[b].insert(a);
+        }
+    }
+
+    fn unlink(&mut self, a: usize, b: usize) {
+        self.neighbours[a].remove(&b);
+        self.neighbours[b].remove(&a);
+    }
+}
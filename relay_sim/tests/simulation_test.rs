@@ -0,0 +1,76 @@
+use relay_sim::{
+    config::{SimConfig, Topology},
+    simulation::Simulation,
+};
+
+fn ring(relays: usize, ttl: u8) -> SimConfig {
+    SimConfig {
+        relays,
+        periods: 8,
+        topology: Topology::Ring,
+        ..SimConfig::default()
+    }
+    .with_ttl(ttl)
+}
+
+#[tokio::test]
+async fn messages_go_around_the_ring() {
+    let report = Simulation::new(ring(6, 8)).unwrap().run().await;
+
+    assert_eq!(report.messages.len(), 6);
+    assert_eq!(report.delivery_ratio(), 1.0);
+    // the relay across the ring is 3 hops away either way, and gets the message from both sides
+    assert_eq!(report.max_hops(), 3);
+    assert!(report.duplicate_rate() > 0.0);
+
+    for message in &report.messages {
+        assert_eq!(message.created_period, 0);
+        let mut hops = message.hops.clone();
+        hops.sort();
+        assert_eq!(hops, vec![1, 1, 2, 2, 3]);
+        // forwarded envelopes go out a period after they came in
+        assert!(
+            message
+                .hops
+                .iter()
+                .zip(&message.latencies)
+                .all(|(hops, latency)| *latency == hops - 1)
+        );
+    }
+}
+
+#[tokio::test]
+async fn ttl_limits_how_far_messages_get() {
+    let report = Simulation::new(ring(8, 2)).unwrap().run().await;
+
+    // two relays on each side out of the other seven
+    assert_eq!(report.max_hops(), 2);
+    assert!(report.messages.iter().all(|message| message.reached() == 4));
+    assert_eq!(report.delivery_ratio(), 4.0 / 7.0);
+}
+
+#[tokio::test]
+async fn every_posting_period_adds_messages() {
+    let config = SimConfig {
+        posting_periods: 3,
+        ..ring(4, 6)
+    };
+    let mut simulation = Simulation::new(config).unwrap();
+
+    simulation.run_period().await;
+    assert_eq!(simulation.report().messages.len(), 4);
+    simulation.run_period().await;
+    simulation.run_period().await;
+    simulation.run_period().await;
+
+    let report = simulation.report();
+    assert_eq!(report.periods, 4);
+    assert_eq!(report.messages.len(), 12);
+    assert!(
+        report
+            .messages
+            .iter()
+            .filter(|message| message.created_period < 2)
+            .all(|message| message.delivery_ratio() == 1.0)
+    );
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeSet;
+
+use relay_sim::{
+    config::{SimConfig, SimConfigError, Topology},
+    topology::Graph,
+};
+
+#[test]
+fn small_world_without_rewiring_is_a_lattice() {
+    let topology = Topology::SmallWorld {
+        neighbours: 4,
+        rewiring_probability: 0.0,
+    };
+    let graph = Graph::build(&topology, 10, 0);
+
+    assert_eq!(graph.links(), 20);
+    assert!(graph.is_connected());
+    for relay in 0..10 {
+        let expected = [8, 9, 1, 2].map(|distance| (relay + distance) % 10);
+        assert_eq!(*graph.neighbours(relay), BTreeSet::from(expected));
+    }
+}
+
+#[test]
+fn rewiring_keeps_the_number_of_links() {
+    let topology = Topology::SmallWorld {
+        neighbours: 4,
+        rewiring_probability: 0.5,
+    };
+    let graph = Graph::build(&topology, 30, 7);
+
+    assert_eq!(graph.links(), 60);
+    assert_ne!(graph, Graph::build(&topology, 30, 8));
+    assert_eq!(graph, Graph::build(&topology, 30, 7));
+}
+
+#[test]
+fn random_graph_follows_link_probability() {
+    let complete = Graph::build(
+        &Topology::Random {
+            link_probability: 1.0,
+        },
+        6,
+        0,
+    );
+    let empty = Graph::build(
+        &Topology::Random {
+            link_probability: 0.0,
+        },
+        6,
+        0,
+    );
+
+    assert_eq!(complete.links(), 15);
+    assert!(complete.is_connected());
+    assert_eq!(empty.links(), 0);
+    assert!(!empty.is_connected());
+    assert_eq!(Graph::ring(6).links(), 6);
+}
+
+#[test]
+fn config_is_checked() {
+    let config: SimConfig = toml::from_str(
+        "
+        relays = 10
+        initial_ttl = 4
+
+        [topology]
+        kind = \"small-world\"
+        neighbours = 3
+        rewiring_probability = 0.1
+        ",
+    )
+    .unwrap();
+
+    assert_eq!(config.initial_ttl, 4);
+    assert_eq!(config.periods, SimConfig::default().periods);
+    assert!(matches!(
+        config.validate(),
+        Err(SimConfigError::InvalidNeighbours)
+    ));
+
+    let config = SimConfig {
+        topology: Topology::Random {
+            link_probability: 1.5,
+        },
+        ..SimConfig::default()
+    };
+    assert!(matches!(
+        config.validate(),
+        Err(SimConfigError::NotProbability("link_probability"))
+    ));
+
+    let config = SimConfig {
+        periods: 10,
+        posting_periods: 3,
+        ..SimConfig::default()
+    };
+    assert!(config.with_ttl(8).validate().is_ok());
+    assert!(matches!(
+        config.with_ttl(9).validate(),
+        Err(SimConfigError::TooFewPeriods)
+    ));
+    let config = SimConfig {
+        posting_periods: 30,
+        ..SimConfig::default()
+    };
+    assert!(matches!(
+        config.validate(),
+        Err(SimConfigError::TooFewPeriods)
+    ));
+}
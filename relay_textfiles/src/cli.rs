@@ -1,13 +1,18 @@
-use std::path::{Path, PathBuf};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Result, anyhow};
 use clap::{Parser, Subcommand};
-use relay_core::crypto::SecretKey;
+use relay_core::crypto::{PublicKey, SecretKey};
 
-use crate::textfiles::Textfiles;
+use crate::{config::RelaytConfig, textfiles::Textfiles};
 
 mod run;
 
+const PASSPHRASE_ENV_VAR: &str = "RELAYT_PASSPHRASE";
+
 #[derive(Parser)]
 #[command(version)]
 #[command(arg_required_else_help(true))]
@@ -28,11 +33,17 @@ enum Commands {
         /// Init with debug mode config
         #[arg(short, long)]
         debug: bool,
+        /// Encrypt secret key with a passphrase (or RELAYT_PASSPHRASE)
+        #[arg(short, long)]
+        encrypt_key: bool,
     },
     /// Create a store directory only
     InitStore {
         /// Store directory to initialize
         dir: String,
+        /// Encrypt secret key with a passphrase (or RELAYT_PASSPHRASE)
+        #[arg(short, long)]
+        encrypt_key: bool,
     },
     /// Run a relay using given directory
     Start {
@@ -44,6 +55,23 @@ enum Commands {
         #[arg(short, long)]
         debug: bool,
     },
+    /// Show fingerprints and safety numbers to compare with paired relays
+    Fingerprint {
+        /// Relay directory
+        dir: String,
+        /// Optional separate storage directory
+        store_dir: Option<String>,
+        /// Use debug mode config
+        #[arg(short, long)]
+        debug: bool,
+    },
+    /// Replace the relay key with a new one signed by the old one
+    RotateKey {
+        /// Relay directory
+        dir: String,
+        /// Optional separate storage directory
+        store_dir: Option<String>,
+    },
 }
 
 pub async fn do_cli() -> Result<()> {
@@ -51,10 +79,28 @@ pub async fn do_cli() -> Result<()> {
 
     if let Some(command) = cli.command {
         match command {
-            Commands::Init { dir, name, debug } => {
+            Commands::Init {
+                dir,
+                name,
+                debug,
+                encrypt_key,
+            } => {
                 let path = Path::new(&dir);
                 let relay_name = name.as_deref().unwrap_or(get_relay_name_from_dir(path));
-                match Textfiles::init_regular(path, relay_name, &SecretKey::generate(), debug) {
+                let passphrase = match encrypt_key.then(get_new_passphrase).transpose() {
+                    Ok(passphrase) => passphrase,
+                    Err(e) => {
+                        eprintln!("Could not read passphrase: {e}");
+                        return Ok(());
+                    }
+                };
+                match Textfiles::init_regular(
+                    path,
+                    relay_name,
+                    &SecretKey::generate(),
+                    passphrase.as_deref(),
+                    debug,
+                ) {
                     Ok(()) => {
                         println!("Created relay \"{relay_name}\"")
                     }
@@ -63,9 +109,16 @@ pub async fn do_cli() -> Result<()> {
                     }
                 }
             }
-            Commands::InitStore { dir } => {
+            Commands::InitStore { dir, encrypt_key } => {
                 let path = Path::new(&dir);
-                match Textfiles::init_store(path, &SecretKey::generate()) {
+                let passphrase = match encrypt_key.then(get_new_passphrase).transpose() {
+                    Ok(passphrase) => passphrase,
+                    Err(e) => {
+                        eprintln!("Could not read passphrase: {e}");
+                        return Ok(());
+                    }
+                };
+                match Textfiles::init_store(path, &SecretKey::generate(), passphrase.as_deref()) {
                     Ok(()) => {
                         println!("Created store directory")
                     }
@@ -98,12 +151,102 @@ pub async fn do_cli() -> Result<()> {
                     Err(_) => eprintln!("Could not open relay directory \"{dir}\""),
                 }
             }
+            Commands::Fingerprint {
+                dir,
+                store_dir,
+                debug,
+            } => {
+                match Textfiles::new(Path::new(&dir), store_dir.as_deref().map(Path::new), debug)
+                    .and_then(|textfiles| {
+                        Ok((textfiles.read_public_key()?, textfiles.read_config()?))
+                    }) {
+                    Ok((public_key, config)) => print_fingerprints(&public_key, &config),
+                    Err(e) => eprintln!("Could not read relay: {e}"),
+                }
+            }
+            Commands::RotateKey { dir, store_dir } => {
+                let textfiles = match Textfiles::new(
+                    Path::new(&dir),
+                    store_dir.as_deref().map(Path::new),
+                    false,
+                ) {
+                    Ok(textfiles) => textfiles,
+                    Err(e) => {
+                        eprintln!("Could not open relay: {e}");
+                        return Ok(());
+                    }
+                };
+                let passphrase = match get_passphrase_if_encrypted(&textfiles) {
+                    Ok(passphrase) => passphrase,
+                    Err(e) => {
+                        eprintln!("Could not read passphrase: {e}");
+                        return Ok(());
+                    }
+                };
+                match textfiles.rotate_secret(&SecretKey::generate(), passphrase.as_deref()) {
+                    Ok(key_succession) => {
+                        println!(
+                            "Rotated key from {} to {}",
+                            key_succession.old_key(),
+                            key_succession.new_key()
+                        )
+                    }
+                    Err(e) => {
+                        eprintln!("Could not rotate key: {e}")
+                    }
+                }
+            }
         }
     }
 
     Ok(())
 }
 
+fn print_fingerprints(public_key: &PublicKey, config: &RelaytConfig) {
+    println!("Relay \"{}\"", config.name);
+    println!("  Fingerprint: {}", public_key.fingerprint());
+    println!(
+        "  Fingerprint words: {}",
+        public_key.fingerprint().to_words()
+    );
+    for relay in &config.trusted_relays {
+        println!(
+            "Paired with \"{}\"",
+            relay.nickname.clone().unwrap_or(relay.key.to_string())
+        );
+        println!("  Fingerprint: {}", relay.key.fingerprint());
+        println!(
+            "  Fingerprint words: {}",
+            relay.key.fingerprint().to_words()
+        );
+        println!("  Safety number: {}", public_key.safety_number(&relay.key));
+    }
+}
+
+fn get_new_passphrase() -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV_VAR) {
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password("New passphrase: ")?;
+    if passphrase != rpassword::prompt_password("Confirm passphrase: ")? {
+        return Err(anyhow!("passphrases do not match"));
+    }
+
+    Ok(passphrase)
+}
+
+fn get_passphrase_if_encrypted(textfiles: &Textfiles) -> Result<Option<String>> {
+    if !textfiles.is_secret_encrypted()? {
+        return Ok(None);
+    }
+
+    match env::var(PASSPHRASE_ENV_VAR) {
+        Ok(passphrase) => Ok(Some(passphrase)),
+        Err(_) => Ok(Some(rpassword::prompt_password("Passphrase: ")?)),
+    }
+}
+
 fn get_checked_dir_path(path_string: &str) -> Result<PathBuf> {
     let path = Path::new(&path_string);
     if !path.is_dir() {
@@ -1,16 +1,30 @@
-use std::{fmt::Display, path::Path, sync::Arc};
+use std::{collections::VecDeque, fmt::Display, path::Path, sync::Arc};
 
 use anyhow::Result;
 use parking_lot::Mutex;
-use relay_core::mailroom::{GetNextLine, NextLine};
+use relay_core::{
+    crypto::PublicKey,
+    mailroom::{GetNextLine, NextDirectLine, NextLine},
+    message::MessageKind,
+    payload::RejectedEnvelope,
+};
 use relay_daemon::{
     config::{DaemonConfig, RelayData},
     daemon::Daemon,
     event::Event,
 };
+use thiserror::Error;
 use tokio::sync::mpsc;
 
-use crate::textfiles::Textfiles;
+use crate::{config::RelaytConfig, textfiles::Textfiles};
+
+#[derive(Error, Debug)]
+enum OutboxLineError {
+    #[error("line doesn't start with \"recipient:\"")]
+    MissingRecipient,
+    #[error("no paired relay nickname or key \"{0}\"")]
+    UnknownRecipient(String),
+}
 
 pub async fn run(dir_path: &Path, store_dir_path: Option<&Path>, debug_mode: bool) -> Result<()> {
     if debug_mode {
@@ -21,12 +35,15 @@ pub async fn run(dir_path: &Path, store_dir_path: Option<&Path>, debug_mode: boo
 
     let initial_relayt_config = textfiles.read_config()?;
     let initial_poem = textfiles.read_poem()?;
+    let initial_outbox = textfiles.read_outbox()?;
+    let initial_retract = textfiles.read_retract()?;
 
     let line_generator_wrapper = LineGeneratorWrapper {
         line_generator: Arc::new(Mutex::new(LineGenerator::new(
             initial_relayt_config.name.clone(),
             initial_poem.clone(),
         ))),
+        textfiles: textfiles.clone(),
     };
     let line_generator = line_generator_wrapper.line_generator.clone();
 
@@ -38,23 +55,30 @@ pub async fn run(dir_path: &Path, store_dir_path: Option<&Path>, debug_mode: boo
         }
     });
 
-    let secret_key = textfiles.read_secret()?;
+    let passphrase = super::get_passphrase_if_encrypted(&textfiles)?;
+    let secret_key = textfiles.read_secret(passphrase.as_deref())?;
+    let key_successions = textfiles.read_successions()?;
     let db_url = textfiles.archive_path().as_os_str().try_into()?;
     let daemon_config = DaemonConfig {
         trusted_relays: initial_relayt_config.trusted_relays.clone(),
         custom_initial_ttl: initial_relayt_config.initial_ttl,
         custom_max_forwarding_ttl: initial_relayt_config.max_forwarding_ttl,
+        custom_max_message_age: initial_relayt_config.max_message_age(),
+        custom_carry_over_periods: initial_relayt_config.carry_over_periods,
+        forwarding_limits: initial_relayt_config.forwarding_limits(),
+        filters: initial_relayt_config.filters.clone(),
     };
 
     println!("Starting relay \"{}\"...", initial_relayt_config.name);
     println!("Public key: {}", secret_key.public_key());
+    println!("Fingerprint: {}", secret_key.public_key().fingerprint());
     print!("{initial_relayt_config}");
     if !initial_poem.is_empty() {
         println!("Poem:");
         print_poem(&initial_poem);
     }
 
-    let mut relay_daemon = if debug_mode {
+    let relay_daemon = Arc::new(if debug_mode {
         Daemon::new_fast(
             line_generator_wrapper,
             event_tx,
@@ -72,9 +96,21 @@ pub async fn run(dir_path: &Path, store_dir_path: Option<&Path>, debug_mode: boo
             daemon_config,
         )
         .await
-    }?;
+    }?);
+
+    relay_daemon.set_key_successions(key_successions).await;
 
-    relay_daemon.start_sender().await?;
+    // queued only now, so the direct message in a restored snapshot isn't replaced by one of these
+    let sent = textfiles.read_sent()?;
+    queue_outbox_lines(
+        initial_outbox
+            .iter()
+            .filter(|outbox_line| !sent.contains(outbox_line)),
+        &initial_relayt_config,
+        &line_generator,
+    );
+
+    relay_daemon.start_sender().await;
 
     if let Some(listening_config) = &initial_relayt_config.listener {
         relay_daemon.start_listener(listening_config.port).await?;
@@ -83,6 +119,7 @@ pub async fn run(dir_path: &Path, store_dir_path: Option<&Path>, debug_mode: boo
     let mut config_change_rx = textfiles.watch_config_changes()?;
     let textfiles_clone = textfiles.clone();
     let line_generator_clone = Arc::clone(&line_generator);
+    let relay_daemon_clone = Arc::clone(&relay_daemon);
     tokio::spawn(async move {
         let mut last_config = initial_relayt_config;
         while let Some(events) = config_change_rx.recv().await {
@@ -96,12 +133,20 @@ pub async fn run(dir_path: &Path, store_dir_path: Option<&Path>, debug_mode: boo
                         if new_config.trusted_relays != last_config.trusted_relays
                             || new_config.initial_ttl != last_config.initial_ttl
                             || new_config.max_forwarding_ttl != last_config.max_forwarding_ttl
+                            || new_config.max_message_age_hours != last_config.max_message_age_hours
+                            || new_config.carry_over_periods != last_config.carry_over_periods
+                            || new_config.forwarding_limits() != last_config.forwarding_limits()
+                            || new_config.filters != last_config.filters
                         {
-                            relay_daemon
+                            relay_daemon_clone
                                 .update_config(DaemonConfig {
                                     trusted_relays: new_config.trusted_relays.clone(),
                                     custom_initial_ttl: new_config.initial_ttl,
                                     custom_max_forwarding_ttl: new_config.max_forwarding_ttl,
+                                    custom_max_message_age: new_config.max_message_age(),
+                                    custom_carry_over_periods: new_config.carry_over_periods,
+                                    forwarding_limits: new_config.forwarding_limits(),
+                                    filters: new_config.filters.clone(),
                                 })
                                 .await
                         }
@@ -128,6 +173,92 @@ pub async fn run(dir_path: &Path, store_dir_path: Option<&Path>, debug_mode: boo
         }
     });
 
+    if let Some(mut outbox_change_rx) = textfiles.watch_outbox_changes()? {
+        let textfiles_clone = textfiles.clone();
+        let line_generator_clone = Arc::clone(&line_generator);
+        tokio::spawn(async move {
+            // lines already in the outbox on start were queued above, or sent by an earlier run
+            let mut last_outbox = initial_outbox;
+            while let Some(events) = outbox_change_rx.recv().await {
+                if events.is_ok() {
+                    match textfiles_clone
+                        .read_outbox()
+                        .and_then(|outbox| Ok((outbox, textfiles_clone.read_config()?)))
+                    {
+                        Ok((new_outbox, config)) => {
+                            queue_outbox_lines(
+                                new_outbox
+                                    .iter()
+                                    .filter(|outbox_line| !last_outbox.contains(outbox_line)),
+                                &config,
+                                &line_generator_clone,
+                            );
+
+                            last_outbox = new_outbox;
+                        }
+                        Err(e) => {
+                            print_from_source(Source::Direct, format!("Can't read outbox: {e}"));
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    if let Some(mut retract_change_rx) = textfiles.watch_retract_changes()? {
+        let textfiles_clone = textfiles.clone();
+        tokio::spawn(async move {
+            // lines already in retract.txt on start were retracted by an earlier run
+            let mut last_retract = initial_retract;
+            while let Some(events) = retract_change_rx.recv().await {
+                if events.is_ok() {
+                    match textfiles_clone.read_retract() {
+                        Ok(new_retract) => {
+                            for line in new_retract
+                                .iter()
+                                .filter(|line| !last_retract.contains(line))
+                            {
+                                match relay_daemon.retract_line(line).await {
+                                    Ok(retracting) if retracting.is_empty() => {
+                                        print_from_source(
+                                            Source::Retract,
+                                            format!(
+                                                "No line \"{line}\" sent with the current key to retract"
+                                            ),
+                                        );
+                                    }
+                                    Ok(retracting) => {
+                                        print_from_source(
+                                            Source::Retract,
+                                            format!(
+                                                "Queued retraction of {} messages with line \"{line}\"",
+                                                retracting.len()
+                                            ),
+                                        );
+                                    }
+                                    Err(e) => {
+                                        print_from_source(
+                                            Source::Retract,
+                                            format!("Can't retract \"{line}\": {e}"),
+                                        );
+                                    }
+                                }
+                            }
+
+                            last_retract = new_retract;
+                        }
+                        Err(e) => {
+                            print_from_source(
+                                Source::Retract,
+                                format!("Can't read retract.txt: {e}"),
+                            );
+                        }
+                    }
+                }
+            }
+        });
+    }
+
     let mut poem_change_rx = textfiles.watch_poem_changes()?;
     tokio::spawn(async move {
         let mut last_poem = initial_poem;
@@ -158,18 +289,36 @@ pub async fn run(dir_path: &Path, store_dir_path: Option<&Path>, debug_mode: boo
 
 struct LineGeneratorWrapper {
     line_generator: Arc<Mutex<LineGenerator>>,
+    textfiles: Textfiles,
 }
 
 impl GetNextLine for LineGeneratorWrapper {
     fn get_next_line(&mut self) -> Option<NextLine> {
         self.line_generator.lock().get_next_line()
     }
+
+    // the mailroom keeps the direct message made from the line in its snapshot, so from here on the
+    // line is sent as far as the outbox goes
+    fn get_next_direct_line(&mut self) -> Option<NextDirectLine> {
+        let (outbox_line, direct_line) = self.line_generator.lock().direct_lines.pop_front()?;
+
+        if let Err(e) = self.textfiles.mark_sent(&outbox_line) {
+            print_from_source(
+                Source::Direct,
+                format!("Can't mark \"{outbox_line}\" as sent: {e}"),
+            );
+        }
+
+        Some(direct_line)
+    }
 }
 
 struct LineGenerator {
     author: String,
     poem: Vec<String>,
     i: usize,
+    // each with the outbox line it came from
+    direct_lines: VecDeque<(String, NextDirectLine)>,
 }
 
 impl LineGenerator {
@@ -178,6 +327,7 @@ impl LineGenerator {
             author: author.into(),
             poem,
             i: 0,
+            direct_lines: VecDeque::new(),
         }
     }
 
@@ -199,6 +349,71 @@ impl LineGenerator {
         self.poem = poem;
         self.i = 0;
     }
+
+    fn queue_direct_line(&mut self, outbox_line: String, direct_line: NextDirectLine) -> usize {
+        self.direct_lines.push_back((outbox_line, direct_line));
+        self.direct_lines.len()
+    }
+}
+
+// the mailroom takes one direct line at the start of each period, the rest wait in the queue
+fn queue_outbox_lines<'a, I>(
+    outbox_lines: I,
+    config: &RelaytConfig,
+    line_generator: &Mutex<LineGenerator>,
+) where
+    I: IntoIterator<Item = &'a String>,
+{
+    for outbox_line in outbox_lines {
+        match parse_outbox_line(outbox_line, config) {
+            Ok(direct_line) => {
+                let recipient = direct_line.recipient;
+                let line = direct_line.line.clone();
+                let queued = line_generator
+                    .lock()
+                    .queue_direct_line(outbox_line.clone(), direct_line);
+
+                print_from_source(
+                    Source::Direct,
+                    format!(
+                        "Queued direct line for {recipient}: \"{line}\" ({queued} queued, one goes out each period)"
+                    ),
+                );
+            }
+            Err(e) => {
+                print_from_source(
+                    Source::Direct,
+                    format!("Can't queue \"{outbox_line}\": {e}"),
+                );
+            }
+        }
+    }
+}
+
+// outbox lines look like "recipient: line", where the recipient is a paired relay nickname or the
+// public key of any relay
+fn parse_outbox_line(
+    outbox_line: &str,
+    config: &RelaytConfig,
+) -> Result<NextDirectLine, OutboxLineError> {
+    let (recipient, line) = outbox_line
+        .split_once(':')
+        .ok_or(OutboxLineError::MissingRecipient)?;
+    let recipient = recipient.trim();
+
+    let recipient_key = config
+        .trusted_relays
+        .iter()
+        .find(|relay| relay.nickname.as_deref() == Some(recipient))
+        .map(|relay| relay.key)
+        .or_else(|| PublicKey::new_from_b64(recipient).ok())
+        .ok_or_else(|| OutboxLineError::UnknownRecipient(recipient.to_owned()))?;
+
+    Ok(NextDirectLine {
+        recipient: recipient_key,
+        line: line.trim().to_owned(),
+        author: config.name.clone(),
+    })
 }
 
 struct EventPrinter {
@@ -241,12 +456,91 @@ impl EventPrinter {
                     ),
                 );
             }
+            Event::ListenerSuppressedEnvelopes(relay_data, count) => {
+                print_from_source(
+                    Source::Listener,
+                    format!(
+                        "Skipped {count} envelopes sender relay {} already has",
+                        match relay_data {
+                            Some(relay_data) => Self::relay_display(relay_data),
+                            None => "[unknown relay]".into(),
+                        }
+                    ),
+                );
+            }
+            Event::ListenerDroppedExpiredEnvelopes(relay_data, envelopes) => {
+                print_from_source(
+                    Source::Listener,
+                    format!(
+                        "Dropped {} expired envelopes from sender relay {}",
+                        envelopes.len(),
+                        match relay_data {
+                            Some(relay_data) => Self::relay_display(relay_data),
+                            None => "[unknown relay]".into(),
+                        }
+                    ),
+                );
+            }
+            Event::ListenerDroppedFilteredEnvelopes(relay_data, envelopes) => {
+                print_from_source(
+                    Source::Listener,
+                    format!(
+                        "Dropped {} filtered envelopes from sender relay {}",
+                        envelopes.len(),
+                        match relay_data {
+                            Some(relay_data) => Self::relay_display(relay_data),
+                            None => "[unknown relay]".into(),
+                        }
+                    ),
+                );
+            }
+            Event::ListenerQuarantinedEnvelopes(relay_data, envelopes) => {
+                print_from_source(
+                    Source::Listener,
+                    format!(
+                        "Quarantined {} envelopes from sender relay {}",
+                        envelopes.len(),
+                        match relay_data {
+                            Some(relay_data) => Self::relay_display(relay_data),
+                            None => "[unknown relay]".into(),
+                        }
+                    ),
+                );
+            }
+            Event::ListenerRejectedEnvelopes(relay_data, rejected_envelopes) => {
+                let relay_display = match relay_data {
+                    Some(relay_data) => Self::relay_display(relay_data),
+                    None => "[unknown relay]".into(),
+                };
+                for rejected_envelope in rejected_envelopes {
+                    print_from_source(
+                        Source::Listener,
+                        format!(
+                            "Rejected envelope from sender relay {}: {}",
+                            relay_display,
+                            Self::rejected_envelope_display(&rejected_envelope)
+                        ),
+                    );
+                }
+            }
             Event::ListenerReceivedBadPayload => {
                 print_from_source(Source::Listener, "Received bad payload");
             }
             Event::ListenerReceivedFromUntrustedSender => {
                 print_from_source(Source::Listener, "Received from untrusted sender");
             }
+            Event::ListenerReceivedMisaddressedPayload(error) => {
+                print_from_source(
+                    Source::Listener,
+                    format!("Received misaddressed payload: {error}"),
+                );
+            }
+            Event::ListenerReceivedUnsupportedVersion(version) => {
+                print_from_source(
+                    Source::Listener,
+                    format!("Received payload with unsupported protocol version {version}"),
+                );
+            }
             Event::ListenerDBError(error) => {
                 print_from_source(Source::Listener, format!("Had DB error: {error}"));
             }
@@ -281,6 +575,15 @@ impl EventPrinter {
                     ),
                 );
             }
+            Event::SenderSuppressedEnvelopes(relay, count) => {
+                print_from_source(
+                    Source::Sender,
+                    format!(
+                        "Skipped {count} envelopes listener relay {} already has",
+                        Self::relay_display(relay)
+                    ),
+                );
+            }
             Event::SenderReceivedFromListener(relay, envelopes) => {
                 print_from_source(
                     Source::Sender,
@@ -291,6 +594,49 @@ impl EventPrinter {
                     ),
                 );
             }
+            Event::SenderDroppedExpiredEnvelopes(relay, envelopes) => {
+                print_from_source(
+                    Source::Sender,
+                    format!(
+                        "Dropped {} expired envelopes from listener relay {}",
+                        envelopes.len(),
+                        Self::relay_display(relay),
+                    ),
+                );
+            }
+            Event::SenderDroppedFilteredEnvelopes(relay, envelopes) => {
+                print_from_source(
+                    Source::Sender,
+                    format!(
+                        "Dropped {} filtered envelopes from listener relay {}",
+                        envelopes.len(),
+                        Self::relay_display(relay),
+                    ),
+                );
+            }
+            Event::SenderQuarantinedEnvelopes(relay, envelopes) => {
+                print_from_source(
+                    Source::Sender,
+                    format!(
+                        "Quarantined {} envelopes from listener relay {}",
+                        envelopes.len(),
+                        Self::relay_display(relay),
+                    ),
+                );
+            }
+            Event::SenderRejectedEnvelopes(relay, rejected_envelopes) => {
+                let relay_display = Self::relay_display(relay);
+                for rejected_envelope in rejected_envelopes {
+                    print_from_source(
+                        Source::Sender,
+                        format!(
+                            "Rejected envelope from listener relay {}: {}",
+                            relay_display,
+                            Self::rejected_envelope_display(&rejected_envelope)
+                        ),
+                    );
+                }
+            }
             Event::SenderFailedSending(relay, error) => {
                 print_from_source(
                     Source::Sender,
@@ -329,9 +675,67 @@ impl EventPrinter {
                     ),
                 );
             }
+            Event::SenderFellBackToVersion(relay, version) => {
+                print_from_source(
+                    Source::Sender,
+                    format!(
+                        "Falling back to protocol version {} for listener relay {}",
+                        version,
+                        Self::relay_display(relay)
+                    ),
+                );
+            }
+            Event::SenderUpdatedStats(stats) => {
+                let counts = &stats.previous;
+                print_from_source(
+                    Source::Stats,
+                    format!(
+                        "Last period: received {} from {} relays, sent {} to {} relays",
+                        counts.received(),
+                        counts.received_from.len(),
+                        counts.sent(),
+                        counts.sent_to.len(),
+                    ),
+                );
+                print_from_source(
+                    Source::Stats,
+                    format!(
+                        "{} duplicates, {} expired, {} filtered, {} quarantined, {} out of ttl, {} skipped, {} over caps",
+                        counts.duplicates,
+                        counts.expired,
+                        counts.dropped,
+                        counts.quarantined,
+                        counts.ttl_exhausted,
+                        counts.suppressed,
+                        counts.capped,
+                    ),
+                );
+            }
             Event::SenderFinishedRun => {
                 print_from_source(Source::Sender, "Finished run");
             }
+            Event::AddedMessageToArchive(message)
+                if message.contents.kind == MessageKind::Direct =>
+            {
+                print_from_source(
+                    Source::Archive,
+                    format!(
+                        "Adding sealed direct message from \"{}\" to archive",
+                        message.contents.author
+                    ),
+                );
+            }
+            Event::AddedMessageToArchive(message)
+                if message.contents.kind == MessageKind::Retraction =>
+            {
+                print_from_source(
+                    Source::Archive,
+                    format!(
+                        "Adding retraction from \"{}\" to archive",
+                        message.contents.author
+                    ),
+                );
+            }
             Event::AddedMessageToArchive(message) => {
                 print_from_source(
                     Source::Archive,
@@ -348,12 +752,102 @@ impl EventPrinter {
                     }
                 };
             }
+            Event::RestoredMailroomSnapshot => {
+                print_from_source(Source::Archive, "Restored mailroom state from last run");
+            }
+            Event::RetractedLineInArchive(retraction, line) => {
+                print_from_source(
+                    Source::Archive,
+                    format!(
+                        "Marking line \"{}\" as retracted by \"{}\"",
+                        line, retraction.contents.author
+                    ),
+                );
+
+                match self.textfiles.mark_retracted(&line) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        print_from_source(
+                            Source::Archive,
+                            format!("Can't write to listen.txt: {e}"),
+                        );
+                    }
+                };
+            }
+            Event::ReceivedDirectLine(direct_line) => {
+                print_from_source(
+                    Source::Direct,
+                    format!(
+                        "Received direct line from \"{}\" ({}): \"{}\"",
+                        direct_line.message.contents.author,
+                        direct_line.message.certificate.key,
+                        direct_line.line
+                    ),
+                );
+
+                let inbox_line = format!(
+                    "{}: {}",
+                    direct_line.message.contents.author, direct_line.line
+                );
+
+                match self.textfiles.write_inbox(&inbox_line) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        print_from_source(Source::Direct, format!("Can't write to inbox.txt: {e}"));
+                    }
+                };
+            }
+            Event::RelayKeySucceeded(relay, old_key) => {
+                print_from_source(
+                    Source::Config,
+                    format!(
+                        "Paired relay {} rotated its key from {} to {}",
+                        Self::relay_display(relay.clone()),
+                        old_key,
+                        relay.key
+                    ),
+                );
+
+                // otherwise the next reload of relay.toml would bring back the old key
+                match self
+                    .textfiles
+                    .replace_paired_relay_key(&old_key, &relay.key)
+                {
+                    Ok(true) => {
+                        print_from_source(Source::Config, "Updated key in relay.toml".to_owned());
+                    }
+                    Ok(false) => {
+                        print_from_source(
+                            Source::Config,
+                            "Can't find old key in relay.toml, update it by hand to keep the new one"
+                                .to_owned(),
+                        );
+                    }
+                    Err(e) => {
+                        print_from_source(
+                            Source::Config,
+                            format!("Can't update key in relay.toml: {e}"),
+                        );
+                    }
+                }
+            }
         }
     }
 
     fn relay_display(relay: RelayData) -> String {
         format!("\"{}\"", relay.nickname.unwrap_or(relay.key.to_string()))
     }
+
+    fn rejected_envelope_display(rejected_envelope: &RejectedEnvelope) -> String {
+        format!(
+            "#{} by {}: {}",
+            rejected_envelope.index(),
+            rejected_envelope
+                .certificate_key()
+                .unwrap_or("[unknown key]"),
+            rejected_envelope.reason()
+        )
+    }
 }
 
 enum Source {
@@ -362,6 +856,9 @@ enum Source {
     Archive,
     Config,
     Poem,
+    Direct,
+    Retract,
+    Stats,
 }
 
 fn print_from_source<S: Display>(source: Source, line: S) {
@@ -373,6 +870,9 @@ fn print_from_source<S: Display>(source: Source, line: S) {
             Source::Archive => "[Archive]  ",
             Source::Config => "[Config]   ",
             Source::Poem => "[Poem]     ",
+            Source::Direct => "[Direct]   ",
+            Source::Retract => "[Retract]  ",
+            Source::Stats => "[Stats]    ",
         }
     )
 }
@@ -1,6 +1,10 @@
-use std::fmt::Display;
+use std::{fmt::Display, time::Duration};
 
-use relay_daemon::config::RelayData;
+use relay_core::{
+    filter::{FilterRules, Filters},
+    mailroom::ForwardingLimits,
+};
+use relay_daemon::config::{Compression, RelayData};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -10,9 +14,29 @@ pub struct RelaytConfig {
     pub listener: Option<ListeningConfig>,
     pub initial_ttl: Option<u8>,
     pub max_forwarding_ttl: Option<u8>,
+    pub max_message_age_hours: Option<u64>,
+    pub carry_over_periods: Option<u32>,
+    pub max_envelopes_per_payload: Option<usize>,
+    pub max_envelopes_per_origin: Option<usize>,
     #[serde(rename = "paired_relays")]
     #[serde(default)]
     pub trusted_relays: Vec<RelayData>,
+    #[serde(default)]
+    pub filters: Filters,
+}
+
+impl RelaytConfig {
+    pub fn max_message_age(&self) -> Option<Duration> {
+        self.max_message_age_hours
+            .map(|hours| Duration::from_secs(hours.saturating_mul(60 * 60)))
+    }
+
+    pub fn forwarding_limits(&self) -> ForwardingLimits {
+        ForwardingLimits {
+            max_envelopes_per_payload: self.max_envelopes_per_payload,
+            max_envelopes_per_origin: self.max_envelopes_per_origin,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -29,15 +53,36 @@ impl Display for RelaytConfig {
         if let Some(max_forwarding_ttl) = self.max_forwarding_ttl {
             writeln!(f, "Max forwarding TTL: {max_forwarding_ttl}")?;
         }
+        if let Some(max_message_age_hours) = self.max_message_age_hours {
+            writeln!(f, "Max message age: {max_message_age_hours} hours")?;
+        }
+        if let Some(carry_over_periods) = self.carry_over_periods {
+            writeln!(f, "Carry over periods: {carry_over_periods}")?;
+        }
+        if let Some(max_envelopes_per_payload) = self.max_envelopes_per_payload {
+            writeln!(f, "Max envelopes per payload: {max_envelopes_per_payload}")?;
+        }
+        if let Some(max_envelopes_per_origin) = self.max_envelopes_per_origin {
+            writeln!(f, "Max envelopes per origin: {max_envelopes_per_origin}")?;
+        }
         for relay in &self.trusted_relays {
             writeln!(f, "Paired with:")?;
             if let Some(nickname) = &relay.nickname {
                 writeln!(f, "  Nickname: {nickname}")?;
             }
             writeln!(f, "  Key: {}", relay.key)?;
+            writeln!(f, "  Fingerprint: {}", relay.key.fingerprint())?;
             if let Some(endpoint) = relay.endpoint() {
                 writeln!(f, "  Endpoint: {endpoint}")?;
             }
+            if relay.compression != Compression::None {
+                writeln!(f, "  Compression: {}", relay.compression)?;
+            }
+        }
+        if !self.filters.is_empty() {
+            writeln!(f, "Filters:")?;
+            write_filter_rules(f, "Drop", &self.filters.drop)?;
+            write_filter_rules(f, "Quarantine", &self.filters.quarantine)?;
         }
         if let Some(listener) = &self.listener {
             writeln!(f, "Listening!")?;
@@ -49,3 +94,21 @@ impl Display for RelaytConfig {
         Ok(())
     }
 }
+
+fn write_filter_rules(
+    f: &mut std::fmt::Formatter<'_>,
+    action: &str,
+    rules: &FilterRules,
+) -> std::fmt::Result {
+    if !rules.is_empty() {
+        writeln!(
+            f,
+            "  {action}: {} keys, {} authors, {} line patterns",
+            rules.keys.len(),
+            rules.authors.len(),
+            rules.lines.len()
+        )?;
+    }
+
+    Ok(())
+}
@@ -6,28 +6,49 @@ use std::{
     time::Duration,
 };
 
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, OsRng, rand_core::RngCore},
+};
 use notify::{PollWatcher, RecursiveMode};
 use notify_debouncer_mini::{DebouncedEvent, Debouncer};
 use parking_lot::Mutex;
 use pem::{Pem, PemError};
 use relay_core::{
-    crypto::SecretKey,
-    mailroom::{DEFAULT_INITIAL_TTL, DEFAULT_MAX_FORWARDING_TTL},
+    crypto::{KeySuccession, NewKeyError, PublicKey, SecretKey},
+    mailroom::{
+        DEFAULT_CARRY_OVER_PERIODS, DEFAULT_INITIAL_TTL, DEFAULT_MAX_FORWARDING_TTL,
+        DEFAULT_MAX_MESSAGE_AGE,
+    },
 };
 use relay_daemon::daemon::DEFAULT_LISTENING_PORT;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::mpsc::{self, UnboundedReceiver};
+use zeroize::Zeroizing;
 
 use crate::config::RelaytConfig;
 
 const CONFIG_FILE_PATH: &str = "relay.toml";
 const CONFIG_DEBUG_FILE_PATH: &str = "relay.debug.toml";
 const POEM_FILE_PATH: &str = "poem.txt";
+const OUTBOX_FILE_PATH: &str = "outbox.txt";
+const RETRACT_FILE_PATH: &str = "retract.txt";
 const LISTEN_FILE_PATH: &str = "listen.txt";
+const INBOX_FILE_PATH: &str = "inbox.txt";
 const PUBLIC_FILE_PATH: &str = "public.txt";
 const STORE_DIR_PATH: &str = "store";
 const ARCHIVE_FILE_PATH: &str = "archive.db";
 const SECRET_FILE_PATH: &str = "secret.pem";
+const SUCCESSIONS_FILE_PATH: &str = "successions.toml";
+const SENT_FILE_PATH: &str = "sent.txt";
+const SECRET_PEM_TAG: &str = "SECRET";
+const ENCRYPTED_SECRET_PEM_TAG: &str = "ENCRYPTED SECRET";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const SECRET_ENCRYPTION_VERSION: u8 = 1;
+const KDF_PARAMS_LENGTH: usize = 12;
 
 type WatcherReceiver = UnboundedReceiver<Result<Vec<DebouncedEvent>, notify::Error>>;
 
@@ -39,10 +60,22 @@ pub enum TextfilesError {
     NotifyError(#[from] notify::Error),
     #[error("toml error: {0}")]
     TomlError(#[from] toml::de::Error),
+    #[error("toml error: {0}")]
+    TomlSerError(#[from] toml::ser::Error),
     #[error("pem error: {0}")]
     PemError(#[from] PemError),
     #[error("key is wrong length")]
     KeyLengthError,
+    #[error("key error: {0}")]
+    KeyError(#[from] NewKeyError),
+    #[error("secret file has unknown format")]
+    UnknownSecretFormat,
+    #[error("secret is encrypted but no passphrase was given")]
+    MissingPassphrase,
+    #[error("cannot derive key from passphrase")]
+    PassphraseKdfError,
+    #[error("cannot decrypt secret (is the passphrase right?)")]
+    DecryptSecretError,
     #[error("trying to init in dir that is not empty")]
     InitDirNotEmpty,
     #[error("missing config file")]
@@ -66,6 +99,7 @@ impl Textfiles {
         dir_path: &Path,
         relay_name: &str,
         secret_key: &SecretKey,
+        passphrase: Option<&str>,
         debug_mode: bool,
     ) -> Result<(), TextfilesError> {
         let paths = Paths::new(dir_path, None, debug_mode);
@@ -84,17 +118,25 @@ impl Textfiles {
                 relay_name = relay_name,
                 default_listening_port = DEFAULT_LISTENING_PORT,
                 default_initial_ttl = DEFAULT_INITIAL_TTL,
-                default_max_forwarding_ttl = DEFAULT_MAX_FORWARDING_TTL
+                default_max_forwarding_ttl = DEFAULT_MAX_FORWARDING_TTL,
+                default_max_message_age_hours = DEFAULT_MAX_MESSAGE_AGE.as_secs() / 60 / 60,
+                default_carry_over_periods = DEFAULT_CARRY_OVER_PERIODS
             ),
         )?;
         fs::write(&paths.poem_path, include_str!("templates/poem.txt"))?;
+        fs::write(&paths.outbox_path, "")?;
+        fs::write(&paths.retract_path, "")?;
 
-        Self::init_store_files(&paths, secret_key)?;
+        Self::init_store_files(&paths, secret_key, passphrase)?;
 
         Ok(())
     }
 
-    pub fn init_store(dir_path: &Path, secret_key: &SecretKey) -> Result<(), TextfilesError> {
+    pub fn init_store(
+        dir_path: &Path,
+        secret_key: &SecretKey,
+        passphrase: Option<&str>,
+    ) -> Result<(), TextfilesError> {
         let paths = Paths::new(dir_path, Some(dir_path), false);
 
         fs::create_dir_all(dir_path)?;
@@ -102,18 +144,30 @@ impl Textfiles {
             return Err(TextfilesError::InitDirNotEmpty);
         };
 
-        Self::init_store_files(&paths, secret_key)?;
+        Self::init_store_files(&paths, secret_key, passphrase)?;
 
         Ok(())
     }
 
-    fn init_store_files(paths: &Paths, secret_key: &SecretKey) -> Result<(), TextfilesError> {
+    fn init_store_files(
+        paths: &Paths,
+        secret_key: &SecretKey,
+        passphrase: Option<&str>,
+    ) -> Result<(), TextfilesError> {
         fs::write(&paths.listen_path, "")?;
+        fs::write(&paths.inbox_path, "")?;
+        Self::write_secret_files(paths, secret_key, passphrase)?;
+
+        Ok(())
+    }
+
+    fn write_secret_files(
+        paths: &Paths,
+        secret_key: &SecretKey,
+        passphrase: Option<&str>,
+    ) -> Result<(), TextfilesError> {
         fs::write(&paths.public_path, secret_key.public_key().to_string())?;
-        fs::write(
-            &paths.secret_path,
-            pem::encode(&Pem::new("SECRET", secret_key.as_bytes())),
-        )?;
+        fs::write(&paths.secret_path, encode_secret(secret_key, passphrase)?)?;
 
         Ok(())
     }
@@ -152,6 +206,26 @@ impl Textfiles {
         self.watch_file(self.paths.poem_path.clone())
     }
 
+    pub fn watch_outbox_changes(&self) -> Result<Option<WatcherReceiver>, TextfilesError> {
+        self.watch_optional_file(self.paths.outbox_path.clone())
+    }
+
+    pub fn watch_retract_changes(&self) -> Result<Option<WatcherReceiver>, TextfilesError> {
+        self.watch_optional_file(self.paths.retract_path.clone())
+    }
+
+    // relays made before a file was added to init don't have it, so there's nothing to watch
+    fn watch_optional_file(
+        &self,
+        path: PathBuf,
+    ) -> Result<Option<WatcherReceiver>, TextfilesError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        self.watch_file(path).map(Some)
+    }
+
     fn watch_file(&self, path: PathBuf) -> Result<WatcherReceiver, TextfilesError> {
         let (tx, rx) = mpsc::unbounded_channel();
 
@@ -181,21 +255,156 @@ impl Textfiles {
     }
 
     pub fn read_poem(&self) -> Result<Vec<String>, TextfilesError> {
-        Ok(fs::read_to_string(&self.paths.poem_path)?
-            .lines()
-            .map(|line| line.trim())
-            .filter(|line| !line.is_empty())
-            .map(String::from)
-            .collect())
+        read_lines(&self.paths.poem_path)
+    }
+
+    pub fn read_outbox(&self) -> Result<Vec<String>, TextfilesError> {
+        if !self.paths.outbox_path.exists() {
+            return Ok(vec![]);
+        }
+
+        read_lines(&self.paths.outbox_path)
+    }
+
+    // outbox lines already handed to the relay, so lines still waiting are queued again on restart
+    pub fn read_sent(&self) -> Result<Vec<String>, TextfilesError> {
+        if !self.paths.sent_path.exists() {
+            return Ok(vec![]);
+        }
+
+        read_lines(&self.paths.sent_path)
+    }
+
+    // lines no longer in the outbox are forgotten, so a line taken out and put back is sent again
+    pub fn mark_sent(&self, outbox_line: &str) -> Result<(), TextfilesError> {
+        let outbox = self.read_outbox()?;
+        let mut sent: Vec<String> = self
+            .read_sent()?
+            .into_iter()
+            .filter(|line| outbox.contains(line) && line != outbox_line)
+            .collect();
+        sent.push(outbox_line.to_owned());
+
+        let (staged_path, path) =
+            stage_file(&self.paths.sent_path, (sent.join("\n") + "\n").as_bytes())?;
+        fs::rename(staged_path, path)?;
+
+        Ok(())
+    }
+
+    pub fn read_retract(&self) -> Result<Vec<String>, TextfilesError> {
+        if !self.paths.retract_path.exists() {
+            return Ok(vec![]);
+        }
+
+        read_lines(&self.paths.retract_path)
+    }
+
+    pub fn read_public_key(&self) -> Result<PublicKey, TextfilesError> {
+        Ok(PublicKey::new_from_b64(
+            fs::read_to_string(&self.paths.public_path)?.trim(),
+        )?)
+    }
+
+    pub fn is_secret_encrypted(&self) -> Result<bool, TextfilesError> {
+        Ok(self.read_secret_pem()?.tag() == ENCRYPTED_SECRET_PEM_TAG)
+    }
+
+    pub fn read_secret(&self, passphrase: Option<&str>) -> Result<SecretKey, TextfilesError> {
+        let secret_pem = self.read_secret_pem()?;
+        let tag = secret_pem.tag().to_owned();
+        let contents = Zeroizing::new(secret_pem.into_contents());
+
+        match tag.as_str() {
+            SECRET_PEM_TAG => Ok(SecretKey::new_from_bytes(
+                contents
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| TextfilesError::KeyLengthError)?,
+            )),
+            ENCRYPTED_SECRET_PEM_TAG => decrypt_secret(
+                &contents,
+                passphrase.ok_or(TextfilesError::MissingPassphrase)?,
+            ),
+            _ => Err(TextfilesError::UnknownSecretFormat),
+        }
+    }
+
+    fn read_secret_pem(&self) -> Result<Pem, TextfilesError> {
+        Ok(pem::parse(Zeroizing::new(fs::read_to_string(
+            &self.paths.secret_path,
+        )?))?)
+    }
+
+    pub fn read_successions(&self) -> Result<Vec<KeySuccession>, TextfilesError> {
+        if !self.paths.successions_path.exists() {
+            return Ok(vec![]);
+        }
+
+        let successions_file: SuccessionsFile =
+            toml::from_str(&fs::read_to_string(&self.paths.successions_path)?)?;
+
+        Ok(successions_file.successions)
+    }
+
+    pub fn rotate_secret(
+        &self,
+        new_secret_key: &SecretKey,
+        passphrase: Option<&str>,
+    ) -> Result<KeySuccession, TextfilesError> {
+        let old_secret_key = self.read_secret(passphrase)?;
+        let key_succession = KeySuccession::new(&old_secret_key, new_secret_key.public_key());
+
+        let mut successions = self.read_successions()?;
+        successions.push(key_succession.clone());
+
+        // everything is staged before anything is replaced, and the new secret is in place before
+        // the succession pointing to it is
+        let staged_files = [
+            stage_file(
+                &self.paths.secret_path,
+                encode_secret(new_secret_key, passphrase)?.as_bytes(),
+            )?,
+            stage_file(
+                &self.paths.public_path,
+                new_secret_key.public_key().to_string().as_bytes(),
+            )?,
+            stage_file(
+                &self.paths.successions_path,
+                toml::to_string(&SuccessionsFile { successions })?.as_bytes(),
+            )?,
+        ];
+        for (staged_path, path) in staged_files {
+            fs::rename(staged_path, path)?;
+        }
+
+        Ok(key_succession)
     }
 
-    pub fn read_secret(&self) -> Result<SecretKey, TextfilesError> {
-        Ok(SecretKey::new_from_bytes(
-            pem::parse(fs::read_to_string(&self.paths.secret_path)?)?
-                .contents()
-                .try_into()
-                .map_err(|_| TextfilesError::KeyLengthError)?,
-        ))
+    // the key is swapped in place, so the rest of the config and its comments stay as they were
+    pub fn replace_paired_relay_key(
+        &self,
+        old_key: &PublicKey,
+        new_key: &PublicKey,
+    ) -> Result<bool, TextfilesError> {
+        let config = fs::read_to_string(&self.paths.config_path)?;
+        let replaced = ['"', '\'']
+            .into_iter()
+            .fold(config.clone(), |config, quote| {
+                config.replace(
+                    &format!("{quote}{old_key}{quote}"),
+                    &format!("{quote}{new_key}{quote}"),
+                )
+            });
+
+        if replaced == config {
+            return Ok(false);
+        }
+
+        let (staged_path, path) = stage_file(&self.paths.config_path, replaced.as_bytes())?;
+        fs::rename(staged_path, path)?;
+
+        Ok(true)
     }
 
     pub fn write_listen(&self, line: &str) -> Result<(), TextfilesError> {
@@ -206,19 +415,228 @@ impl Textfiles {
         Ok(())
     }
 
+    // listen.txt only has the lines themselves, so the same line from different keys is marked too.
+    // lines that aren't in it anymore are appended marked, so the retraction isn't lost
+    pub fn mark_retracted(&self, line: &str) -> Result<(), TextfilesError> {
+        let retracted = format!("[retracted] {line}");
+        let listen = fs::read_to_string(&self.paths.listen_path)?;
+        let mut found = false;
+        let mut lines: Vec<&str> = listen
+            .lines()
+            .map(|listen_line| match listen_line == line {
+                true => {
+                    found = true;
+                    retracted.as_str()
+                }
+                false => listen_line,
+            })
+            .collect();
+        if !found {
+            lines.push(&retracted);
+        }
+
+        let (staged_path, path) = stage_file(
+            &self.paths.listen_path,
+            (lines.join("\n") + "\n").as_bytes(),
+        )?;
+        fs::rename(staged_path, path)?;
+
+        Ok(())
+    }
+
+    pub fn write_inbox(&self, line: &str) -> Result<(), TextfilesError> {
+        let mut inbox_file = File::options()
+            .append(true)
+            .create(true)
+            .open(&self.paths.inbox_path)?;
+
+        writeln!(&mut inbox_file, "{line}")?;
+
+        Ok(())
+    }
+
     pub fn archive_path(&self) -> &PathBuf {
         &self.paths.archive_path
     }
 }
 
+fn read_lines(path: &Path) -> Result<Vec<String>, TextfilesError> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+fn encode_secret(
+    secret_key: &SecretKey,
+    passphrase: Option<&str>,
+) -> Result<Zeroizing<String>, TextfilesError> {
+    let secret_pem = match passphrase {
+        Some(passphrase) => Pem::new(
+            ENCRYPTED_SECRET_PEM_TAG,
+            encrypt_secret(secret_key, passphrase)?,
+        ),
+        None => Pem::new(SECRET_PEM_TAG, secret_key.as_bytes()),
+    };
+
+    Ok(Zeroizing::new(pem::encode(&secret_pem)))
+}
+
+// written next to the file it replaces, so renaming it over that file can't half happen
+fn stage_file(path: &Path, contents: &[u8]) -> Result<(PathBuf, PathBuf), TextfilesError> {
+    let mut staged_path = path.as_os_str().to_owned();
+    staged_path.push(".tmp");
+    let staged_path = PathBuf::from(staged_path);
+
+    let mut staged_file = File::create(&staged_path)?;
+    staged_file.write_all(contents)?;
+    staged_file.sync_all()?;
+
+    Ok((staged_path, path.to_path_buf()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl KdfParams {
+    // what argon2's defaults were when secrets were first encrypted, spelled out so a change to
+    // them can't lock anyone out
+    const ARGON2ID_V1: KdfParams = KdfParams {
+        m_cost: 19 * 1024,
+        t_cost: 2,
+        p_cost: 1,
+    };
+
+    fn to_bytes(self) -> [u8; KDF_PARAMS_LENGTH] {
+        let mut bytes = [0; KDF_PARAMS_LENGTH];
+        bytes[0..4].copy_from_slice(&self.m_cost.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.t_cost.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.p_cost.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; KDF_PARAMS_LENGTH]) -> Self {
+        let read = |i: usize| {
+            u32::from_be_bytes(
+                bytes[i..i + 4]
+                    .try_into()
+                    .expect("should be able to read 4 bytes"),
+            )
+        };
+
+        Self {
+            m_cost: read(0),
+            t_cost: read(4),
+            p_cost: read(8),
+        }
+    }
+}
+
+fn derive_secret_encryption_key(
+    passphrase: &str,
+    salt: &[u8],
+    kdf_params: KdfParams,
+) -> Result<Zeroizing<[u8; 32]>, TextfilesError> {
+    let mut encryption_key = Zeroizing::new([0; 32]);
+    let params = Params::new(
+        kdf_params.m_cost,
+        kdf_params.t_cost,
+        kdf_params.p_cost,
+        Some(encryption_key.len()),
+    )
+    .map_err(|_| TextfilesError::PassphraseKdfError)?;
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, encryption_key.as_mut())
+        .map_err(|_| TextfilesError::PassphraseKdfError)?;
+
+    Ok(encryption_key)
+}
+
+// encrypted secret is stored as version || m_cost || t_cost || p_cost || salt || nonce ||
+// ciphertext, with the costs as big endian u32s
+fn encrypt_secret(secret_key: &SecretKey, passphrase: &str) -> Result<Vec<u8>, TextfilesError> {
+    let kdf_params = KdfParams::ARGON2ID_V1;
+    let mut salt = [0; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let encryption_key = derive_secret_encryption_key(passphrase, &salt, kdf_params)?;
+    let ciphertext = XChaCha20Poly1305::new(encryption_key.as_ref().into())
+        .encrypt(&nonce, secret_key.as_bytes().as_slice())
+        .expect("should be able to encrypt any secret key");
+
+    Ok([
+        [SECRET_ENCRYPTION_VERSION].as_slice(),
+        &kdf_params.to_bytes(),
+        &salt,
+        nonce.as_slice(),
+        &ciphertext,
+    ]
+    .concat())
+}
+
+fn decrypt_secret(contents: &[u8], passphrase: &str) -> Result<SecretKey, TextfilesError> {
+    let (&version, rest) = contents
+        .split_first()
+        .ok_or(TextfilesError::KeyLengthError)?;
+    if version != SECRET_ENCRYPTION_VERSION {
+        return Err(TextfilesError::UnknownSecretFormat);
+    }
+    if rest.len() < KDF_PARAMS_LENGTH + SALT_LENGTH + NONCE_LENGTH {
+        return Err(TextfilesError::KeyLengthError);
+    }
+
+    let (kdf_params, rest) = rest.split_at(KDF_PARAMS_LENGTH);
+    let kdf_params = KdfParams::from_bytes(
+        kdf_params
+            .try_into()
+            .expect("should be able to read kdf params"),
+    );
+    let (salt, rest) = rest.split_at(SALT_LENGTH);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+
+    let encryption_key = derive_secret_encryption_key(passphrase, salt, kdf_params)?;
+    let plaintext = Zeroizing::new(
+        XChaCha20Poly1305::new(encryption_key.as_ref().into())
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| TextfilesError::DecryptSecretError)?,
+    );
+
+    Ok(SecretKey::new_from_bytes(
+        plaintext
+            .as_slice()
+            .try_into()
+            .map_err(|_| TextfilesError::KeyLengthError)?,
+    ))
+}
+
+#[derive(Serialize, Deserialize)]
+struct SuccessionsFile {
+    #[serde(rename = "succession")]
+    #[serde(default)]
+    successions: Vec<KeySuccession>,
+}
+
 #[derive(Debug, Clone)]
 struct Paths {
     config_path: PathBuf,
     poem_path: PathBuf,
+    outbox_path: PathBuf,
+    retract_path: PathBuf,
     listen_path: PathBuf,
+    inbox_path: PathBuf,
     archive_path: PathBuf,
     public_path: PathBuf,
     secret_path: PathBuf,
+    successions_path: PathBuf,
+    sent_path: PathBuf,
 }
 
 impl Paths {
@@ -229,30 +647,50 @@ impl Paths {
             dir_path.join(CONFIG_FILE_PATH)
         };
         let poem_path = dir_path.join(POEM_FILE_PATH);
-        let (listen_path, archive_path, public_path, secret_path) =
-            if let Some(store_dir_path) = store_dir_path {
-                (
-                    store_dir_path.join(LISTEN_FILE_PATH),
-                    store_dir_path.join(ARCHIVE_FILE_PATH),
-                    store_dir_path.join(PUBLIC_FILE_PATH),
-                    store_dir_path.join(SECRET_FILE_PATH),
-                )
-            } else {
-                (
-                    dir_path.join(LISTEN_FILE_PATH),
-                    dir_path.join(STORE_DIR_PATH).join(ARCHIVE_FILE_PATH),
-                    dir_path.join(PUBLIC_FILE_PATH),
-                    dir_path.join(STORE_DIR_PATH).join(SECRET_FILE_PATH),
-                )
-            };
+        let outbox_path = dir_path.join(OUTBOX_FILE_PATH);
+        let retract_path = dir_path.join(RETRACT_FILE_PATH);
+        let (
+            listen_path,
+            inbox_path,
+            archive_path,
+            public_path,
+            secret_path,
+            successions_path,
+            sent_path,
+        ) = if let Some(store_dir_path) = store_dir_path {
+            (
+                store_dir_path.join(LISTEN_FILE_PATH),
+                store_dir_path.join(INBOX_FILE_PATH),
+                store_dir_path.join(ARCHIVE_FILE_PATH),
+                store_dir_path.join(PUBLIC_FILE_PATH),
+                store_dir_path.join(SECRET_FILE_PATH),
+                store_dir_path.join(SUCCESSIONS_FILE_PATH),
+                store_dir_path.join(SENT_FILE_PATH),
+            )
+        } else {
+            (
+                dir_path.join(LISTEN_FILE_PATH),
+                dir_path.join(INBOX_FILE_PATH),
+                dir_path.join(STORE_DIR_PATH).join(ARCHIVE_FILE_PATH),
+                dir_path.join(PUBLIC_FILE_PATH),
+                dir_path.join(STORE_DIR_PATH).join(SECRET_FILE_PATH),
+                dir_path.join(STORE_DIR_PATH).join(SUCCESSIONS_FILE_PATH),
+                dir_path.join(STORE_DIR_PATH).join(SENT_FILE_PATH),
+            )
+        };
 
         Self {
             config_path,
             poem_path,
+            outbox_path,
+            retract_path,
             listen_path,
+            inbox_path,
             archive_path,
             public_path,
             secret_path,
+            successions_path,
+            sent_path,
         }
     }
 }
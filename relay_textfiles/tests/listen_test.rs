@@ -0,0 +1,30 @@
+use std::fs;
+
+use relay_core::crypto::SecretKey;
+use relay_textfiles::textfiles::Textfiles;
+
+#[test]
+fn retracted_lines_are_marked_in_place() {
+    let dir = std::env::temp_dir().join(format!("relayt-{}-listen", std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    Textfiles::init_regular(&dir, "listen", &SecretKey::generate(), None, false).unwrap();
+    let textfiles = Textfiles::new(&dir, None, false).unwrap();
+
+    textfiles.write_listen("first").unwrap();
+    textfiles.write_listen("second").unwrap();
+    textfiles.write_listen("third").unwrap();
+    textfiles.mark_retracted("second").unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("listen.txt")).unwrap(),
+        "first\n[retracted] second\nthird\n"
+    );
+
+    // no longer in listen.txt, so the retraction is kept at the end
+    textfiles.mark_retracted("gone").unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("listen.txt")).unwrap(),
+        "first\n[retracted] second\nthird\n[retracted] gone\n"
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
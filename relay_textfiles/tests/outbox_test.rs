@@ -0,0 +1,33 @@
+use std::fs;
+
+use relay_core::crypto::SecretKey;
+use relay_textfiles::textfiles::Textfiles;
+
+#[test]
+fn sent_lines_follow_the_outbox() {
+    let dir = std::env::temp_dir().join(format!("relayt-{}-outbox", std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    Textfiles::init_regular(&dir, "outbox", &SecretKey::generate(), None, false).unwrap();
+    let textfiles = Textfiles::new(&dir, None, false).unwrap();
+
+    assert!(textfiles.read_sent().unwrap().is_empty());
+
+    fs::write(dir.join("outbox.txt"), "a: first\na: second\na: third\n").unwrap();
+    textfiles.mark_sent("a: first").unwrap();
+    textfiles.mark_sent("a: second").unwrap();
+    textfiles.mark_sent("a: first").unwrap();
+    assert_eq!(
+        textfiles.read_sent().unwrap(),
+        vec!["a: second", "a: first"]
+    );
+
+    // taken out of the outbox, so no longer remembered as sent
+    fs::write(dir.join("outbox.txt"), "a: second\na: third\n").unwrap();
+    textfiles.mark_sent("a: third").unwrap();
+    assert_eq!(
+        textfiles.read_sent().unwrap(),
+        vec!["a: second", "a: third"]
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,116 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use relay_core::crypto::SecretKey;
+use relay_textfiles::textfiles::{Textfiles, TextfilesError};
+
+const PASSPHRASE: &str = "correct horse battery staple";
+
+fn temp_relay(name: &str, passphrase: Option<&str>) -> (PathBuf, SecretKey) {
+    let dir = std::env::temp_dir().join(format!("relayt-{}-{name}", std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+
+    let secret_key = SecretKey::generate();
+    Textfiles::init_regular(&dir, name, &secret_key, passphrase, false).unwrap();
+
+    (dir, secret_key)
+}
+
+fn secret_path(dir: &Path) -> PathBuf {
+    dir.join("store").join("secret.pem")
+}
+
+fn rewrite_secret_contents(dir: &Path, contents: Vec<u8>) {
+    let secret_pem = pem::parse(fs::read_to_string(secret_path(dir)).unwrap()).unwrap();
+    let secret_pem = pem::Pem::new(secret_pem.tag(), contents);
+
+    fs::write(secret_path(dir), pem::encode(&secret_pem)).unwrap();
+}
+
+#[test]
+fn encrypted_secret_round_trip() {
+    let (dir, secret_key) = temp_relay("round-trip", Some(PASSPHRASE));
+    let textfiles = Textfiles::new(&dir, None, false).unwrap();
+
+    assert!(textfiles.is_secret_encrypted().unwrap());
+    assert_eq!(
+        textfiles
+            .read_secret(Some(PASSPHRASE))
+            .unwrap()
+            .public_key(),
+        secret_key.public_key()
+    );
+    assert!(matches!(
+        textfiles.read_secret(Some("wrong passphrase")),
+        Err(TextfilesError::DecryptSecretError)
+    ));
+    assert!(matches!(
+        textfiles.read_secret(None),
+        Err(TextfilesError::MissingPassphrase)
+    ));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn truncated_secret_is_rejected() {
+    let (dir, _) = temp_relay("truncated", Some(PASSPHRASE));
+    let textfiles = Textfiles::new(&dir, None, false).unwrap();
+    let contents = pem::parse(fs::read_to_string(secret_path(&dir)).unwrap())
+        .unwrap()
+        .into_contents();
+
+    for length in [0, 1, 10, 40] {
+        rewrite_secret_contents(&dir, contents[..length].to_vec());
+        assert!(matches!(
+            textfiles.read_secret(Some(PASSPHRASE)),
+            Err(TextfilesError::KeyLengthError)
+        ));
+    }
+
+    let mut unknown_version = contents.clone();
+    unknown_version[0] = 0xff;
+    rewrite_secret_contents(&dir, unknown_version);
+    assert!(matches!(
+        textfiles.read_secret(Some(PASSPHRASE)),
+        Err(TextfilesError::UnknownSecretFormat)
+    ));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rotated_secret_replaces_old_one() {
+    let (dir, old_secret_key) = temp_relay("rotated", Some(PASSPHRASE));
+    let textfiles = Textfiles::new(&dir, None, false).unwrap();
+    let new_secret_key = SecretKey::generate();
+
+    let key_succession = textfiles
+        .rotate_secret(&new_secret_key, Some(PASSPHRASE))
+        .unwrap();
+
+    assert_eq!(*key_succession.old_key(), old_secret_key.public_key());
+    assert_eq!(
+        textfiles
+            .read_secret(Some(PASSPHRASE))
+            .unwrap()
+            .public_key(),
+        new_secret_key.public_key()
+    );
+    assert_eq!(
+        textfiles.read_public_key().unwrap(),
+        new_secret_key.public_key()
+    );
+    assert_eq!(textfiles.read_successions().unwrap(), vec![key_succession]);
+    assert!(
+        fs::read_dir(dir.join("store")).unwrap().all(|entry| !entry
+            .unwrap()
+            .path()
+            .to_string_lossy()
+            .ends_with(".tmp"))
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}